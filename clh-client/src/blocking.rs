@@ -0,0 +1,142 @@
+//! Blocking client, for programs without an async runtime. It must not be
+//! used from within one.
+
+use crate::{total_count, Builder, Call, Error, Page, Result};
+use clh_types::{DeletedHistoryCount, History, NewHistory, SearchQuery, Stats, StatsQuery};
+
+/// Blocking client of the history server, see `crate::Client`.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::blocking::Client,
+    config: Builder,
+}
+
+impl Client {
+    pub(crate) fn new(config: Builder) -> Result<Self> {
+        let mut builder = reqwest::blocking::Client::builder().user_agent(crate::USER_AGENT);
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = config.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        for certificate in &config.root_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+        if let Some(ref identity) = config.identity {
+            builder = builder.identity(identity.clone());
+        }
+        Ok(Self {
+            http: builder.build()?,
+            config,
+        })
+    }
+
+    fn send(&self, call: Call) -> Result<reqwest::blocking::Response> {
+        let headers = call.headers(self.config.token.as_deref())?;
+        let mut attempt = 0;
+        loop {
+            let mut request = self
+                .http
+                .request(call.method.clone(), call.url(&self.config.server))
+                .headers(headers.clone());
+            if let Some((_, ref body)) = call.body {
+                request = request.body(body.clone());
+            }
+
+            attempt += 1;
+            let result = request.send();
+            let outcome = result.as_ref().map(|r| (r.status(), r.headers()));
+            if let Some(delay) = self.config.retry.after(&call, attempt, outcome) {
+                std::thread::sleep(delay);
+                continue;
+            }
+            let response = result?;
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let status = response.status();
+            let body = response.text().unwrap_or_default();
+            return Err(Error::Status { status, body });
+        }
+    }
+
+    /// Returns a page of the histories matching `q`, most recently run
+    /// first.
+    pub fn search(&self, q: &SearchQuery) -> Result<Page> {
+        let response = self.send(Call::search(q)?)?;
+        let total = total_count(response.headers());
+        Ok(Page {
+            histories: response.json()?,
+            total,
+        })
+    }
+
+    pub fn find(&self, id: i32) -> Result<Option<History>> {
+        Ok(self.send(Call::find(id))?.json()?)
+    }
+
+    /// Records a run of a command.
+    pub fn create(&self, new_history: &NewHistory) -> Result<NewHistory> {
+        Ok(self.send(Call::create(new_history)?)?.json()?)
+    }
+
+    /// Records up to `NewHistory::MAX_BULK` runs, all or none of them.
+    pub fn create_bulk(&self, new_histories: &[NewHistory]) -> Result<Vec<NewHistory>> {
+        Ok(self.send(Call::create_bulk(new_histories)?)?.json()?)
+    }
+
+    /// Moves a history to the trash, or removes it for good if `purge`.
+    pub fn delete(&self, id: i32, purge: bool) -> Result<DeletedHistoryCount> {
+        Ok(self.send(Call::delete(id, purge)?)?.json()?)
+    }
+
+    /// Takes a history out of the trash, returning `None` if it wasn't there.
+    pub fn restore(&self, id: i32) -> Result<Option<History>> {
+        Ok(self.send(Call::restore(id))?.json()?)
+    }
+
+    pub fn stats(&self, q: &SearchQuery, stats_query: &StatsQuery) -> Result<Stats> {
+        Ok(self.send(Call::stats(q, stats_query)?)?.json()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{retry, spawn_server};
+    use reqwest::StatusCode;
+
+    #[test]
+    fn test_create_bulk() {
+        let (url, rx) = spawn_server(vec![(201, "[]")]);
+        let client = Builder::new(&url)
+            .token("secret")
+            .retry(retry())
+            .build_blocking()
+            .unwrap();
+
+        let created = client.create_bulk(&[NewHistory::default()]).unwrap();
+        assert!(created.is_empty());
+        let request = rx.recv().unwrap();
+        assert_eq!(request.request_line, "POST /api/v1/bulk HTTP/1.1");
+        assert!(request.body.starts_with("[{"));
+    }
+
+    #[test]
+    fn test_gives_up_after_attempts() {
+        let (url, rx) = spawn_server(vec![(429, ""); 3]);
+        let client = Builder::new(&url).retry(retry()).build_blocking().unwrap();
+
+        match client.delete(1, true) {
+            Err(Error::Status { status, .. }) => assert_eq!(status, StatusCode::TOO_MANY_REQUESTS),
+            other => panic!("expected too many requests, got {other:?}"),
+        }
+        let requests: Vec<_> = rx.try_iter().collect();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests[0].request_line,
+            "DELETE /api/v1/1?purge=true HTTP/1.1"
+        );
+    }
+}
@@ -0,0 +1,677 @@
+//! Client of the clh-server API, async or, with the `blocking` feature,
+//! blocking.
+//!
+//! ```no_run
+//! # async fn run() -> clh_client::Result<()> {
+//! use clh_client::types::SearchQuery;
+//!
+//! let client = clh_client::Builder::new("https://clh.example.com")
+//!     .token("secret")
+//!     .build()?;
+//! let q = SearchQuery {
+//!     command: Some("cargo".to_string()),
+//!     ..Default::default()
+//! };
+//! let page = client.search(&q).await?;
+//! println!("{} of {}", page.histories.len(), page.total);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests failing in ways that are likely to pass on a later attempt are
+//! retried with exponential backoff, see `Retry`.
+
+use std::fmt;
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::{Certificate, Identity, Method, StatusCode};
+use serde::Serialize;
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+pub use clh_types as types;
+use clh_types::{
+    DeleteQuery, DeletedHistoryCount, History, NewHistory, SearchQuery, Stats, StatsQuery,
+};
+
+/// Where the supported version of the API is mounted.
+const API: &str = "/api/v1";
+const USER_AGENT: &str = concat!("clh-client/", env!("CARGO_PKG_VERSION"));
+const FORM: &str = "application/x-www-form-urlencoded";
+const JSON: &str = "application/json";
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request could not be sent, or the response could not be read.
+    Http(reqwest::Error),
+    /// The server answered with an error.
+    Status { status: StatusCode, body: String },
+    /// The request could not be encoded.
+    Encode(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(e) => write!(f, "{e}"),
+            Self::Status { status, body } if body.is_empty() => write!(f, "{status}"),
+            Self::Status { status, body } => write!(f, "{status}: {body}"),
+            Self::Encode(e) => write!(f, "cannot encode request: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// Whether the server could not be reached or was not able to answer
+    /// for the time being, so that sending the request later may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Http(e) => e.is_connect() || e.is_timeout(),
+            Self::Status { status, .. } => {
+                *status == StatusCode::REQUEST_TIMEOUT
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+                    || status.is_server_error()
+            }
+            Self::Encode(_) => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+/// How failed requests are retried. Requests that never reached the server
+/// and answers of `429 Too Many Requests` or `503 Service Unavailable` are
+/// retried. Timeouts and other gateway errors are only retried for reads,
+/// deletions, and histories all sent with a `client_event_id`, as the
+/// server may have recorded them anyway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    /// Attempts in all, at least 1.
+    pub attempts: u32,
+    /// Wait before the second attempt, doubled for each one after that.
+    /// `Retry-After` given by the server takes precedence.
+    pub delay: Duration,
+    /// Longest wait between attempts. Requests the server asks to wait
+    /// longer for are not retried.
+    pub max_delay: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Retry {
+    /// Never retries.
+    pub fn none() -> Self {
+        Self {
+            attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// `None` if the server asks to wait longer than `max_delay`.
+    fn delay(&self, attempt: u32, headers: Option<&HeaderMap>) -> Option<Duration> {
+        let retry_after = headers
+            .and_then(|h| h.get(RETRY_AFTER))
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs);
+        match retry_after {
+            Some(delay) if delay > self.max_delay => None,
+            Some(delay) => Some(delay),
+            None => Some(
+                self.delay
+                    .saturating_mul(2u32.saturating_pow(attempt))
+                    .min(self.max_delay),
+            ),
+        }
+    }
+
+    /// How long to wait before sending `call` again, after `attempt`
+    /// attempts ended with `outcome`, the status and headers answered or
+    /// the error. `None` if it isn't to be sent again.
+    fn after(
+        &self,
+        call: &Call,
+        attempt: u32,
+        outcome: std::result::Result<(StatusCode, &HeaderMap), &reqwest::Error>,
+    ) -> Option<Duration> {
+        if attempt >= self.attempts {
+            return None;
+        }
+        let retries = match outcome {
+            Ok((status, _)) => match status {
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => true,
+                StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT => call.is_idempotent(),
+                _ => false,
+            },
+            Err(e) => e.is_connect() || (e.is_timeout() && call.is_idempotent()),
+        };
+        if !retries {
+            return None;
+        }
+        self.delay(attempt - 1, outcome.ok().map(|(_, headers)| headers))
+    }
+}
+
+/// A request, encoded up front so that it can be sent again.
+struct Call {
+    method: Method,
+    path: String,
+    query: Option<String>,
+    body: Option<(&'static str, Vec<u8>)>,
+    /// Sent as `Idempotency-Key`, for the server to process the request
+    /// once only.
+    idempotency_key: Option<String>,
+    /// Whether the server processes the request once only however many
+    /// times it is sent, with or without an `idempotency_key`.
+    idempotent: bool,
+}
+
+impl Call {
+    fn new(method: Method, path: String) -> Self {
+        Self {
+            method,
+            path,
+            query: None,
+            body: None,
+            idempotency_key: None,
+            idempotent: false,
+        }
+    }
+
+    fn is_idempotent(&self) -> bool {
+        self.method == Method::GET || self.method == Method::DELETE || self.idempotent
+    }
+
+    /// Headers of each attempt at sending the call.
+    fn headers(&self, token: Option<&str>) -> Result<HeaderMap> {
+        let value = |v: &str| {
+            HeaderValue::from_str(v).map_err(|e| Error::Encode(format!("invalid header: {e}")))
+        };
+        let mut headers = HeaderMap::new();
+        if let Some(token) = token {
+            let mut bearer = value(&format!("Bearer {token}"))?;
+            bearer.set_sensitive(true);
+            headers.insert(AUTHORIZATION, bearer);
+        }
+        if let Some((content_type, _)) = self.body {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        }
+        if let Some(ref key) = self.idempotency_key {
+            headers.insert(IDEMPOTENCY_KEY, value(key)?);
+        }
+        Ok(headers)
+    }
+
+    fn query(mut self, query: &impl Serialize) -> Result<Self> {
+        let query = serde_urlencoded::to_string(query).map_err(|e| Error::Encode(e.to_string()))?;
+        self.query = match self.query {
+            Some(q) if !query.is_empty() => Some(format!("{q}&{query}")),
+            Some(q) => Some(q),
+            None => Some(query),
+        };
+        Ok(self)
+    }
+
+    fn form(mut self, body: &impl Serialize) -> Result<Self> {
+        let body = serde_urlencoded::to_string(body).map_err(|e| Error::Encode(e.to_string()))?;
+        self.body = Some((FORM, body.into_bytes()));
+        Ok(self)
+    }
+
+    fn json(mut self, body: &impl Serialize) -> Result<Self> {
+        let body = serde_json::to_vec(body).map_err(|e| Error::Encode(e.to_string()))?;
+        self.body = Some((JSON, body));
+        Ok(self)
+    }
+
+    fn url(&self, server: &str) -> String {
+        match self.query {
+            Some(ref q) if !q.is_empty() => format!("{server}{API}{}?{q}", self.path),
+            _ => format!("{server}{API}{}", self.path),
+        }
+    }
+
+    fn search(q: &SearchQuery) -> Result<Self> {
+        Self::new(Method::GET, "/".to_string()).query(q)
+    }
+
+    fn find(id: i32) -> Self {
+        Self::new(Method::GET, format!("/{id}"))
+    }
+
+    fn create(new_history: &NewHistory) -> Result<Self> {
+        let mut call = Self::new(Method::POST, "/".to_string()).form(new_history)?;
+        call.idempotency_key = new_history.client_event_id.clone();
+        call.idempotent = call.idempotency_key.is_some();
+        Ok(call)
+    }
+
+    /// The server records each history once per `client_event_id`, so
+    /// runs all sent with one may be sent again.
+    fn create_bulk(new_histories: &[NewHistory]) -> Result<Self> {
+        let mut call = Self::new(Method::POST, "/bulk".to_string()).json(&new_histories)?;
+        call.idempotent = new_histories.iter().all(|h| h.client_event_id.is_some());
+        Ok(call)
+    }
+
+    fn delete(id: i32, purge: bool) -> Result<Self> {
+        let q = DeleteQuery { purge: Some(purge) };
+        Self::new(Method::DELETE, format!("/{id}")).query(&q)
+    }
+
+    fn restore(id: i32) -> Self {
+        Self::new(Method::POST, format!("/{id}/restore"))
+    }
+
+    fn stats(q: &SearchQuery, stats_query: &StatsQuery) -> Result<Self> {
+        Self::new(Method::GET, "/stats".to_string())
+            .query(q)?
+            .query(stats_query)
+    }
+}
+
+/// A page of search results.
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub histories: Vec<History>,
+    /// Number of histories matching the query, on all pages.
+    pub total: i64,
+}
+
+fn total_count(headers: &HeaderMap) -> i64 {
+    headers
+        .get("x-total-count")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Configures and builds clients.
+#[derive(Debug, Clone)]
+pub struct Builder {
+    server: String,
+    token: Option<String>,
+    retry: Retry,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    root_certificates: Vec<Certificate>,
+    identity: Option<Identity>,
+}
+
+impl Builder {
+    /// A client of the server at `server`, like `http://localhost:8088`.
+    pub fn new(server: &str) -> Self {
+        Self {
+            server: server.trim_end_matches('/').to_string(),
+            token: None,
+            retry: Retry::default(),
+            timeout: None,
+            connect_timeout: None,
+            root_certificates: Vec::new(),
+            identity: None,
+        }
+    }
+
+    /// Token sent as `Authorization: Bearer <token>`
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = Retry {
+            attempts: retry.attempts.max(1),
+            ..retry
+        };
+        self
+    }
+
+    /// Timeout of each attempt.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Timeout of connecting to the server, on each attempt.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Trusts `certificate` for HTTPS, in addition to the system roots.
+    pub fn add_root_certificate(mut self, certificate: Certificate) -> Self {
+        self.root_certificates.push(certificate);
+        self
+    }
+
+    /// Authenticates with a client certificate.
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    pub fn build(self) -> Result<Client> {
+        let mut builder = reqwest::Client::builder().user_agent(USER_AGENT);
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        for certificate in &self.root_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+        if let Some(ref identity) = self.identity {
+            builder = builder.identity(identity.clone());
+        }
+        Ok(Client {
+            http: builder.build()?,
+            config: self,
+        })
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn build_blocking(self) -> Result<blocking::Client> {
+        blocking::Client::new(self)
+    }
+}
+
+/// Async client of the history server.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    config: Builder,
+}
+
+impl Client {
+    async fn send(&self, call: Call) -> Result<reqwest::Response> {
+        let headers = call.headers(self.config.token.as_deref())?;
+        let mut attempt = 0;
+        loop {
+            let mut request = self
+                .http
+                .request(call.method.clone(), call.url(&self.config.server))
+                .headers(headers.clone());
+            if let Some((_, ref body)) = call.body {
+                request = request.body(body.clone());
+            }
+
+            attempt += 1;
+            let result = request.send().await;
+            let outcome = result.as_ref().map(|r| (r.status(), r.headers()));
+            if let Some(delay) = self.config.retry.after(&call, attempt, outcome) {
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            let response = result?;
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(Error::Status { status, body });
+        }
+    }
+
+    /// Returns a page of the histories matching `q`, most recently run
+    /// first.
+    pub async fn search(&self, q: &SearchQuery) -> Result<Page> {
+        let response = self.send(Call::search(q)?).await?;
+        let total = total_count(response.headers());
+        Ok(Page {
+            histories: response.json().await?,
+            total,
+        })
+    }
+
+    pub async fn find(&self, id: i32) -> Result<Option<History>> {
+        Ok(self.send(Call::find(id)).await?.json().await?)
+    }
+
+    /// Records a run of a command.
+    pub async fn create(&self, new_history: &NewHistory) -> Result<NewHistory> {
+        Ok(self.send(Call::create(new_history)?).await?.json().await?)
+    }
+
+    /// Records up to `NewHistory::MAX_BULK` runs, all or none of them.
+    pub async fn create_bulk(&self, new_histories: &[NewHistory]) -> Result<Vec<NewHistory>> {
+        let call = Call::create_bulk(new_histories)?;
+        Ok(self.send(call).await?.json().await?)
+    }
+
+    /// Moves a history to the trash, or removes it for good if `purge`.
+    pub async fn delete(&self, id: i32, purge: bool) -> Result<DeletedHistoryCount> {
+        Ok(self.send(Call::delete(id, purge)?).await?.json().await?)
+    }
+
+    /// Takes a history out of the trash, returning `None` if it wasn't there.
+    pub async fn restore(&self, id: i32) -> Result<Option<History>> {
+        Ok(self.send(Call::restore(id)).await?.json().await?)
+    }
+
+    pub async fn stats(&self, q: &SearchQuery, stats_query: &StatsQuery) -> Result<Stats> {
+        let call = Call::stats(q, stats_query)?;
+        Ok(self.send(call).await?.json().await?)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// A request as received by `spawn_server`.
+    #[derive(Debug)]
+    pub(crate) struct Received {
+        pub request_line: String,
+        pub idempotency_key: Option<String>,
+        pub body: String,
+    }
+
+    /// Answers one request with each of `responses`, as status and body,
+    /// returning the server URL.
+    pub(crate) fn spawn_server(
+        responses: Vec<(u16, &'static str)>,
+    ) -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut length = 0;
+                let mut idempotency_key = None;
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    let Some((name, value)) = line.trim_end().split_once(": ") else {
+                        break;
+                    };
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.parse().unwrap();
+                    } else if name.eq_ignore_ascii_case(IDEMPOTENCY_KEY) {
+                        idempotency_key = Some(value.to_string());
+                    }
+                }
+                let mut request_body = vec![0; length];
+                reader.read_exact(&mut request_body).unwrap();
+
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Status\r\nContent-Length: {}\r\nX-Total-Count: 7\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+                let received = Received {
+                    request_line: request_line.trim_end().to_string(),
+                    idempotency_key,
+                    body: String::from_utf8_lossy(&request_body).into_owned(),
+                };
+                if tx.send(received).is_err() {
+                    return;
+                }
+            }
+        });
+
+        (url, rx)
+    }
+
+    pub(crate) fn retry() -> Retry {
+        Retry {
+            attempts: 3,
+            delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_retries_unavailable_server() {
+        let (url, rx) = spawn_server(vec![(503, ""), (200, "[]")]);
+        let client = Builder::new(&url).retry(retry()).build().unwrap();
+
+        let q = SearchQuery {
+            hostname: Some("host a".to_string()),
+            ..Default::default()
+        };
+        let page = client.search(&q).await.unwrap();
+        assert!(page.histories.is_empty());
+        assert_eq!(page.total, 7);
+
+        let requests: Vec<Received> = rx.try_iter().collect();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[1].request_line,
+            "GET /api/v1/?hostname=host+a HTTP/1.1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_is_not_retried_after_gateway_errors() {
+        let (url, rx) = spawn_server(vec![(502, "bad gateway"), (201, "{}")]);
+        let client = Builder::new(&url).retry(retry()).build().unwrap();
+
+        let new_history = NewHistory {
+            hostname: "host".to_string(),
+            working_directory: "/tmp".to_string(),
+            command: "ls -l".to_string(),
+            ..Default::default()
+        };
+        match client.create(&new_history).await {
+            Err(Error::Status { status, body }) => {
+                assert_eq!(status, StatusCode::BAD_GATEWAY);
+                assert_eq!(body, "bad gateway");
+            }
+            other => panic!("expected a bad gateway, got {other:?}"),
+        }
+        let request = rx.recv().unwrap();
+        assert_eq!(request.request_line, "POST /api/v1/ HTTP/1.1");
+        assert!(request.body.contains("command=ls+-l"));
+    }
+
+    #[tokio::test]
+    async fn test_create_with_event_id_is_retried() {
+        let created = r#"{"hostname":"host","working_directory":"/tmp","command":"ls"}"#;
+        let (url, rx) = spawn_server(vec![(502, ""), (201, created)]);
+        let client = Builder::new(&url).retry(retry()).build().unwrap();
+
+        let new_history = NewHistory {
+            command: "ls".to_string(),
+            client_event_id: Some("event-1".to_string()),
+            ..Default::default()
+        };
+        client.create(&new_history).await.unwrap();
+        let requests: Vec<Received> = rx.try_iter().collect();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].idempotency_key.as_deref(), Some("event-1"));
+    }
+
+    #[tokio::test]
+    async fn test_bulk_with_event_ids_is_retried() {
+        let (url, rx) = spawn_server(vec![(504, ""), (201, "[]"), (504, ""), (201, "[]")]);
+        let client = Builder::new(&url).retry(retry()).build().unwrap();
+
+        let run = |event_id: Option<&str>| NewHistory {
+            command: "ls".to_string(),
+            client_event_id: event_id.map(str::to_string),
+            ..Default::default()
+        };
+        let runs = [run(Some("event-1")), run(Some("event-2"))];
+        client.create_bulk(&runs).await.unwrap();
+        assert_eq!(rx.try_iter().count(), 2);
+
+        let runs = [run(Some("event-3")), run(None)];
+        match client.create_bulk(&runs).await {
+            Err(Error::Status { status, .. }) => assert_eq!(status, StatusCode::GATEWAY_TIMEOUT),
+            other => panic!("expected a gateway timeout, got {other:?}"),
+        }
+        assert_eq!(
+            rx.recv().unwrap().request_line,
+            "POST /api/v1/bulk HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let status = |status| Error::Status {
+            status,
+            body: String::new(),
+        };
+        assert!(status(StatusCode::SERVICE_UNAVAILABLE).is_transient());
+        assert!(status(StatusCode::TOO_MANY_REQUESTS).is_transient());
+        assert!(!status(StatusCode::BAD_REQUEST).is_transient());
+        assert!(!status(StatusCode::UNAUTHORIZED).is_transient());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = Retry {
+            attempts: 4,
+            delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+        };
+        assert_eq!(retry.delay(0, None), Some(Duration::from_millis(100)));
+        assert_eq!(retry.delay(2, None), Some(Duration::from_millis(400)));
+        assert_eq!(retry.delay(10, None), Some(Duration::from_secs(5)));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, "3".parse().unwrap());
+        assert_eq!(retry.delay(0, Some(&headers)), Some(Duration::from_secs(3)));
+        // Waiting longer than `max_delay` gives up instead.
+        headers.insert(RETRY_AFTER, "100".parse().unwrap());
+        assert_eq!(retry.delay(0, Some(&headers)), None);
+    }
+}
@@ -0,0 +1,8 @@
+//! Request and response types of the clh-server API, shared by the server
+//! and its clients.
+
+pub mod models;
+#[cfg(feature = "diesel")]
+pub mod schema;
+
+pub use models::*;
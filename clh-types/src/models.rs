@@ -0,0 +1,499 @@
+use serde::{Deserialize, Serialize};
+
+use chrono::prelude::*;
+use chrono::DateTime;
+
+#[cfg(feature = "diesel")]
+use diesel::{AsChangeset, Insertable, Queryable, QueryableByName};
+#[cfg(feature = "openapi")]
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "diesel", derive(Queryable))]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct History {
+    pub id: i32,
+    pub hostname: String,
+    pub working_directory: Option<String>,
+    pub command: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub git_remote: Option<String>,
+    pub git_branch: Option<String>,
+    pub git_root: Option<String>,
+    pub normalized_directory: Option<String>,
+    /// Exit status of the most recent run.
+    pub exit_status: Option<i32>,
+    pub run_count: i32,
+    pub failure_count: i32,
+    /// Set when the history was deleted, until it is restored or purged.
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Whether `command` and `working_directory` were encrypted by the
+    /// client, see `e2e`.
+    pub encrypted: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blind_index: Vec<String>,
+    /// Key the history is encrypted at rest with, see `at_rest`.
+    #[serde(skip)]
+    pub key_id: Option<String>,
+    #[serde(skip)]
+    pub data_key: Option<String>,
+    /// Identifies the history among those of its host, see `at_rest`.
+    #[serde(skip)]
+    pub content_hash: Option<String>,
+    /// Finds the history when encrypted at rest, see `at_rest`.
+    #[serde(skip)]
+    pub search_index: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "diesel", derive(Insertable))]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+#[cfg_attr(feature = "diesel", diesel(table_name = crate::schema::histories))]
+pub struct NewHistory {
+    pub hostname: String,
+    pub working_directory: String,
+    pub command: String,
+    /// Remote URL of the git repository the command was run in.
+    pub git_remote: Option<String>,
+    pub git_branch: Option<String>,
+    /// Top-level directory of the git working tree.
+    pub git_root: Option<String>,
+    /// `working_directory` rewritten through the path mappings; filled in by
+    /// the server.
+    #[serde(default, skip_deserializing)]
+    pub normalized_directory: Option<String>,
+    pub exit_status: Option<i32>,
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Keyed hashes the history can be found by when encrypted, comma
+    /// separated in forms.
+    #[serde(
+        default,
+        with = "comma_separated",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
+    pub blind_index: Vec<String>,
+    /// Filled in by `at_rest::Keyring::seal`.
+    #[serde(skip)]
+    pub key_id: Option<String>,
+    #[serde(skip)]
+    pub data_key: Option<String>,
+    #[serde(skip)]
+    pub content_hash: Option<String>,
+    #[serde(skip)]
+    pub search_index: Option<Vec<String>>,
+    /// When the command was run, if not just now, e.g. when a client
+    /// replays the runs it recorded while offline or imports older ones.
+    /// Recorded as when the history was created if it's new, and as when
+    /// it was updated if it's later than that.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "diesel", diesel(skip_insertion))]
+    pub executed_at: Option<DateTime<Utc>>,
+    /// Generated by the client for each run. The server records a run once
+    /// only, however many times it is sent with the same id, as long as
+    /// it's within the idempotency window the server is configured with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "diesel", diesel(skip_insertion))]
+    pub client_event_id: Option<String>,
+}
+
+impl NewHistory {
+    /// Most histories `POST /bulk` records at once.
+    pub const MAX_BULK: usize = 1000;
+    /// Longest `client_event_id` or `Idempotency-Key` accepted.
+    pub const MAX_IDEMPOTENCY_KEY: usize = 128;
+}
+
+/// (De)serializes a list as one comma separated string, as forms and query
+/// strings can't hold lists.
+mod comma_separated {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(values: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&values.join(","))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<String>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Ok(s.split(',')
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "diesel", derive(Queryable))]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct Host {
+    pub id: i32,
+    pub name: String,
+    /// Other names this host reports itself as, e.g. its FQDN.
+    pub aliases: Vec<String>,
+    pub tags: Vec<String>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Body of `PUT /hosts/{name}`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct HostUpdate {
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Element of the `GET /hosts` response
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "diesel", derive(QueryableByName))]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct HostSummary {
+    #[cfg_attr(feature = "diesel", diesel(sql_type = diesel::sql_types::Varchar))]
+    pub name: String,
+    #[cfg_attr(feature = "diesel", diesel(sql_type = diesel::sql_types::Array<diesel::sql_types::Text>))]
+    pub aliases: Vec<String>,
+    #[cfg_attr(feature = "diesel", diesel(sql_type = diesel::sql_types::Array<diesel::sql_types::Text>))]
+    pub tags: Vec<String>,
+    #[cfg_attr(feature = "diesel", diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Timestamptz>))]
+    pub last_seen_at: Option<DateTime<Utc>>,
+    #[cfg_attr(feature = "diesel", diesel(sql_type = diesel::sql_types::BigInt))]
+    pub command_count: i64,
+    #[cfg_attr(feature = "diesel", diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Timestamptz>))]
+    pub last_activity: Option<DateTime<Utc>>,
+}
+
+/// Extra query parameters for `GET /stats`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(IntoParams))]
+#[cfg_attr(feature = "openapi", into_params(parameter_in = Query))]
+pub struct StatsQuery {
+    /// Length of the ranked lists.
+    pub top: Option<i64>,
+}
+
+impl StatsQuery {
+    /// Returns the effective length of ranked lists, capped at 1,000.
+    pub fn effective_top(&self) -> i64 {
+        self.top.unwrap_or(10).clamp(1, 1000)
+    }
+}
+
+/// Response of `GET /stats`
+///
+/// Counts are numbers of runs, so a command recorded again in the same
+/// directory on the same host counts once per run. Time based buckets
+/// count each run at the time it ran, in UTC. Runs recorded before servers
+/// kept track of them individually count at the time of the most recent
+/// run of their history. With `since` or `until`, only the runs within
+/// them are counted.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct Stats {
+    pub total: StatsEntry,
+    pub top_commands: Vec<StatsEntry>,
+    /// Ranked by the first word of the command.
+    pub top_programs: Vec<StatsEntry>,
+    pub per_host: Vec<StatsEntry>,
+    pub per_directory: Vec<StatsEntry>,
+    pub per_day: Vec<DayCount>,
+    pub heatmap: Vec<HeatmapCell>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct StatsEntry {
+    pub key: String,
+    pub count: i64,
+    pub failures: i64,
+    pub failure_rate: f64,
+}
+
+impl StatsEntry {
+    pub fn new(key: String, count: i64, failures: i64) -> Self {
+        let failure_rate = if count > 0 {
+            failures as f64 / count as f64
+        } else {
+            0.0
+        };
+        StatsEntry {
+            key,
+            count,
+            failures,
+            failure_rate,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct DayCount {
+    pub day: NaiveDate,
+    pub count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct HeatmapCell {
+    /// 0 is Sunday.
+    pub day_of_week: i32,
+    pub hour: i32,
+    pub count: i64,
+}
+
+/// Query parameters for `DELETE /{id}`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(IntoParams))]
+#[cfg_attr(feature = "openapi", into_params(parameter_in = Query))]
+pub struct DeleteQuery {
+    /// Remove the history for good instead of moving it to the trash.
+    pub purge: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Histories {
+    pub elements: Vec<History>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct DeletedHistoryCount {
+    pub count: usize,
+    pub message: String,
+}
+
+/// Query parameters for `GET /`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(IntoParams))]
+#[cfg_attr(feature = "openapi", into_params(parameter_in = Query))]
+pub struct SearchQuery {
+    /// Matches `normalized_directory`, so callers should normalize it first.
+    pub pwd: Option<String>,
+    /// Matches the histories of the host, including those recorded under
+    /// one of its aliases.
+    pub hostname: Option<String>,
+    /// Host `pwd` was taken on, used to pick per-host path mappings.
+    /// Defaults to `hostname`.
+    pub origin: Option<String>,
+    /// Matches `git_remote`, regardless of how the remote URL was spelled.
+    pub repo: Option<String>,
+    pub branch: Option<String>,
+    /// Matches commands containing this text, ignoring case.
+    pub command: Option<String>,
+    /// Comma separated keyed hashes encrypted histories must all have.
+    pub blind_index: Option<String>,
+    /// Only these histories, for filters applied outside the database.
+    #[serde(skip)]
+    pub ids: Option<Vec<i32>>,
+    /// Only histories last run at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Only histories last run before this time.
+    pub until: Option<DateTime<Utc>>,
+    /// Search deleted histories instead of live ones.
+    pub deleted: Option<bool>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl SearchQuery {
+    /// Page size when the query doesn't give one, unless the server is
+    /// configured with another.
+    pub const DEFAULT_LIMIT: i64 = 1000;
+    /// Largest page size, however the server is configured.
+    pub const MAX_LIMIT: i64 = 10_000;
+
+    /// Returns the effective limit, capped at 10,000.
+    pub fn effective_limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(Self::DEFAULT_LIMIT)
+            .clamp(1, Self::MAX_LIMIT)
+    }
+
+    /// Returns the effective offset, floored at 0.
+    pub fn effective_offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SimpleMessage {
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "diesel", derive(Queryable))]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    /// Key the payload is signed with. Never sent back to clients.
+    #[serde(skip_serializing)]
+    pub secret: Option<String>,
+    pub events: Vec<String>,
+    /// Postgres regular expression recorded commands must match.
+    pub command_pattern: Option<String>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Body of `POST /webhooks` and `PUT /webhooks/{id}`
+///
+/// `PUT` replaces the whole webhook, so leaving out `secret` or
+/// `command_pattern` removes it.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "diesel", derive(Insertable, AsChangeset))]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+#[cfg_attr(feature = "diesel", diesel(table_name = crate::schema::webhooks, treat_none_as_null = true))]
+pub struct NewWebhook {
+    pub url: String,
+    pub secret: Option<String>,
+    #[serde(default = "NewWebhook::default_events")]
+    pub events: Vec<String>,
+    pub command_pattern: Option<String>,
+    #[serde(default = "NewWebhook::default_active")]
+    pub active: bool,
+}
+
+impl NewWebhook {
+    pub const EVENTS: [&'static str; 2] = ["created", "deleted"];
+
+    fn default_events() -> Vec<String> {
+        Self::EVENTS.map(String::from).to_vec()
+    }
+
+    fn default_active() -> bool {
+        true
+    }
+
+    /// Checks everything but `command_pattern`, which only the database can.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.url.starts_with("http://") || self.url.starts_with("https://")) {
+            return Err(format!("url must be http or https: {}", self.url));
+        }
+        if self.events.is_empty() {
+            return Err("events must not be empty".to_string());
+        }
+        if let Some(event) = self
+            .events
+            .iter()
+            .find(|e| !Self::EVENTS.contains(&e.as_str()))
+        {
+            return Err(format!(
+                "unknown event {event:?}, expected one of {:?}",
+                Self::EVENTS
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// An entry of the audit log, see `audit`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "diesel", derive(Queryable))]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct AuditEvent {
+    pub id: i64,
+    /// What was done, like `history.delete`.
+    pub action: String,
+    /// Who did it: `cert:<common name>`, `token:<token hash prefix>`,
+    /// `cli` or `anonymous`.
+    pub actor: String,
+    pub ip: Option<String>,
+    pub history_ids: Vec<i32>,
+    /// Query the affected histories were selected by, if any.
+    pub filters: Option<serde_json::Value>,
+    pub details: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Query parameters for `GET /audit`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(IntoParams))]
+#[cfg_attr(feature = "openapi", into_params(parameter_in = Query))]
+pub struct AuditQuery {
+    pub action: Option<String>,
+    pub actor: Option<String>,
+    /// Only events affecting this history.
+    pub history_id: Option<i32>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl AuditQuery {
+    pub const DEFAULT_LIMIT: i64 = 100;
+    pub const MAX_LIMIT: i64 = 1000;
+
+    pub fn effective_limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(Self::DEFAULT_LIMIT)
+            .clamp(1, Self::MAX_LIMIT)
+    }
+
+    pub fn effective_offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
+
+/// Body of `GET /healthz` and `GET /readyz`
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct Health {
+    /// `ok`, or `unavailable` when `error` is set.
+    pub status: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending_migrations: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Health {
+    pub fn ok() -> Self {
+        Self {
+            status: "ok".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            pending_migrations: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn fail(mut self, error: String) -> Self {
+        self.status = "unavailable".to_string();
+        self.error = Some(error);
+        self
+    }
+}
+
+/// Query parameters for `GET /stream` and `GET /stream/ws`
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(IntoParams))]
+#[cfg_attr(feature = "openapi", into_params(parameter_in = Query))]
+pub struct StreamQuery {
+    /// Matches `normalized_directory`, so callers should normalize it first.
+    pub pwd: Option<String>,
+    pub hostname: Option<String>,
+    /// Host `pwd` was taken on, used to pick per-host path mappings.
+    /// Defaults to `hostname`.
+    pub origin: Option<String>,
+}
+
+impl StreamQuery {
+    pub fn matches(&self, history: &History) -> bool {
+        self.hostname
+            .as_ref()
+            .is_none_or(|h| *h == history.hostname)
+            && self
+                .pwd
+                .as_ref()
+                .is_none_or(|pwd| Some(pwd) == history.normalized_directory.as_ref())
+    }
+}
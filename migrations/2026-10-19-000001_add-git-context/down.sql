@@ -0,0 +1,4 @@
+drop index if exists histories_git_remote_index;
+alter table histories drop column git_root;
+alter table histories drop column git_branch;
+alter table histories drop column git_remote;
@@ -0,0 +1,4 @@
+alter table histories add column git_remote text;
+alter table histories add column git_branch text;
+alter table histories add column git_root text;
+create index histories_git_remote_index on histories (git_remote);
@@ -0,0 +1,2 @@
+drop index if exists histories_normalized_directory_index;
+alter table histories drop column normalized_directory;
@@ -0,0 +1,3 @@
+alter table histories add column normalized_directory text;
+update histories set normalized_directory = working_directory;
+create index histories_normalized_directory_index on histories (normalized_directory);
@@ -0,0 +1,13 @@
+create table if not exists hosts (
+  id serial primary key
+  , name varchar not null unique
+  , aliases text[] not null default '{}'
+  , tags text[] not null default '{}'
+  , last_seen_at timestamp with time zone
+  , created_at timestamp with time zone not null default current_timestamp
+  , updated_at timestamp with time zone not null default current_timestamp
+);
+create index hosts_aliases_index on hosts using gin (aliases);
+
+insert into hosts (name, last_seen_at)
+  select hostname, max(updated_at) from histories group by hostname;
@@ -0,0 +1,3 @@
+alter table histories drop column failure_count;
+alter table histories drop column run_count;
+alter table histories drop column exit_status;
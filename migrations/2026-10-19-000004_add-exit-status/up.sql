@@ -0,0 +1,3 @@
+alter table histories add column exit_status integer;
+alter table histories add column run_count integer not null default 1;
+alter table histories add column failure_count integer not null default 0;
@@ -0,0 +1,2 @@
+drop index if exists histories_updated_at_index;
+alter table histories drop column deleted_at;
@@ -0,0 +1,2 @@
+alter table histories add column deleted_at timestamp with time zone;
+create index histories_updated_at_index on histories (updated_at) where deleted_at is null;
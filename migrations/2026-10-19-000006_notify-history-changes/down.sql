@@ -0,0 +1,2 @@
+drop trigger if exists histories_notify_change on histories;
+drop function if exists notify_history_change();
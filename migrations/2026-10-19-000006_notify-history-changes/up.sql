@@ -0,0 +1,11 @@
+create function notify_history_change() returns trigger as $$
+begin
+  perform pg_notify('history_changes', new.id::text);
+  return new;
+end;
+$$ language plpgsql;
+
+create trigger histories_notify_change
+  after insert or update on histories
+  for each row when (new.deleted_at is null)
+  execute function notify_history_change();
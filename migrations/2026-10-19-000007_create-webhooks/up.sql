@@ -0,0 +1,10 @@
+create table if not exists webhooks (
+  id serial primary key
+  , url text not null
+  , secret text
+  , events text[] not null default '{created,deleted}'
+  , command_pattern text
+  , active boolean not null default true
+  , created_at timestamp with time zone not null default current_timestamp
+  , updated_at timestamp with time zone not null default current_timestamp
+);
@@ -0,0 +1,3 @@
+drop index if exists histories_blind_index_index;
+alter table histories drop column blind_index;
+alter table histories drop column encrypted;
@@ -0,0 +1,3 @@
+alter table histories add column encrypted boolean not null default false;
+alter table histories add column blind_index text[] not null default '{}';
+create index histories_blind_index_index on histories using gin (blind_index);
@@ -0,0 +1,12 @@
+drop trigger histories_notify_update on histories;
+drop trigger histories_notify_insert on histories;
+create trigger histories_notify_change
+  after insert or update on histories
+  for each row when (new.deleted_at is null)
+  execute function notify_history_change();
+
+alter table histories drop constraint histories_unique_constraint;
+alter table histories add constraint histories_unique_constraint unique (hostname, working_directory, command);
+alter table histories drop column content_hash;
+alter table histories drop column data_key;
+alter table histories drop column key_id;
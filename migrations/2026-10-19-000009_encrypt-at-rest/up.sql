@@ -0,0 +1,16 @@
+alter table histories add column key_id text;
+alter table histories add column data_key text;
+alter table histories add column content_hash text;
+update histories set content_hash = encode(sha256(sha256(convert_to(working_directory, 'UTF8')) || sha256(convert_to(command, 'UTF8'))), 'hex');
+alter table histories drop constraint histories_unique_constraint;
+alter table histories add constraint histories_unique_constraint unique (hostname, content_hash);
+
+drop trigger histories_notify_change on histories;
+create trigger histories_notify_insert
+  after insert on histories
+  for each row when (new.deleted_at is null)
+  execute function notify_history_change();
+create trigger histories_notify_update
+  after update on histories
+  for each row when (new.deleted_at is null and (new.updated_at is distinct from old.updated_at or old.deleted_at is not null))
+  execute function notify_history_change();
@@ -0,0 +1,2 @@
+drop table audit_events;
+drop function forbid_audit_event_changes();
@@ -0,0 +1,23 @@
+create table if not exists audit_events (
+  id bigserial primary key
+  , action text not null
+  , actor text not null
+  , ip text
+  , history_ids integer[] not null default '{}'
+  , filters jsonb
+  , details jsonb not null default '{}'
+  , created_at timestamp with time zone not null default current_timestamp
+);
+create index audit_events_created_at_index on audit_events (created_at);
+create index audit_events_history_ids_index on audit_events using gin (history_ids);
+
+create function forbid_audit_event_changes() returns trigger as $$
+begin
+  raise exception 'audit_events is append-only';
+end;
+$$ language plpgsql;
+
+create trigger audit_events_append_only
+  before update or delete on audit_events
+  for each row
+  execute function forbid_audit_event_changes();
@@ -0,0 +1,4 @@
+create table if not exists client_events (
+  id text primary key
+  , created_at timestamp with time zone not null default current_timestamp
+);
@@ -0,0 +1,3 @@
+drop index idempotency_keys_created_at_index;
+alter table idempotency_keys drop column response;
+alter table idempotency_keys rename to client_events;
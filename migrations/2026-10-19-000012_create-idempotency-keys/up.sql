@@ -0,0 +1,3 @@
+alter table client_events rename to idempotency_keys;
+alter table idempotency_keys add column response jsonb;
+create index idempotency_keys_created_at_index on idempotency_keys (created_at);
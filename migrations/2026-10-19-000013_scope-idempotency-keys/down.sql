@@ -0,0 +1,5 @@
+delete from idempotency_keys;
+alter table idempotency_keys drop constraint idempotency_keys_pkey;
+alter table idempotency_keys drop column fingerprint;
+alter table idempotency_keys drop column hostname;
+alter table idempotency_keys add constraint client_events_pkey primary key (id);
@@ -0,0 +1,6 @@
+-- Keys claimed so far aren't scoped to a host, and are forgotten.
+delete from idempotency_keys;
+alter table idempotency_keys drop constraint client_events_pkey;
+alter table idempotency_keys add column hostname text not null;
+alter table idempotency_keys add column fingerprint text not null;
+alter table idempotency_keys add primary key (hostname, id);
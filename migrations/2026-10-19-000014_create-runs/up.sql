@@ -0,0 +1,13 @@
+create table if not exists runs (
+  id bigserial primary key
+  , history_id integer not null references histories (id) on delete cascade
+  , executed_at timestamp with time zone not null default current_timestamp
+  , exit_status integer
+);
+create index runs_history_id_executed_at_index on runs (history_id, executed_at);
+
+-- Earlier runs were only counted, so they are all taken as run at the time
+-- of the most recent one, which alone kept its exit status.
+insert into runs (history_id, executed_at, exit_status)
+select id, updated_at, case when n = run_count then exit_status end
+from histories, generate_series(1, run_count) as n;
@@ -0,0 +1,2 @@
+drop index if exists histories_search_index_index;
+alter table histories drop column search_index;
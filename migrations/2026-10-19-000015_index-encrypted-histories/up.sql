@@ -0,0 +1,5 @@
+-- Keyed hashes of the trigrams of commands encrypted at rest, and of their
+-- directories. Histories encrypted before are left without one until
+-- `rotate-keys --all` seals them again.
+alter table histories add column search_index text[];
+create index histories_search_index_index on histories using gin (search_index);
@@ -4,13 +4,13 @@ curl -X POST \
      --data-urlencode "hostname=localhost" \
      --data-urlencode "working_directory=$(pwd)" \
      --data-urlencode "command=echo this is test" \
-     http://localhost:8088
+     http://localhost:8088/api/v1/
 echo
-curl http://localhost:8088
-id=$(curl --silent http://localhost:8088 | jq -r ".[0].id")
+curl http://localhost:8088/api/v1/
+id=$(curl --silent http://localhost:8088/api/v1/ | jq -r ".[0].id")
 echo
 echo -----
 
-curl http://localhost:8088/${id}
+curl http://localhost:8088/api/v1/${id}
 echo
 
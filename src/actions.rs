@@ -1,7 +1,13 @@
 use diesel::dsl::*;
+use diesel::pg::upsert::excluded;
+use diesel::pg::Pg;
 use diesel::prelude::*;
+use diesel::sql_types::{Bool, Date, Integer, Nullable, Text};
 
+use crate::at_rest;
+use crate::metrics;
 use crate::models;
+use crate::paths::PathMappings;
 
 type HistoriesQuery<'a> = crate::schema::histories::BoxedQuery<'a, diesel::pg::Pg>;
 
@@ -9,34 +15,124 @@ fn with_filters<'a>(query: HistoriesQuery<'a>, q: &'a models::SearchQuery) -> Hi
     use crate::schema::histories::dsl::*;
     let mut query = query;
     if let Some(ref pwd) = q.pwd {
-        query = query.filter(working_directory.eq(pwd));
+        query = query.filter(normalized_directory.eq(pwd));
     }
     if let Some(ref host) = q.hostname {
-        query = query.filter(hostname.eq(host));
+        // Histories recorded under one of its aliases before they were set
+        // are the host's too.
+        let aliased =
+            sql::<Bool>("histories.hostname in (select unnest(aliases) from hosts where name = ")
+                .bind::<Text, _>(host)
+                .sql(")");
+        query = query.filter(hostname.eq(host).or(aliased));
+    }
+    if let Some(ref repo) = q.repo {
+        query = query.filter(git_remote.eq(canonical_git_remote(repo)));
+    }
+    if let Some(ref branch) = q.branch {
+        query = query.filter(git_branch.eq(branch));
+    }
+    if let Some(ref text) = q.command {
+        query = query.filter(command.ilike(format!("%{}%", escape_like(text))));
+    }
+    if let Some(ref hashes) = q.blind_index {
+        let hashes: Vec<&str> = hashes.split(',').filter(|h| !h.is_empty()).collect();
+        query = query.filter(blind_index.contains(hashes));
+    }
+    if let Some(ref ids) = q.ids {
+        query = query.filter(id.eq_any(ids));
+    }
+    if let Some(since) = q.since {
+        query = query.filter(updated_at.ge(since));
+    }
+    if let Some(until) = q.until {
+        query = query.filter(updated_at.lt(until));
+    }
+    if q.deleted.unwrap_or(false) {
+        query = query.filter(deleted_at.is_not_null());
+    } else {
+        query = query.filter(deleted_at.is_null());
     }
     query
 }
 
+/// Escapes the `LIKE` wildcards in `text`, so that it matches literally.
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Reduces a git remote URL to `host/path` so that the same repository is
+/// recognised whether it was cloned over ssh, https or with the scp-like
+/// `user@host:path` syntax. Local paths are returned unchanged.
+pub fn canonical_git_remote(remote: &str) -> String {
+    let remote = remote.trim().trim_end_matches('/');
+    let remote = remote.strip_suffix(".git").unwrap_or(remote);
+
+    let (authority, path) = if let Some((_, rest)) = remote.split_once("://") {
+        match rest.split_once('/') {
+            Some((authority, path)) => (authority, path),
+            None => (rest, ""),
+        }
+    } else {
+        match remote.split_once(':') {
+            Some((authority, path)) if !authority.contains('/') => (authority, path),
+            _ => return remote.to_string(),
+        }
+    };
+
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    let host = host.split(':').next().unwrap_or(host).to_lowercase();
+    let path = path.trim_matches('/');
+
+    if path.is_empty() {
+        host
+    } else {
+        format!("{host}/{path}")
+    }
+}
+
 pub fn find(
     conn: &mut PgConnection,
     history_id: i32,
 ) -> Result<Option<models::History>, diesel::result::Error> {
     use crate::schema::histories::dsl::*;
 
+    let _timer = metrics::query_timer("find");
+
     let history = histories
         .filter(id.eq(history_id))
+        .filter(deleted_at.is_null())
         .first::<models::History>(conn)
         .optional()?;
 
     Ok(history)
 }
 
+/// Like `find`, but also returns histories that are in the trash.
+pub fn find_including_deleted(
+    conn: &mut PgConnection,
+    history_id: i32,
+) -> Result<Option<models::History>, diesel::result::Error> {
+    use crate::schema::histories::dsl::*;
+
+    let _timer = metrics::query_timer("find_including_deleted");
+
+    histories
+        .filter(id.eq(history_id))
+        .first::<models::History>(conn)
+        .optional()
+}
+
 pub fn search(
     conn: &mut PgConnection,
     q: &models::SearchQuery,
 ) -> Result<(Vec<models::History>, i64), diesel::result::Error> {
     use crate::schema::histories::dsl::*;
 
+    let _timer = metrics::query_timer("search");
+
     let total: i64 = with_filters(histories.into_boxed(), q)
         .count()
         .get_result(conn)?;
@@ -50,37 +146,342 @@ pub fn search(
     Ok((results, total))
 }
 
+/// Decrypts histories encrypted at rest, failing as if they couldn't be
+/// read.
+pub fn decrypt<'a>(
+    keys: &at_rest::Keyring,
+    found: impl IntoIterator<Item = &'a mut models::History>,
+) -> Result<(), diesel::result::Error> {
+    for h in found {
+        keys.open(h)
+            .map_err(|e| diesel::result::Error::DeserializationError(e.into()))?;
+    }
+    Ok(())
+}
+
+/// Whether a decrypted history matches the filters of `q` on the columns
+/// encrypted at rest, as `with_filters` would.
+fn matches_encrypted_filters(q: &models::SearchQuery, h: &models::History) -> bool {
+    let pwd_matches = q
+        .pwd
+        .as_ref()
+        .is_none_or(|pwd| h.normalized_directory.as_ref() == Some(pwd));
+    let command_matches = q
+        .command
+        .as_ref()
+        .is_none_or(|text| h.command.to_lowercase().contains(&text.to_lowercase()));
+    pwd_matches && command_matches
+}
+
+/// The filters of `q` the database can apply to histories encrypted at
+/// rest with `keys`.
+fn database_filters(q: &models::SearchQuery, keys: &at_rest::Keyring) -> models::SearchQuery {
+    let pwd = if keys.encrypts_directories() {
+        None
+    } else {
+        q.pwd.clone()
+    };
+    models::SearchQuery {
+        pwd,
+        command: None,
+        ..q.clone()
+    }
+}
+
+/// Whether the database can't apply all the filters of `q` to histories
+/// encrypted at rest with `keys`.
+fn needs_scan(q: &models::SearchQuery, keys: &at_rest::Keyring) -> bool {
+    keys.is_enabled() && (q.command.is_some() || (q.pwd.is_some() && keys.encrypts_directories()))
+}
+
+/// Most histories decrypted for a single query.
+const MAX_SCANNED: i64 = 10_000;
+
+type Candidates =
+    Box<dyn BoxableExpression<crate::schema::histories::table, Pg, SqlType = Nullable<Bool>>>;
+
+/// Narrows down the histories to decrypt for the filters of `q` on the
+/// columns encrypted at rest: plaintext histories matching them, histories
+/// whose search index holds all the hashes of `q`, and histories encrypted
+/// before they were indexed.
+fn candidates(q: &models::SearchQuery, keys: &at_rest::Keyring) -> Candidates {
+    use crate::schema::histories::dsl::*;
+
+    let mut plaintext: Candidates = Box::new(key_id.is_null().nullable());
+    if let Some(ref text) = q.command {
+        plaintext = Box::new(plaintext.and(command.ilike(format!("%{}%", escape_like(text)))));
+    }
+    if let Some(ref pwd) = q.pwd {
+        plaintext = Box::new(plaintext.and(normalized_directory.eq(pwd.clone())));
+    }
+    let mut found: Candidates =
+        Box::new(plaintext.or(key_id.is_not_null().and(search_index.is_null())));
+    for (key, hashes) in keys.search_query(q) {
+        found = Box::new(
+            found.or(key_id
+                .eq(key.to_string())
+                .and(search_index.contains(hashes))),
+        );
+    }
+    found
+}
+
+/// Loads the histories matching `q`, most recent first, filtering on the
+/// columns encrypted at rest once decrypted. Only the `MAX_SCANNED` most
+/// recent candidates are decrypted, so matches past them are missed.
+fn scan(
+    conn: &mut PgConnection,
+    q: &models::SearchQuery,
+    keys: &at_rest::Keyring,
+) -> Result<Vec<models::History>, diesel::result::Error> {
+    use crate::schema::histories::dsl::*;
+
+    let _timer = metrics::query_timer("scan");
+
+    let mut found = with_filters(histories.into_boxed(), &database_filters(q, keys))
+        .filter(candidates(q, keys))
+        .order((updated_at.desc(), id.desc()))
+        .limit(MAX_SCANNED)
+        .load::<models::History>(conn)?;
+    decrypt(keys, &mut found)?;
+    found.retain(|h| matches_encrypted_filters(q, h));
+    Ok(found)
+}
+
+/// Like `search`, decrypting the histories encrypted at rest. The database
+/// can't filter on their command, nor on their directories if those are
+/// encrypted too, so when `q` does, the candidates their search index
+/// narrows them down to are decrypted and filtered here. The total is then
+/// that of the scanned candidates, a lower bound past `MAX_SCANNED`.
+pub fn search_decrypted(
+    conn: &mut PgConnection,
+    q: &models::SearchQuery,
+    keys: &at_rest::Keyring,
+) -> Result<(Vec<models::History>, i64), diesel::result::Error> {
+    if !needs_scan(q, keys) {
+        let (mut results, total) = search(conn, q)?;
+        decrypt(keys, &mut results)?;
+        return Ok((results, total));
+    }
+
+    let found = scan(conn, q, keys)?;
+    let total = found.len() as i64;
+    let results = found
+        .into_iter()
+        .skip(q.effective_offset() as usize)
+        .take(q.effective_limit() as usize)
+        .collect();
+    Ok((results, total))
+}
+
+define_sql_function!(
+    fn coalesce<T: diesel::sql_types::SqlType + diesel::sql_types::SingleValue>(
+        x: diesel::sql_types::Nullable<T>,
+        y: diesel::sql_types::Nullable<T>,
+    ) -> diesel::sql_types::Nullable<T>
+);
+
+define_sql_function!(
+    fn greatest<T: diesel::sql_types::SqlType + diesel::sql_types::SingleValue>(x: T, y: T) -> T
+);
+
+define_sql_function!(
+    fn least<T: diesel::sql_types::SqlType + diesel::sql_types::SingleValue>(x: T, y: T) -> T
+);
+
+/// Records a run, into the history of the same command run in the same
+/// directory on the same host if there is one. Returns the id of the
+/// history along with the run as recorded.
 pub fn create_history(
     conn: &mut PgConnection,
-    h: &str,
-    w: &str,
-    c: &str,
-) -> Result<models::NewHistory, diesel::result::Error> {
+    h: &models::NewHistory,
+) -> Result<(i32, models::NewHistory), diesel::result::Error> {
     use crate::schema::histories::dsl::*;
 
+    let _timer = metrics::query_timer("create_history");
+
     let new_history = models::NewHistory {
-        hostname: h.to_string(),
-        working_directory: w.to_string(),
-        command: c.to_string(),
+        hostname: h.hostname.clone(),
+        working_directory: h.working_directory.clone(),
+        command: h.command.clone(),
+        git_remote: h.git_remote.as_deref().map(canonical_git_remote),
+        git_branch: h.git_branch.clone(),
+        git_root: h.git_root.clone(),
+        normalized_directory: Some(
+            h.normalized_directory
+                .clone()
+                .unwrap_or_else(|| h.working_directory.clone()),
+        ),
+        exit_status: h.exit_status,
+        encrypted: h.encrypted,
+        blind_index: h.blind_index.clone(),
+        key_id: h.key_id.clone(),
+        data_key: h.data_key.clone(),
+        content_hash: h
+            .content_hash
+            .clone()
+            .or_else(|| at_rest::content_hash(None, Some(&h.working_directory), &h.command)),
+        search_index: h.search_index.clone(),
+        executed_at: h.executed_at,
+        client_event_id: h.client_event_id.clone(),
     };
+    let failed = i32::from(new_history.exit_status.is_some_and(|status| status != 0));
 
-    diesel::insert_into(histories)
-        .values(&new_history)
-        .on_conflict((hostname, working_directory, command))
+    // Keep the previously recorded git context when a client without git
+    // support re-runs the same command. Encrypted fields are all replaced,
+    // as they must match the data key. Runs replayed late don't move the
+    // history back in time, nor replace what a later run recorded.
+    let latest = excluded(updated_at).ge(updated_at);
+    let sealed = excluded(key_id).is_not_null();
+    let history_id = diesel::insert_into(histories)
+        .values((
+            &new_history,
+            failure_count.eq(failed),
+            h.executed_at.map(|t| created_at.eq(t)),
+            h.executed_at.map(|t| updated_at.eq(t)),
+        ))
+        .on_conflict((hostname, content_hash))
         .do_update()
-        .set(updated_at.eq(now))
+        .set((
+            (
+                command.eq(excluded(command)),
+                working_directory.eq(excluded(working_directory)),
+                key_id.eq(excluded(key_id)),
+                data_key.eq(excluded(data_key)),
+                search_index.eq(excluded(search_index)),
+            ),
+            created_at.eq(least(created_at, excluded(created_at))),
+            updated_at.eq(greatest(updated_at, excluded(updated_at))),
+            deleted_at.eq(None::<chrono::DateTime<chrono::Utc>>),
+            run_count.eq(run_count + 1),
+            failure_count.eq(failure_count + excluded(failure_count)),
+            exit_status.eq(
+                case_when(latest, coalesce(excluded(exit_status), exit_status))
+                    .otherwise(exit_status),
+            ),
+            normalized_directory.eq(case_when(latest.or(sealed), excluded(normalized_directory))
+                .otherwise(normalized_directory)),
+            git_remote.eq(
+                case_when(latest, coalesce(excluded(git_remote), git_remote)).otherwise(git_remote),
+            ),
+            git_branch.eq(
+                case_when(latest, coalesce(excluded(git_branch), git_branch)).otherwise(git_branch),
+            ),
+            git_root
+                .eq(case_when(latest, coalesce(excluded(git_root), git_root)).otherwise(git_root)),
+        ))
+        .returning(id)
+        .get_result::<i32>(conn)?;
+
+    record_run(conn, history_id, h.executed_at, h.exit_status)?;
+    Ok((history_id, new_history))
+}
+
+/// Records a run of history `run_history_id`, for the time based buckets
+/// of `stats`. Runs without an execution time are taken as run now.
+fn record_run(
+    conn: &mut PgConnection,
+    run_history_id: i32,
+    run_executed_at: Option<chrono::DateTime<chrono::Utc>>,
+    run_exit_status: Option<i32>,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::runs::dsl::*;
+
+    diesel::insert_into(runs)
+        .values((
+            history_id.eq(run_history_id),
+            run_executed_at.map(|t| executed_at.eq(t)),
+            exit_status.eq(run_exit_status),
+        ))
+        .execute(conn)
+}
+
+/// Forgets the idempotency keys claimed more than `window` ago.
+pub fn forget_idempotency_keys(
+    conn: &mut PgConnection,
+    window: chrono::Duration,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::idempotency_keys::dsl::*;
+
+    let _timer = metrics::query_timer("forget_idempotency_keys");
+
+    diesel::delete(idempotency_keys.filter(created_at.lt(chrono::Utc::now() - window)))
+        .execute(conn)
+}
+
+/// Claims the idempotency key `key` of `host` for the request being
+/// processed, in the transaction it is processed in, as recording the run
+/// identified by `run_fingerprint`. Returns `None` if the key is new, or
+/// else the fingerprint of the run it was claimed for and what that request
+/// was answered with.
+pub fn claim_idempotency_key(
+    conn: &mut PgConnection,
+    host: &str,
+    key: &str,
+    run_fingerprint: &str,
+) -> Result<Option<(String, Option<serde_json::Value>)>, diesel::result::Error> {
+    use crate::schema::idempotency_keys::dsl::*;
+
+    let _timer = metrics::query_timer("claim_idempotency_key");
+
+    // Waits for a concurrent request with the same key to be done.
+    let inserted = diesel::insert_into(idempotency_keys)
+        .values((
+            hostname.eq(host),
+            id.eq(key),
+            fingerprint.eq(run_fingerprint),
+        ))
+        .on_conflict_do_nothing()
         .execute(conn)?;
+    if inserted == 1 {
+        return Ok(None);
+    }
+    idempotency_keys
+        .find((host, key))
+        .select((fingerprint, response))
+        .first(conn)
+        .map(Some)
+}
+
+/// Keeps what the request that claimed `key` of `host` was answered with.
+pub fn store_idempotent_response(
+    conn: &mut PgConnection,
+    host: &str,
+    key: &str,
+    body: &serde_json::Value,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::idempotency_keys::dsl::*;
 
-    Ok(new_history)
+    let _timer = metrics::query_timer("store_idempotent_response");
+
+    diesel::update(idempotency_keys.find((host, key)))
+        .set(response.eq(body))
+        .execute(conn)?;
+    Ok(())
 }
 
+/// Moves a history to the trash, or removes it for good when `purge` is
+/// set. Purging also works on histories that are already in the trash.
 pub fn delete_history(
     conn: &mut PgConnection,
     history_id: i32,
+    purge: bool,
 ) -> Result<models::DeletedHistoryCount, diesel::result::Error> {
     use crate::schema::histories::dsl::*;
 
-    let deleted_count = diesel::delete(histories.filter(id.eq(history_id))).execute(conn)?;
+    let _timer = metrics::query_timer("delete_history");
+
+    let deleted_count = if purge {
+        diesel::delete(histories.filter(id.eq(history_id))).execute(conn)?
+    } else {
+        diesel::update(
+            histories
+                .filter(id.eq(history_id))
+                .filter(deleted_at.is_null()),
+        )
+        .set(deleted_at.eq(now))
+        .execute(conn)?
+    };
     let deleted_history_count = models::DeletedHistoryCount {
         count: deleted_count,
         message: String::from("Successfully deleted"),
@@ -89,6 +490,715 @@ pub fn delete_history(
     Ok(deleted_history_count)
 }
 
+/// Takes a history back out of the trash, returning it if it was there.
+pub fn restore_history(
+    conn: &mut PgConnection,
+    history_id: i32,
+) -> Result<Option<models::History>, diesel::result::Error> {
+    use crate::schema::histories::dsl::*;
+
+    let _timer = metrics::query_timer("restore_history");
+
+    diesel::update(
+        histories
+            .filter(id.eq(history_id))
+            .filter(deleted_at.is_not_null()),
+    )
+    .set(deleted_at.eq(None::<chrono::DateTime<chrono::Utc>>))
+    .get_result::<models::History>(conn)
+    .optional()
+}
+
+/// SQL condition on `runs`, for them to have run within the `since` and
+/// `until` of `q`.
+fn runs_in_range(q: &models::SearchQuery) -> String {
+    // Timestamps are formatted by chrono, so they can't break out of the
+    // literals.
+    let mut range = "true".to_string();
+    if let Some(since) = q.since {
+        range.push_str(&format!(
+            " and runs.executed_at >= '{}'",
+            since.to_rfc3339()
+        ));
+    }
+    if let Some(until) = q.until {
+        range.push_str(&format!(" and runs.executed_at < '{}'", until.to_rfc3339()));
+    }
+    range
+}
+
+/// The filters of `q` on histories, with those on when they ran left to
+/// `runs_in_range`.
+fn without_range(q: &models::SearchQuery) -> models::SearchQuery {
+    models::SearchQuery {
+        since: None,
+        until: None,
+        ..q.clone()
+    }
+}
+
+/// Aggregates the histories matching `q` into usage statistics. With
+/// `since` or `until`, only the runs within them are counted, and only the
+/// histories that ran within them.
+pub fn stats(
+    conn: &mut PgConnection,
+    q: &models::SearchQuery,
+    top: i64,
+) -> Result<models::Stats, diesel::result::Error> {
+    use crate::schema::histories::dsl::*;
+    use crate::schema::runs;
+    use diesel::sql_types::{BigInt, Nullable};
+
+    let _timer = metrics::query_timer("stats");
+
+    // Histories count all their runs, including those recorded before
+    // runs were kept individually, unless only some of them are in range.
+    let windowed = q.since.is_some() || q.until.is_some();
+    let range = format!("runs.history_id = histories.id and {}", runs_in_range(q));
+    let (runs_sum, failures_sum) = if windowed {
+        (
+            format!("sum((select count(*) from runs where {range}))::bigint"),
+            format!(
+                "sum((select count(*) from runs where {range} and runs.exit_status <> 0))::bigint"
+            ),
+        )
+    } else {
+        (
+            "sum(histories.run_count)".to_string(),
+            "sum(histories.failure_count)".to_string(),
+        )
+    };
+    let runs = || sql::<Nullable<BigInt>>(&runs_sum);
+    let failures = || sql::<Nullable<BigInt>>(&failures_sum);
+    let entries = |rows: Vec<(String, Option<i64>, Option<i64>)>| {
+        rows.into_iter()
+            .map(|(key, count, failed)| {
+                models::StatsEntry::new(key, count.unwrap_or(0), failed.unwrap_or(0))
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Boxed queries cannot be grouped, so the filters are applied through a
+    // subquery instead.
+    let unranged = without_range(q);
+    let ran_in_range = sql::<Bool>(&format!("exists (select from runs where {range})"));
+    let matching = || {
+        histories
+            .filter(id.eq_any(with_filters(histories.into_boxed(), &unranged).select(id)))
+            .filter(ran_in_range.clone())
+    };
+
+    let (total_runs, total_failures) = matching()
+        .select((runs(), failures()))
+        .get_result::<(Option<i64>, Option<i64>)>(conn)?;
+
+    let top_commands = matching()
+        .group_by(command)
+        .select((command, runs(), failures()))
+        .order((runs().desc(), command))
+        .limit(top)
+        .load(conn)?;
+
+    // Diesel can only group by columns, so computed keys are SQL literals.
+    let program = sql::<Text>("split_part(btrim(command), ' ', 1)");
+    let top_programs = matching()
+        .group_by(program.clone())
+        .select((program.clone(), runs(), failures()))
+        .order((runs().desc(), program))
+        .limit(top)
+        .load(conn)?;
+
+    let per_host = matching()
+        .group_by(hostname)
+        .select((hostname, runs(), failures()))
+        .order((runs().desc(), hostname))
+        .load(conn)?;
+
+    let per_directory = matching()
+        .filter(normalized_directory.is_not_null())
+        .group_by(normalized_directory)
+        .select((normalized_directory.assume_not_null(), runs(), failures()))
+        .order((runs().desc(), normalized_directory))
+        .limit(top)
+        .load(conn)?;
+
+    // Time based buckets count each run at the time it ran.
+    let in_range = sql::<Bool>(&runs_in_range(q));
+    let matching_runs = || {
+        runs::table
+            .filter(
+                runs::history_id.eq_any(with_filters(histories.into_boxed(), &unranged).select(id)),
+            )
+            .filter(in_range.clone())
+    };
+
+    let day = sql::<Date>("(executed_at at time zone 'UTC')::date");
+    let per_day = matching_runs()
+        .group_by(day.clone())
+        .select((day.clone(), count_star()))
+        .order(day)
+        .load::<(chrono::NaiveDate, i64)>(conn)?
+        .into_iter()
+        .map(|(d, count)| models::DayCount { day: d, count })
+        .collect();
+
+    let day_of_week = sql::<Integer>("extract(dow from executed_at at time zone 'UTC')::integer");
+    let hour = sql::<Integer>("extract(hour from executed_at at time zone 'UTC')::integer");
+    let heatmap = matching_runs()
+        .group_by((day_of_week.clone(), hour.clone()))
+        .select((day_of_week.clone(), hour.clone(), count_star()))
+        .order((day_of_week, hour))
+        .load::<(i32, i32, i64)>(conn)?
+        .into_iter()
+        .map(|(dow, h, count)| models::HeatmapCell {
+            day_of_week: dow,
+            hour: h,
+            count,
+        })
+        .collect();
+
+    Ok(models::Stats {
+        total: models::StatsEntry::new(
+            String::new(),
+            total_runs.unwrap_or(0),
+            total_failures.unwrap_or(0),
+        ),
+        top_commands: entries(top_commands),
+        top_programs: entries(top_programs),
+        per_host: entries(per_host),
+        per_directory: entries(per_directory),
+        per_day,
+        heatmap,
+    })
+}
+
+/// Counts the runs and failed runs of each of `history_ids` within the
+/// `since` and `until` of `q`.
+fn count_runs_in_range(
+    conn: &mut PgConnection,
+    history_ids: &[i32],
+    q: &models::SearchQuery,
+) -> Result<std::collections::HashMap<i32, (i64, i64)>, diesel::result::Error> {
+    use crate::schema::runs::dsl::*;
+
+    let failed = sql::<diesel::sql_types::BigInt>("count(*) filter (where runs.exit_status <> 0)");
+    let counts = runs
+        .filter(history_id.eq_any(history_ids))
+        .filter(sql::<Bool>(&runs_in_range(q)))
+        .group_by(history_id)
+        .select((history_id, count_star(), failed))
+        .load::<(i32, i64, i64)>(conn)?;
+    Ok(counts
+        .into_iter()
+        .map(|(h, count, failures)| (h, (count, failures)))
+        .collect())
+}
+
+/// Ranks decrypted histories by `key`, like the grouped queries of `stats`,
+/// given their runs and failed runs.
+fn rank(found: impl Iterator<Item = (String, (i64, i64))>, top: i64) -> Vec<models::StatsEntry> {
+    let mut totals = std::collections::HashMap::<String, (i64, i64)>::new();
+    for (key, (runs, failures)) in found {
+        let total = totals.entry(key).or_default();
+        total.0 += runs;
+        total.1 += failures;
+    }
+    let mut entries: Vec<_> = totals
+        .into_iter()
+        .map(|(key, (count, failed))| models::StatsEntry::new(key, count, failed))
+        .collect();
+    entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+    entries.truncate(usize::try_from(top).unwrap_or(0));
+    entries
+}
+
+/// Like `stats`, for histories encrypted at rest. Their ciphertext can't be
+/// grouped by, so the rankings by command and directory are made here from
+/// the decrypted histories, the `MAX_SCANNED` most recent ones. So are the
+/// other stats when filtering on encrypted columns.
+pub fn stats_decrypted(
+    conn: &mut PgConnection,
+    q: &models::SearchQuery,
+    top: i64,
+    keys: &at_rest::Keyring,
+) -> Result<models::Stats, diesel::result::Error> {
+    if !keys.is_enabled() {
+        return stats(conn, q, top);
+    }
+
+    let found = scan(conn, &without_range(q), keys)?;
+    let ids = found.iter().map(|h| h.id).collect::<Vec<_>>();
+    let mut stats = if needs_scan(q, keys) {
+        let database_query = models::SearchQuery {
+            pwd: None,
+            command: None,
+            ids: Some(ids.clone()),
+            ..q.clone()
+        };
+        stats(conn, &database_query, top)?
+    } else {
+        stats(conn, q, top)?
+    };
+
+    // Like `stats`, histories count all their runs unless only some of them
+    // are in range.
+    let in_range = if q.since.is_some() || q.until.is_some() {
+        Some(count_runs_in_range(conn, &ids, q)?)
+    } else {
+        None
+    };
+    let counted = found
+        .iter()
+        .filter_map(|h| match in_range {
+            Some(ref counts) => Some((h, *counts.get(&h.id)?)),
+            None => Some((h, (i64::from(h.run_count), i64::from(h.failure_count)))),
+        })
+        .collect::<Vec<_>>();
+
+    let program = |cmd: &str| {
+        let cmd = cmd.trim_matches(' ');
+        cmd.split(' ').next().unwrap_or(cmd).to_string()
+    };
+    stats.top_commands = rank(
+        counted
+            .iter()
+            .map(|&(h, counts)| (h.command.clone(), counts)),
+        top,
+    );
+    stats.top_programs = rank(
+        counted
+            .iter()
+            .map(|&(h, counts)| (program(&h.command), counts)),
+        top,
+    );
+    stats.per_directory = rank(
+        counted
+            .iter()
+            .filter_map(|&(h, counts)| Some((h.normalized_directory.clone()?, counts))),
+        top,
+    );
+    Ok(stats)
+}
+
+/// Returns the canonical name of the host that reports itself as `h`, and
+/// records that it has just been seen. Unknown hostnames are registered as
+/// hosts of their own.
+pub fn resolve_host(conn: &mut PgConnection, h: &str) -> Result<String, diesel::result::Error> {
+    use crate::schema::hosts::dsl::*;
+
+    let _timer = metrics::query_timer("resolve_host");
+
+    let canonical = hosts
+        .filter(aliases.contains(vec![h]))
+        .select(name)
+        .first::<String>(conn)
+        .optional()?
+        .unwrap_or_else(|| h.to_string());
+
+    diesel::insert_into(hosts)
+        .values((name.eq(&canonical), last_seen_at.eq(now)))
+        .on_conflict(name)
+        .do_update()
+        .set(last_seen_at.eq(now))
+        .execute(conn)?;
+
+    Ok(canonical)
+}
+
+pub fn find_host(
+    conn: &mut PgConnection,
+    host_name: &str,
+) -> Result<Option<models::Host>, diesel::result::Error> {
+    use crate::schema::hosts::dsl::*;
+
+    let _timer = metrics::query_timer("find_host");
+
+    hosts
+        .filter(name.eq(host_name))
+        .first::<models::Host>(conn)
+        .optional()
+}
+
+/// Sets the aliases and tags of host `host_name`, creating it if needed.
+/// Hosts that were registered under one of the new aliases are merged into
+/// it, and their names returned along with the host.
+pub fn update_host(
+    conn: &mut PgConnection,
+    host_name: &str,
+    update: &models::HostUpdate,
+) -> Result<(models::Host, Vec<String>), diesel::result::Error> {
+    use crate::schema::hosts::dsl::*;
+
+    let _timer = metrics::query_timer("update_host");
+
+    conn.transaction(|conn| {
+        let merged = diesel::delete(
+            hosts
+                .filter(name.eq_any(&update.aliases))
+                .filter(name.ne(host_name)),
+        )
+        .returning(name)
+        .get_results::<String>(conn)?;
+
+        let host = diesel::insert_into(hosts)
+            .values((
+                name.eq(host_name),
+                aliases.eq(&update.aliases),
+                tags.eq(&update.tags),
+            ))
+            .on_conflict(name)
+            .do_update()
+            .set((
+                aliases.eq(&update.aliases),
+                tags.eq(&update.tags),
+                updated_at.eq(now),
+            ))
+            .get_result::<models::Host>(conn)?;
+        Ok((host, merged))
+    })
+}
+
+pub fn list_hosts(
+    conn: &mut PgConnection,
+) -> Result<Vec<models::HostSummary>, diesel::result::Error> {
+    let _timer = metrics::query_timer("list_hosts");
+
+    diesel::sql_query(
+        "select h.name, h.aliases, h.tags, h.last_seen_at
+           , count(hi.id) as command_count, max(hi.updated_at) as last_activity
+         from hosts h
+         left join histories hi
+           on (hi.hostname = h.name or hi.hostname = any(h.aliases)) and hi.deleted_at is null
+         group by h.id
+         order by last_activity desc nulls last, h.name",
+    )
+    .load::<models::HostSummary>(conn)
+}
+
+/// Counts the histories that are not in the trash.
+pub fn count_histories(conn: &mut PgConnection) -> Result<i64, diesel::result::Error> {
+    use crate::schema::histories::dsl::*;
+
+    let _timer = metrics::query_timer("count_histories");
+
+    histories
+        .filter(deleted_at.is_null())
+        .count()
+        .get_result(conn)
+}
+
+/// Returns the ids of the keys histories are encrypted at rest with.
+pub fn key_ids(conn: &mut PgConnection) -> Result<Vec<String>, diesel::result::Error> {
+    use crate::schema::histories::dsl::*;
+
+    let _timer = metrics::query_timer("key_ids");
+
+    histories
+        .select(key_id.assume_not_null())
+        .filter(key_id.is_not_null())
+        .distinct()
+        .load(conn)
+}
+
+/// Re-encrypts with the current key up to `limit` histories after id
+/// `after` that aren't encrypted with it yet, or all of them with `all`.
+///
+/// Content hashes change with the key, so a history recorded again under
+/// the new key may already exist. The older one is merged into it.
+pub fn rotate_keys(
+    conn: &mut PgConnection,
+    keys: &at_rest::Keyring,
+    after: i32,
+    limit: i64,
+    all: bool,
+) -> Result<models::RotatedBatch, diesel::result::Error> {
+    use crate::schema::histories::dsl::*;
+    use crate::schema::runs;
+
+    let _timer = metrics::query_timer("rotate_keys");
+
+    let current = keys.current().expect("rotating keys requires encryption");
+    conn.transaction(|conn| {
+        // Locking queries can't be boxed, so `all` is a query parameter.
+        let mut found = histories
+            .filter(id.gt(after))
+            .filter(key_id.is_distinct_from(current).or(all.into_sql::<Bool>()))
+            .order(id)
+            .limit(limit)
+            .for_update()
+            .load::<models::History>(conn)?;
+        decrypt(keys, &mut found)?;
+
+        let mut batch = models::RotatedBatch {
+            last_id: found.last().map(|h| h.id),
+            ..Default::default()
+        };
+        for mut h in found {
+            keys.reseal(&mut h);
+            let twin = histories
+                .filter(hostname.eq(&h.hostname))
+                .filter(content_hash.eq(&h.content_hash))
+                .filter(id.ne(h.id))
+                .for_update()
+                .first::<models::History>(conn)
+                .optional()?;
+
+            if let Some(twin) = twin {
+                // The merged history is live if either was, and holds the
+                // context of the most recent run, as `create_history` would.
+                let (newer, older) = if h.updated_at > twin.updated_at {
+                    (&h, &twin)
+                } else {
+                    (&twin, &h)
+                };
+                let deleted = match (h.deleted_at, twin.deleted_at) {
+                    (Some(a), Some(b)) => Some(a.max(b)),
+                    _ => None,
+                };
+                diesel::update(histories.filter(id.eq(twin.id)))
+                    .set((
+                        run_count.eq(run_count + h.run_count),
+                        failure_count.eq(failure_count + h.failure_count),
+                        created_at.eq(h.created_at.min(twin.created_at)),
+                        updated_at.eq(newer.updated_at),
+                        deleted_at.eq(deleted),
+                        exit_status.eq(newer.exit_status.or(older.exit_status)),
+                        git_remote.eq(newer.git_remote.as_ref().or(older.git_remote.as_ref())),
+                        git_branch.eq(newer.git_branch.as_ref().or(older.git_branch.as_ref())),
+                        git_root.eq(newer.git_root.as_ref().or(older.git_root.as_ref())),
+                    ))
+                    .execute(conn)?;
+                diesel::update(runs::table.filter(runs::history_id.eq(h.id)))
+                    .set(runs::history_id.eq(twin.id))
+                    .execute(conn)?;
+                diesel::delete(histories.filter(id.eq(h.id))).execute(conn)?;
+                batch.merged += 1;
+            } else {
+                diesel::update(histories.filter(id.eq(h.id)))
+                    .set((
+                        command.eq(&h.command),
+                        working_directory.eq(&h.working_directory),
+                        normalized_directory.eq(&h.normalized_directory),
+                        key_id.eq(&h.key_id),
+                        data_key.eq(&h.data_key),
+                        content_hash.eq(&h.content_hash),
+                        search_index.eq(&h.search_index),
+                    ))
+                    .execute(conn)?;
+                batch.reencrypted += 1;
+            }
+        }
+        Ok(batch)
+    })
+}
+
+/// Normalizes again the working directories of up to `limit` histories
+/// after id `after`, e.g. after `path_mappings` changed, updating those
+/// whose normalized directory differs.
+pub fn renormalize(
+    conn: &mut PgConnection,
+    keys: &at_rest::Keyring,
+    path_mappings: &PathMappings,
+    after: i32,
+    limit: i64,
+) -> Result<models::RenormalizedBatch, diesel::result::Error> {
+    use crate::schema::histories::dsl::*;
+
+    let _timer = metrics::query_timer("renormalize");
+
+    conn.transaction(|conn| {
+        let found = histories
+            .filter(id.gt(after))
+            .order(id)
+            .limit(limit)
+            .for_update()
+            .load::<models::History>(conn)?;
+
+        let mut batch = models::RenormalizedBatch {
+            last_id: found.last().map(|h| h.id),
+            ..Default::default()
+        };
+        for stored in found {
+            let mut h = stored.clone();
+            decrypt(keys, [&mut h])?;
+            let Some(ref directory) = h.working_directory else {
+                continue;
+            };
+            let normalized = path_mappings.normalize(Some(&h.hostname), directory);
+            if h.normalized_directory.as_deref() == Some(normalized.as_str()) {
+                continue;
+            }
+            let error = |e: String| diesel::result::Error::SerializationError(e.into());
+            let sealed = keys
+                .seal_normalized_directory(&stored, &normalized)
+                .map_err(error)?;
+            let index = keys
+                .reindex(&stored, Some(&normalized), &h.command)
+                .map_err(error)?;
+            diesel::update(histories.filter(id.eq(stored.id)))
+                .set((normalized_directory.eq(sealed), search_index.eq(index)))
+                .execute(conn)?;
+            batch.updated += 1;
+        }
+        Ok(batch)
+    })
+}
+
+pub fn list_webhooks(
+    conn: &mut PgConnection,
+) -> Result<Vec<models::Webhook>, diesel::result::Error> {
+    use crate::schema::webhooks::dsl::*;
+
+    let _timer = metrics::query_timer("list_webhooks");
+
+    webhooks.order(id).load::<models::Webhook>(conn)
+}
+
+pub fn find_webhook(
+    conn: &mut PgConnection,
+    webhook_id: i32,
+) -> Result<Option<models::Webhook>, diesel::result::Error> {
+    use crate::schema::webhooks::dsl::*;
+
+    let _timer = metrics::query_timer("find_webhook");
+
+    webhooks
+        .filter(id.eq(webhook_id))
+        .first::<models::Webhook>(conn)
+        .optional()
+}
+
+pub fn create_webhook(
+    conn: &mut PgConnection,
+    w: &models::NewWebhook,
+) -> Result<models::Webhook, diesel::result::Error> {
+    use crate::schema::webhooks::dsl::*;
+
+    let _timer = metrics::query_timer("create_webhook");
+
+    diesel::insert_into(webhooks)
+        .values(w)
+        .get_result::<models::Webhook>(conn)
+}
+
+pub fn update_webhook(
+    conn: &mut PgConnection,
+    webhook_id: i32,
+    w: &models::NewWebhook,
+) -> Result<Option<models::Webhook>, diesel::result::Error> {
+    use crate::schema::webhooks::dsl::*;
+
+    let _timer = metrics::query_timer("update_webhook");
+
+    diesel::update(webhooks.filter(id.eq(webhook_id)))
+        .set((w, updated_at.eq(now)))
+        .get_result::<models::Webhook>(conn)
+        .optional()
+}
+
+pub fn delete_webhook(
+    conn: &mut PgConnection,
+    webhook_id: i32,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::webhooks::dsl::*;
+
+    let _timer = metrics::query_timer("delete_webhook");
+
+    diesel::delete(webhooks.filter(id.eq(webhook_id))).execute(conn)
+}
+
+/// Whether Postgres accepts `pattern` as a regular expression, so that a
+/// bad webhook pattern can't break `matching_webhooks` for everyone.
+pub fn is_valid_pattern(
+    conn: &mut PgConnection,
+    pattern: &str,
+) -> Result<bool, diesel::result::Error> {
+    let _timer = metrics::query_timer("is_valid_pattern");
+
+    let check = diesel::select(sql::<diesel::sql_types::Bool>("'' ~ ").bind::<Text, _>(pattern));
+    match check.get_result::<bool>(conn) {
+        Ok(_) => Ok(true),
+        Err(diesel::result::Error::DatabaseError(_, _)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the active webhooks subscribed to `event` whose pattern matches
+/// `cmd`.
+pub fn matching_webhooks(
+    conn: &mut PgConnection,
+    event: &str,
+    cmd: &str,
+) -> Result<Vec<models::Webhook>, diesel::result::Error> {
+    use crate::schema::webhooks::dsl::*;
+
+    let _timer = metrics::query_timer("matching_webhooks");
+
+    webhooks
+        .filter(active.eq(true))
+        .filter(events.contains(vec![event]))
+        .filter(
+            sql::<diesel::sql_types::Bool>("(command_pattern is null or ")
+                .bind::<Text, _>(cmd)
+                .sql(" ~ command_pattern)"),
+        )
+        .order(id)
+        .load::<models::Webhook>(conn)
+}
+
+pub fn record_audit(
+    conn: &mut PgConnection,
+    event: &models::NewAuditEvent,
+) -> Result<models::AuditEvent, diesel::result::Error> {
+    use crate::schema::audit_events::dsl::*;
+
+    let _timer = metrics::query_timer("record_audit");
+
+    diesel::insert_into(audit_events)
+        .values(event)
+        .get_result::<models::AuditEvent>(conn)
+}
+
+/// Returns the audit events matching `q`, newest first, and how many there
+/// are in all.
+pub fn list_audit(
+    conn: &mut PgConnection,
+    q: &models::AuditQuery,
+) -> Result<(Vec<models::AuditEvent>, i64), diesel::result::Error> {
+    use crate::schema::audit_events::dsl::*;
+
+    let _timer = metrics::query_timer("list_audit");
+
+    let filtered = || {
+        let mut query = audit_events.into_boxed();
+        if let Some(ref a) = q.action {
+            query = query.filter(action.eq(a));
+        }
+        if let Some(ref a) = q.actor {
+            query = query.filter(actor.eq(a));
+        }
+        if let Some(history_id) = q.history_id {
+            query = query.filter(history_ids.contains(vec![history_id]));
+        }
+        if let Some(since) = q.since {
+            query = query.filter(created_at.ge(since));
+        }
+        if let Some(until) = q.until {
+            query = query.filter(created_at.lt(until));
+        }
+        query
+    };
+
+    let total: i64 = filtered().count().get_result(conn)?;
+    let results = filtered()
+        .order(id.desc())
+        .limit(q.effective_limit())
+        .offset(q.effective_offset())
+        .load::<models::AuditEvent>(conn)?;
+
+    Ok((results, total))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -112,9 +1222,98 @@ mod tests {
             hostname: hostname.map(str::to_string),
             limit,
             offset,
+            ..Default::default()
+        }
+    }
+
+    fn new_history(h: &str, w: &str, c: &str) -> models::NewHistory {
+        models::NewHistory {
+            hostname: h.to_string(),
+            working_directory: w.to_string(),
+            command: c.to_string(),
+            ..Default::default()
         }
     }
 
+    #[test]
+    fn test_scan_filters_plaintext_directories_in_the_database() {
+        let keys = |working_directory| {
+            at_rest::Keyring::load(&at_rest::EncryptionConfig {
+                current: "k".to_string(),
+                working_directory,
+                keys: [("k".to_string(), "0123456789abcdef".repeat(4))].into(),
+            })
+            .unwrap()
+        };
+        let q = make_query(Some("/srv/app"), Some("host"), None, None);
+
+        assert!(!needs_scan(&q, &keys(false)));
+        assert!(needs_scan(&q, &keys(true)));
+        assert!(!needs_scan(&q, &at_rest::Keyring::default()));
+
+        let q = models::SearchQuery {
+            command: Some("cargo".to_string()),
+            ..q
+        };
+        assert!(needs_scan(&q, &keys(false)));
+        let filters = database_filters(&q, &keys(false));
+        assert_eq!(filters.pwd.as_deref(), Some("/srv/app"));
+        assert!(filters.command.is_none());
+        assert!(database_filters(&q, &keys(true)).pwd.is_none());
+    }
+
+    #[test]
+    fn test_renormalize_keeps_encryption() {
+        let keys = at_rest::Keyring::load(&at_rest::EncryptionConfig {
+            current: "k".to_string(),
+            working_directory: true,
+            keys: [("k".to_string(), "0123456789abcdef".repeat(4))].into(),
+        })
+        .unwrap();
+        let path_mappings: PathMappings =
+            toml::from_str("canonicalize_home = true\nhome_prefixes = [\"/home/*\"]").unwrap();
+
+        let mut conn = setup();
+        conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
+            let mut plain = new_history("renormalize-host", "/home/alice/plain", "ls");
+            let (plain_id, _) = create_history(conn, &plain)?;
+            plain.working_directory = "/home/alice/sealed".to_string();
+            keys.seal(&mut plain);
+            let (sealed_id, _) = create_history(conn, &plain)?;
+            assert!(plain_id < sealed_id);
+
+            let load = |conn: &mut PgConnection| {
+                use crate::schema::histories::dsl::*;
+                histories
+                    .filter(id.eq_any([plain_id, sealed_id]))
+                    .order(id)
+                    .load::<models::History>(conn)
+            };
+            let before = load(conn)?;
+
+            let batch = renormalize(conn, &keys, &path_mappings, plain_id - 1, 2)?;
+            assert_eq!(batch.last_id, Some(sealed_id));
+            assert_eq!(batch.updated, 2);
+            let batch = renormalize(conn, &keys, &path_mappings, plain_id - 1, 2)?;
+            assert_eq!(batch.updated, 0, "nothing is left to normalize");
+
+            let mut after = load(conn)?;
+            assert_eq!(after[0].normalized_directory.as_deref(), Some("~/plain"));
+            let sealed = after[1].normalized_directory.as_deref().unwrap();
+            assert!(sealed.starts_with(at_rest::PREFIX));
+            assert_eq!(after[1].key_id, before[1].key_id);
+            assert_eq!(after[1].content_hash, before[1].content_hash);
+            decrypt(&keys, &mut after)?;
+            assert_eq!(after[1].normalized_directory.as_deref(), Some("~/sealed"));
+            assert_eq!(
+                after[1].working_directory.as_deref(),
+                Some("/home/alice/sealed")
+            );
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn test_create_and_search_history() {
         let mut conn = setup();
@@ -123,10 +1322,10 @@ mod tests {
             let w = "/test/dir";
             let c = "test command";
 
-            let new_history = create_history(conn, h, w, c)?;
-            assert_eq!(new_history.hostname, h);
-            assert_eq!(new_history.working_directory, w);
-            assert_eq!(new_history.command, c);
+            let (_, created) = create_history(conn, &new_history(h, w, c))?;
+            assert_eq!(created.hostname, h);
+            assert_eq!(created.working_directory, w);
+            assert_eq!(created.command, c);
 
             let q = make_query(Some(w), None, None, None);
             let (results, total) = search(conn, &q)?;
@@ -150,14 +1349,14 @@ mod tests {
             let w = "/delete/dir";
             let c = "delete command";
 
-            create_history(conn, h, w, c)?;
+            create_history(conn, &new_history(h, w, c))?;
 
             let q = make_query(Some(w), None, None, None);
             let (results, _) = search(conn, &q)?;
             assert_eq!(results.len(), 1);
             let history_to_delete = &results[0];
 
-            let delete_result = delete_history(conn, history_to_delete.id)?;
+            let delete_result = delete_history(conn, history_to_delete.id, false)?;
             assert_eq!(delete_result.count, 1);
 
             let find_result = find(conn, history_to_delete.id)?;
@@ -175,7 +1374,7 @@ mod tests {
             let w = "/upsert/dir";
             let c = "upsert command";
 
-            create_history(conn, h, w, c)?;
+            create_history(conn, &new_history(h, w, c))?;
             diesel::sql_query("COMMIT;").execute(conn)?;
             let q_all = make_query(None, None, Some(10000), None);
             let (results1, _) = search(conn, &q_all)?;
@@ -186,7 +1385,7 @@ mod tests {
                 .updated_at;
 
             std::thread::sleep(std::time::Duration::from_secs(1));
-            create_history(conn, h, w, c)?;
+            create_history(conn, &new_history(h, w, c))?;
 
             let q = make_query(Some(w), None, None, None);
             let (results2, total) = search(conn, &q)?;
@@ -207,9 +1406,9 @@ mod tests {
         let mut conn = setup();
         conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
             let w = "/pagination/dir";
-            create_history(conn, "host-a", w, "cmd-alpha")?;
-            create_history(conn, "host-b", w, "cmd-beta")?;
-            create_history(conn, "host-c", w, "cmd-gamma")?;
+            create_history(conn, &new_history("host-a", w, "cmd-alpha"))?;
+            create_history(conn, &new_history("host-b", w, "cmd-beta"))?;
+            create_history(conn, &new_history("host-c", w, "cmd-gamma"))?;
 
             // First page: 2 items
             let q1 = make_query(Some(w), None, Some(2), Some(0));
@@ -237,8 +1436,8 @@ mod tests {
         let mut conn = setup();
         conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
             let w = "/hostname/dir";
-            create_history(conn, "target-host", w, "cmd-for-target")?;
-            create_history(conn, "other-host", w, "cmd-for-other")?;
+            create_history(conn, &new_history("target-host", w, "cmd-for-target"))?;
+            create_history(conn, &new_history("other-host", w, "cmd-for-other"))?;
 
             let q = make_query(None, Some("target-host"), None, None);
             let (results, total) = search(conn, &q)?;
@@ -257,7 +1456,7 @@ mod tests {
         conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
             let w = "/count/dir";
             for i in 0..5 {
-                create_history(conn, "count-host", w, &format!("cmd-{i}"))?;
+                create_history(conn, &new_history("count-host", w, &format!("cmd-{i}")))?;
             }
 
             // limit=2 but total should reflect all 5
@@ -276,9 +1475,9 @@ mod tests {
         let mut conn = setup();
         conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
             let w = "/combo/dir";
-            create_history(conn, "combo-host", w, "combo-cmd")?;
-            create_history(conn, "combo-host", "/other/dir", "other-cmd")?;
-            create_history(conn, "other-host", w, "yet-other-cmd")?;
+            create_history(conn, &new_history("combo-host", w, "combo-cmd"))?;
+            create_history(conn, &new_history("combo-host", "/other/dir", "other-cmd"))?;
+            create_history(conn, &new_history("other-host", w, "yet-other-cmd"))?;
 
             let q = make_query(Some(w), Some("combo-host"), None, None);
             let (results, total) = search(conn, &q)?;
@@ -291,6 +1490,277 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_search_repo_and_branch_filters() {
+        let mut conn = setup();
+        conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
+            let mut on_main = new_history("repo-host-a", "/home/a/src/x", "repo-cmd-main");
+            on_main.git_remote = Some("git@github.com:example/x.git".to_string());
+            on_main.git_branch = Some("main".to_string());
+            create_history(conn, &on_main)?;
+
+            let mut on_topic = new_history("repo-host-b", "/Users/a/x", "repo-cmd-topic");
+            on_topic.git_remote = Some("https://github.com/example/x".to_string());
+            on_topic.git_branch = Some("topic".to_string());
+            create_history(conn, &on_topic)?;
+
+            create_history(conn, &new_history("repo-host-a", "/tmp", "repo-cmd-none"))?;
+
+            let q = models::SearchQuery {
+                repo: Some("ssh://git@github.com/example/x.git".to_string()),
+                ..Default::default()
+            };
+            let (results, total) = search(conn, &q)?;
+            assert_eq!(total, 2);
+            assert!(results
+                .iter()
+                .all(|h| h.git_remote.as_deref() == Some("github.com/example/x")));
+
+            let q = models::SearchQuery {
+                repo: Some("github.com/example/x".to_string()),
+                branch: Some("topic".to_string()),
+                ..Default::default()
+            };
+            let (results, total) = search(conn, &q)?;
+            assert_eq!(total, 1);
+            assert_eq!(results[0].command, "repo-cmd-topic");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_upsert_keeps_git_context() {
+        let mut conn = setup();
+        conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
+            let mut with_git = new_history("git-upsert-host", "/git/upsert", "git upsert");
+            with_git.git_remote = Some("git@example.com:team/repo.git".to_string());
+            with_git.git_branch = Some("main".to_string());
+            with_git.git_root = Some("/git/upsert".to_string());
+            create_history(conn, &with_git)?;
+            create_history(
+                conn,
+                &new_history("git-upsert-host", "/git/upsert", "git upsert"),
+            )?;
+
+            let q = make_query(Some("/git/upsert"), None, None, None);
+            let (results, _) = search(conn, &q)?;
+            assert_eq!(results.len(), 1);
+            assert_eq!(
+                results[0].git_remote.as_deref(),
+                Some("example.com/team/repo")
+            );
+            assert_eq!(results[0].git_branch.as_deref(), Some("main"));
+            assert_eq!(results[0].git_root.as_deref(), Some("/git/upsert"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_resolve_host_through_aliases() {
+        let mut conn = setup();
+        conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
+            assert_eq!(
+                resolve_host(conn, "resolve-laptop.example.com")?,
+                "resolve-laptop.example.com"
+            );
+
+            let update = models::HostUpdate {
+                aliases: vec!["resolve-laptop.example.com".to_string()],
+                tags: vec!["laptop".to_string()],
+            };
+            let (host, merged) = update_host(conn, "resolve-laptop", &update)?;
+            assert_eq!(host.name, "resolve-laptop");
+            assert_eq!(host.tags, vec!["laptop"]);
+            assert_eq!(merged, ["resolve-laptop.example.com"]);
+
+            assert_eq!(
+                resolve_host(conn, "resolve-laptop.example.com")?,
+                "resolve-laptop"
+            );
+            assert_eq!(resolve_host(conn, "resolve-laptop")?, "resolve-laptop");
+
+            create_history(
+                conn,
+                &new_history("resolve-laptop.example.com", "/h", "old"),
+            )?;
+            create_history(conn, &new_history("resolve-laptop", "/h", "new"))?;
+
+            let summaries = list_hosts(conn)?;
+            assert!(!summaries
+                .iter()
+                .any(|h| h.name == "resolve-laptop.example.com"));
+            let summary = summaries
+                .iter()
+                .find(|h| h.name == "resolve-laptop")
+                .expect("host should be listed");
+            assert_eq!(summary.command_count, 2);
+            assert!(summary.last_seen_at.is_some());
+            assert!(summary.last_activity.is_some());
+
+            // Histories recorded under an alias are the host's too.
+            let (found, total) =
+                search(conn, &make_query(None, Some("resolve-laptop"), None, None))?;
+            assert_eq!(total, 2);
+            assert!(found
+                .iter()
+                .any(|h| h.hostname == "resolve-laptop.example.com"));
+            let (_, total) = search(
+                conn,
+                &make_query(None, Some("resolve-laptop.example.com"), None, None),
+            )?;
+            assert_eq!(total, 1, "aliases only match their own histories");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_search_command_filter() {
+        let mut conn = setup();
+        conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
+            let w = "/command-filter/dir";
+            create_history(conn, &new_history("cf-host", w, "git commit -m 'WIP'"))?;
+            create_history(conn, &new_history("cf-host", w, "echo 100% done"))?;
+            create_history(conn, &new_history("cf-host", w, "echo 100 done"))?;
+
+            let q = models::SearchQuery {
+                pwd: Some(w.to_string()),
+                command: Some("wip".to_string()),
+                ..Default::default()
+            };
+            let (results, _) = search(conn, &q)?;
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].command, "git commit -m 'WIP'");
+
+            let q = models::SearchQuery {
+                pwd: Some(w.to_string()),
+                command: Some("100%".to_string()),
+                ..Default::default()
+            };
+            let (results, _) = search(conn, &q)?;
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].command, "echo 100% done");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_restore_and_purge_history() {
+        let mut conn = setup();
+        conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
+            let w = "/trash/dir";
+            create_history(conn, &new_history("trash-host", w, "trash command"))?;
+            let (results, _) = search(conn, &make_query(Some(w), None, None, None))?;
+            let history_id = results[0].id;
+
+            delete_history(conn, history_id, false)?;
+            assert_eq!(delete_history(conn, history_id, false)?.count, 0);
+            assert_eq!(search(conn, &make_query(Some(w), None, None, None))?.1, 0);
+
+            let trash = models::SearchQuery {
+                pwd: Some(w.to_string()),
+                deleted: Some(true),
+                ..Default::default()
+            };
+            let (results, total) = search(conn, &trash)?;
+            assert_eq!(total, 1);
+            assert!(results[0].deleted_at.is_some());
+
+            let restored = restore_history(conn, history_id)?.expect("history should be restored");
+            assert!(restored.deleted_at.is_none());
+            assert!(restore_history(conn, history_id)?.is_none());
+            assert!(find(conn, history_id)?.is_some());
+
+            delete_history(conn, history_id, false)?;
+            assert_eq!(delete_history(conn, history_id, true)?.count, 1);
+            assert_eq!(search(conn, &trash)?.1, 0);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_search_date_range() {
+        let mut conn = setup();
+        conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
+            let w = "/date-range/dir";
+            create_history(conn, &new_history("range-host", w, "range command"))?;
+            let current = chrono::Utc::now();
+            let hour = chrono::Duration::hours(1);
+
+            let mut q = make_query(Some(w), None, None, None);
+            q.since = Some(current - hour);
+            q.until = Some(current + hour);
+            assert_eq!(search(conn, &q)?.1, 1);
+
+            q.since = Some(current + hour);
+            q.until = None;
+            assert_eq!(search(conn, &q)?.1, 0);
+
+            q.since = None;
+            q.until = Some(current - hour);
+            assert_eq!(search(conn, &q)?.1, 0);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_matching_webhooks() {
+        let mut conn = setup();
+        conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
+            use crate::schema::webhooks::dsl::*;
+
+            diesel::delete(webhooks).execute(conn)?;
+            let webhook =
+                |pattern: Option<&str>, event_types: &[&str], is_active: bool| models::NewWebhook {
+                    url: "http://example.com/hook".to_string(),
+                    secret: None,
+                    events: event_types.iter().map(|e| e.to_string()).collect(),
+                    command_pattern: pattern.map(str::to_string),
+                    active: is_active,
+                };
+            let all = create_webhook(conn, &webhook(None, &["created", "deleted"], true))?;
+            let rm = create_webhook(conn, &webhook(Some(r"^rm\s+-rf"), &["created"], true))?;
+            create_webhook(conn, &webhook(None, &["created"], false))?;
+
+            let ids = |hooks: Vec<models::Webhook>| hooks.iter().map(|w| w.id).collect::<Vec<_>>();
+            assert_eq!(ids(matching_webhooks(conn, "created", "ls -la")?), [all.id]);
+            assert_eq!(
+                ids(matching_webhooks(conn, "created", "rm -rf /")?),
+                [all.id, rm.id]
+            );
+            assert_eq!(
+                ids(matching_webhooks(conn, "deleted", "rm -rf /")?),
+                [all.id]
+            );
+
+            assert!(is_valid_pattern(conn, r"^kubectl\s+delete")?);
+            assert!(!is_valid_pattern(conn, "(unclosed")?);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_canonical_git_remote() {
+        for remote in [
+            "git@github.com:okkez/clh-server.git",
+            "https://github.com/okkez/clh-server",
+            "https://user@GitHub.com/okkez/clh-server.git/",
+            "ssh://git@github.com:22/okkez/clh-server.git",
+        ] {
+            assert_eq!(canonical_git_remote(remote), "github.com/okkez/clh-server");
+        }
+        assert_eq!(
+            canonical_git_remote("/srv/git/project.git"),
+            "/srv/git/project"
+        );
+    }
+
     #[test]
     fn test_search_limit_cap() {
         let q = models::SearchQuery {
@@ -298,6 +1768,7 @@ mod tests {
             hostname: None,
             limit: Some(99_999),
             offset: None,
+            ..Default::default()
         };
         assert_eq!(q.effective_limit(), 10_000);
 
@@ -306,6 +1777,7 @@ mod tests {
             hostname: None,
             limit: Some(0),
             offset: None,
+            ..Default::default()
         };
         assert_eq!(q_zero.effective_limit(), 1);
     }
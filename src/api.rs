@@ -0,0 +1,100 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{get, web, HttpResponse, Responder, Result};
+use utoipa::OpenApi;
+
+use crate::models::*;
+
+/// Where the current version of the API is mounted.
+pub const PREFIX: &str = "/api/v1";
+/// When the unversioned routes were deprecated, as a `Deprecation` header.
+const DEPRECATED_SINCE: &str = "@1792368000";
+
+const DOCS_HTML: &str = include_str!("api/docs.html");
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "clh-server"),
+    servers((url = "/api/v1")),
+    paths(
+        crate::index,
+        crate::show,
+        crate::create,
+        crate::bulk,
+        crate::restore,
+        crate::delete,
+        crate::stats,
+        crate::hosts,
+        crate::update_host,
+        crate::stream::events,
+        crate::stream::websocket,
+        crate::webhooks::index,
+        crate::webhooks::show,
+        crate::webhooks::create,
+        crate::webhooks::update,
+        crate::webhooks::remove,
+        crate::audit::index,
+    ),
+    components(schemas(
+        History,
+        NewHistory,
+        Host,
+        HostUpdate,
+        HostSummary,
+        Stats,
+        StatsEntry,
+        DayCount,
+        HeatmapCell,
+        DeletedHistoryCount,
+        Webhook,
+        NewWebhook,
+        AuditEvent,
+    ))
+)]
+pub struct ApiDoc;
+
+/// Registers the OpenAPI document and the page browsing it, to be mounted
+/// under `PREFIX`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(document).service(docs);
+}
+
+#[get("/openapi.json")]
+async fn document() -> impl Responder {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+/// Swagger UI, loaded from a CDN.
+#[get("/docs")]
+async fn docs() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(DOCS_HTML)
+}
+
+/// Middleware marking responses of the unversioned aliases as deprecated
+/// (RFC 9745), linking to the versioned route.
+pub async fn deprecated(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    let successor = match req.query_string() {
+        "" => format!("<{PREFIX}{}>; rel=\"successor-version\"", req.path()),
+        query => format!(
+            "<{PREFIX}{}?{query}>; rel=\"successor-version\"",
+            req.path()
+        ),
+    };
+    let mut res = next.call(req).await?;
+    let headers = res.headers_mut();
+    headers.insert(
+        HeaderName::from_static("deprecation"),
+        HeaderValue::from_static(DEPRECATED_SINCE),
+    );
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.insert(actix_web::http::header::LINK, link);
+    }
+    Ok(res)
+}
@@ -0,0 +1,598 @@
+//! Encryption at rest of recorded commands.
+//!
+//! With `[encryption]` configured, the server encrypts the command of each
+//! history it records, and optionally its directories, before storing it.
+//! Every history is encrypted with a random data key of its own, stored
+//! alongside it wrapped (encrypted) with one of the configured keys, whose
+//! id is stored too. Keys are rotated by making a new one current, then
+//! re-encrypting histories with `rotate-keys` before retiring the old one.
+//!
+//! As ciphertext is random, histories are told apart by `content_hash`, a
+//! hash of their working directory and command keyed with the current key.
+//! Without encryption it isn't keyed, as the database holds the plaintext
+//! anyway.
+//!
+//! For the database to still narrow down searches, each history also has a
+//! search index: hashes of the trigrams of its command and of its
+//! normalized directory, keyed with the key it is encrypted with. A command filter of at least three characters, or a directory
+//! filter, then only decrypts the histories having all its hashes.
+//! Histories encrypted before servers indexed them have none, and are
+//! indexed by `rotate-keys --all`.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::e2e::{decode_key, hmac};
+use crate::models::{History, NewHistory, SearchQuery};
+
+/// Marks encrypted values, and the scheme they were encrypted with.
+pub const PREFIX: &str = "clhr1:";
+const COMMAND: &str = "command";
+const WORKING_DIRECTORY: &str = "working_directory";
+const NORMALIZED_DIRECTORY: &str = "normalized_directory";
+/// Bytes of each keyed hash kept in search indexes.
+const INDEX_HASH_LEN: usize = 8;
+
+/// The `[encryption]` section of the configuration:
+///
+/// ```toml
+/// [encryption]
+/// current = "2026-10"
+/// working_directory = true
+///
+/// [encryption.keys]
+/// "2026-10" = "/etc/clh/keys/2026-10.key"
+/// "2025-04" = "8f1c...64 hex digits"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// Id of the key new histories are encrypted with.
+    pub current: String,
+    /// Also encrypt working directories. Searching by directory then
+    /// decrypts every history on the server.
+    pub working_directory: bool,
+    /// Keys by id: 64 hex digits, or a file holding them as written by
+    /// `client keygen`. Keys histories are still encrypted with must stay
+    /// until `rotate-keys` has moved them to the current one.
+    pub keys: BTreeMap<String, String>,
+}
+
+impl EncryptionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        Keyring::load(self).map(drop)
+    }
+
+    /// Returns the configuration with inline keys masked.
+    pub fn masked(&self) -> Self {
+        let mut config = self.clone();
+        for key in config.keys.values_mut() {
+            if decode_key(key).is_ok() {
+                *key = "********".to_string();
+            }
+        }
+        config
+    }
+}
+
+/// A configured key, from which the key wrapping data keys and the keys of
+/// content hashes and search indexes are derived.
+struct MasterKey {
+    wrap: LessSafeKey,
+    hash_key: [u8; 32],
+    index_key: [u8; 32],
+}
+
+impl MasterKey {
+    fn new(bytes: &[u8; 32]) -> Self {
+        Self {
+            wrap: cipher(&hmac(bytes, &[b"clh at rest wrap"])),
+            hash_key: hmac(bytes, &[b"clh at rest hash"]),
+            index_key: hmac(bytes, &[b"clh at rest index"]),
+        }
+    }
+
+    fn index_hash(&self, kind: &str, value: &str) -> String {
+        let hash = hmac(&self.index_key, &[kind.as_bytes(), b"\0", value.as_bytes()]);
+        hex::encode(&hash[..INDEX_HASH_LEN])
+    }
+
+    /// The search index of a history encrypted with this key.
+    fn search_index(&self, normalized_directory: Option<&str>, command: &str) -> Vec<String> {
+        let mut hashes = trigrams(command)
+            .iter()
+            .map(|trigram| self.index_hash("trigram", trigram))
+            .collect::<BTreeSet<_>>();
+        if let Some(dir) = normalized_directory {
+            hashes.insert(self.index_hash("directory", dir));
+        }
+        hashes.into_iter().collect()
+    }
+}
+
+/// Lowercased trigrams of `text`, the unit encrypted commands are found by.
+fn trigrams(text: &str) -> BTreeSet<String> {
+    let chars = text.to_lowercase().chars().collect::<Vec<_>>();
+    chars.windows(3).map(|w| w.iter().collect()).collect()
+}
+
+fn cipher(key: &[u8; 32]) -> LessSafeKey {
+    LessSafeKey::new(
+        UnboundKey::new(&CHACHA20_POLY1305, key).expect("ChaCha20-Poly1305 takes 32 byte keys"),
+    )
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("cannot generate random bytes");
+    bytes
+}
+
+/// Encrypts `plaintext` with a random nonce, authenticating `aad` along
+/// with it.
+fn seal(key: &LessSafeKey, aad: &str, plaintext: &[u8]) -> String {
+    let nonce = random::<NONCE_LEN>();
+    let mut sealed = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(aad.as_bytes()),
+        &mut sealed,
+    )
+    .expect("commands are far below the ChaCha20-Poly1305 size limit");
+    BASE64.encode([&nonce[..], &sealed].concat())
+}
+
+fn open(key: &LessSafeKey, aad: &str, value: &str) -> Result<Vec<u8>, String> {
+    let data = BASE64
+        .decode(value)
+        .ok()
+        .filter(|data| data.len() > NONCE_LEN)
+        .ok_or("not an encrypted value")?;
+    let (nonce, sealed) = data.split_at(NONCE_LEN);
+
+    let mut sealed = sealed.to_vec();
+    let nonce = Nonce::try_assume_unique_for_key(nonce).expect("nonce has the right length");
+    let plaintext = key
+        .open_in_place(nonce, Aad::from(aad.as_bytes()), &mut sealed)
+        .map_err(|_| format!("cannot decrypt {aad}"))?;
+    Ok(plaintext.to_vec())
+}
+
+/// Hashes a working directory and command, keyed with `key` if any. `None`
+/// without a working directory, as such histories are never merged.
+pub fn content_hash(
+    key: Option<&[u8; 32]>,
+    working_directory: Option<&str>,
+    command: &str,
+) -> Option<String> {
+    let content = [
+        Sha256::digest(working_directory?.as_bytes()),
+        Sha256::digest(command.as_bytes()),
+    ]
+    .concat();
+    let hash = match key {
+        Some(key) => hmac(key, &[&content]),
+        None => Sha256::digest(&content).into(),
+    };
+    Some(hex::encode(hash))
+}
+
+/// The configured keys. The default one encrypts nothing.
+#[derive(Default)]
+pub struct Keyring {
+    current: Option<String>,
+    keys: BTreeMap<String, MasterKey>,
+    working_directory: bool,
+}
+
+impl Keyring {
+    pub fn load(config: &EncryptionConfig) -> Result<Self, String> {
+        if config.keys.is_empty() {
+            return Err("encryption.keys must not be empty".to_string());
+        }
+        let mut keys = BTreeMap::new();
+        for (id, key) in &config.keys {
+            let bytes = match decode_key(key) {
+                Ok(bytes) => bytes,
+                Err(_) => {
+                    let content = std::fs::read_to_string(key)
+                        .map_err(|e| format!("cannot read encryption key {id} from {key}: {e}"))?;
+                    decode_key(&content)
+                        .map_err(|e| format!("invalid encryption key {id} in {key}: {e}"))?
+                }
+            };
+            keys.insert(id.clone(), MasterKey::new(&bytes));
+        }
+        if !keys.contains_key(&config.current) {
+            return Err(format!(
+                "encryption.current {:?} is not one of encryption.keys",
+                config.current
+            ));
+        }
+        Ok(Self {
+            current: Some(config.current.clone()),
+            keys,
+            working_directory: config.working_directory,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Whether directories are encrypted too, so that the database can't
+    /// filter on them.
+    pub fn encrypts_directories(&self) -> bool {
+        self.is_enabled() && self.working_directory
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    /// Returns those of `ids` that aren't configured, so histories
+    /// encrypted with them can't be read.
+    pub fn missing<'a>(&self, ids: &'a [String]) -> Vec<&'a str> {
+        ids.iter()
+            .map(String::as_str)
+            .filter(|id| !self.keys.contains_key(*id))
+            .collect()
+    }
+
+    fn current_key(&self) -> Option<(&str, &MasterKey)> {
+        let id = self.current.as_deref()?;
+        Some((id, &self.keys[id]))
+    }
+
+    fn content_hash(&self, working_directory: Option<&str>, command: &str) -> Option<String> {
+        let key = self.current_key().map(|(_, key)| &key.hash_key);
+        content_hash(key, working_directory, command)
+    }
+
+    /// The search index of history `stored`, as read from the
+    /// database, given its plaintext. `None` if it isn't encrypted.
+    pub fn reindex(
+        &self,
+        stored: &History,
+        normalized_directory: Option<&str>,
+        command: &str,
+    ) -> Result<Option<Vec<String>>, String> {
+        let Some(ref id) = stored.key_id else {
+            return Ok(None);
+        };
+        let key = self
+            .keys
+            .get(id)
+            .ok_or_else(|| format!("encryption key {id} is not configured"))?;
+        Ok(Some(key.search_index(normalized_directory, command)))
+    }
+
+    /// For each configured key, the hashes the search index of histories
+    /// encrypted with it must all hold to match the `pwd` and `command`
+    /// filters of `q`. Commands shorter than a trigram don't narrow them
+    /// down.
+    pub fn search_query(&self, q: &SearchQuery) -> Vec<(&str, Vec<String>)> {
+        let trigrams = q.command.as_deref().map(trigrams).unwrap_or_default();
+        self.keys
+            .iter()
+            .map(|(id, key)| {
+                let mut hashes = trigrams
+                    .iter()
+                    .map(|trigram| key.index_hash("trigram", trigram))
+                    .collect::<Vec<_>>();
+                if let Some(ref dir) = q.pwd {
+                    hashes.push(key.index_hash("directory", dir));
+                }
+                (id.as_str(), hashes)
+            })
+            .collect()
+    }
+
+    /// Encrypts `command`, and the directories when configured to, with a
+    /// new data key. Returns the id of the key it was wrapped with, and the
+    /// wrapped data key.
+    fn seal_fields(
+        &self,
+        command: &mut String,
+        directories: [(&str, Option<&mut String>); 2],
+    ) -> Option<(String, String)> {
+        let (id, key) = self.current_key()?;
+        let data_key = random::<32>();
+        let cipher = cipher(&data_key);
+
+        *command = format!("{PREFIX}{}", seal(&cipher, COMMAND, command.as_bytes()));
+        if self.working_directory {
+            for (field, value) in directories {
+                if let Some(value) = value {
+                    *value = format!("{PREFIX}{}", seal(&cipher, field, value.as_bytes()));
+                }
+            }
+        }
+        Some((id.to_string(), seal(&key.wrap, id, &data_key)))
+    }
+
+    /// Unwraps `data_key`, wrapped with key `id`.
+    fn data_cipher(&self, id: &str, data_key: &str) -> Result<LessSafeKey, String> {
+        let key = self
+            .keys
+            .get(id)
+            .ok_or_else(|| format!("encryption key {id} is not configured"))?;
+        let data_key: [u8; 32] = open(&key.wrap, id, data_key)?
+            .try_into()
+            .map_err(|_| "invalid data key")?;
+        Ok(cipher(&data_key))
+    }
+
+    fn open_fields(
+        &self,
+        id: &str,
+        data_key: &str,
+        command: &mut String,
+        directories: [(&str, Option<&mut String>); 2],
+    ) -> Result<(), String> {
+        let cipher = self.data_cipher(id, data_key)?;
+
+        let decrypt = |field: &str, value: &mut String| -> Result<(), String> {
+            if let Some(sealed) = value.strip_prefix(PREFIX) {
+                let plaintext = open(&cipher, field, sealed)?;
+                *value = String::from_utf8(plaintext).map_err(|e| e.to_string())?;
+            }
+            Ok(())
+        };
+        decrypt(COMMAND, command)?;
+        for (field, value) in directories {
+            if let Some(value) = value {
+                decrypt(field, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hashes and, when enabled, encrypts a history about to be recorded.
+    pub fn seal(&self, h: &mut NewHistory) {
+        h.normalized_directory
+            .get_or_insert_with(|| h.working_directory.clone());
+        h.content_hash = self.content_hash(Some(&h.working_directory), &h.command);
+        h.search_index = self
+            .current_key()
+            .map(|(_, key)| key.search_index(h.normalized_directory.as_deref(), &h.command));
+        let sealed = self.seal_fields(
+            &mut h.command,
+            [
+                (WORKING_DIRECTORY, Some(&mut h.working_directory)),
+                (NORMALIZED_DIRECTORY, h.normalized_directory.as_mut()),
+            ],
+        );
+        (h.key_id, h.data_key) = sealed.unzip();
+    }
+
+    /// Like `seal`, for a history already stored, which is then encrypted
+    /// with the current key and a new data key.
+    pub fn reseal(&self, h: &mut History) {
+        h.content_hash = self.content_hash(h.working_directory.as_deref(), &h.command);
+        h.search_index = self
+            .current_key()
+            .map(|(_, key)| key.search_index(h.normalized_directory.as_deref(), &h.command));
+        let sealed = self.seal_fields(
+            &mut h.command,
+            [
+                (WORKING_DIRECTORY, h.working_directory.as_mut()),
+                (NORMALIZED_DIRECTORY, h.normalized_directory.as_mut()),
+            ],
+        );
+        (h.key_id, h.data_key) = sealed.unzip();
+    }
+
+    /// Decrypts a history returned by `actions::create_history`.
+    pub fn open_new_history(&self, h: &mut NewHistory) -> Result<(), String> {
+        let (Some(id), Some(data_key)) = (h.key_id.take(), h.data_key.take()) else {
+            return Ok(());
+        };
+        self.open_fields(
+            &id,
+            &data_key,
+            &mut h.command,
+            [
+                (WORKING_DIRECTORY, Some(&mut h.working_directory)),
+                (NORMALIZED_DIRECTORY, h.normalized_directory.as_mut()),
+            ],
+        )
+    }
+
+    /// Returns `normalized_directory` as it is to be stored for history
+    /// `stored`, as read from the database: encrypted with its data key if
+    /// its normalized directory is, so that its key and content hash are
+    /// kept.
+    pub fn seal_normalized_directory(
+        &self,
+        stored: &History,
+        normalized_directory: &str,
+    ) -> Result<String, String> {
+        let encrypted = stored
+            .normalized_directory
+            .as_deref()
+            .is_some_and(|value| value.starts_with(PREFIX));
+        match (&stored.key_id, &stored.data_key) {
+            (Some(id), Some(data_key)) if encrypted => {
+                let cipher = self.data_cipher(id, data_key)?;
+                let sealed = seal(
+                    &cipher,
+                    NORMALIZED_DIRECTORY,
+                    normalized_directory.as_bytes(),
+                );
+                Ok(format!("{PREFIX}{sealed}"))
+            }
+            _ => Ok(normalized_directory.to_string()),
+        }
+    }
+
+    /// Decrypts a stored history. Histories that were stored unencrypted
+    /// are left as they are.
+    pub fn open(&self, h: &mut History) -> Result<(), String> {
+        let (Some(id), Some(data_key)) = (h.key_id.take(), h.data_key.take()) else {
+            return Ok(());
+        };
+        self.open_fields(
+            &id,
+            &data_key,
+            &mut h.command,
+            [
+                (WORKING_DIRECTORY, h.working_directory.as_mut()),
+                (NORMALIZED_DIRECTORY, h.normalized_directory.as_mut()),
+            ],
+        )
+        .map_err(|e| format!("history {}: {e}", h.id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(current: &str, working_directory: bool) -> EncryptionConfig {
+        EncryptionConfig {
+            current: current.to_string(),
+            working_directory,
+            keys: BTreeMap::from([
+                ("old".to_string(), "0123456789abcdef".repeat(4)),
+                ("new".to_string(), "fedcba9876543210".repeat(4)),
+            ]),
+        }
+    }
+
+    fn new_history() -> NewHistory {
+        NewHistory {
+            hostname: "host".to_string(),
+            working_directory: "/src/app".to_string(),
+            command: "export TOKEN=s3cret".to_string(),
+            normalized_directory: Some("/src/app".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_seal_round_trip() {
+        let keys = Keyring::load(&config("old", false)).unwrap();
+        let mut h = new_history();
+        keys.seal(&mut h);
+        assert!(h.command.starts_with(PREFIX));
+        assert!(!h.command.contains("s3cret"));
+        assert_eq!(h.working_directory, "/src/app");
+        assert_eq!(h.key_id.as_deref(), Some("old"));
+
+        // Data keys are random, but the content hash is deterministic.
+        let mut again = new_history();
+        keys.seal(&mut again);
+        assert_ne!(again.command, h.command);
+        assert_ne!(again.data_key, h.data_key);
+        assert_eq!(again.content_hash, h.content_hash);
+
+        keys.open_new_history(&mut h).unwrap();
+        assert_eq!(h.command, "export TOKEN=s3cret");
+        assert!(h.key_id.is_none());
+
+        let mut h = new_history();
+        keys.seal(&mut h);
+        h.data_key = again.data_key;
+        assert!(keys.open_new_history(&mut h).is_err());
+    }
+
+    #[test]
+    fn test_working_directory() {
+        let keys = Keyring::load(&config("new", true)).unwrap();
+        let mut h = new_history();
+        keys.seal(&mut h);
+        assert!(h.working_directory.starts_with(PREFIX));
+        assert!(h.normalized_directory.as_ref().unwrap().starts_with(PREFIX));
+
+        keys.open_new_history(&mut h).unwrap();
+        assert_eq!(h.working_directory, "/src/app");
+        assert_eq!(h.normalized_directory.as_deref(), Some("/src/app"));
+    }
+
+    #[test]
+    fn test_search_index() {
+        let keys = Keyring::load(&config("old", true)).unwrap();
+        let mut h = new_history();
+        keys.seal(&mut h);
+        let index = h.search_index.unwrap();
+        let query = |pwd: Option<&str>, command: &str| SearchQuery {
+            pwd: pwd.map(str::to_string),
+            command: Some(command.to_string()),
+            ..Default::default()
+        };
+        let holds = |q: &SearchQuery, key: &str| {
+            let (_, hashes) = keys
+                .search_query(q)
+                .into_iter()
+                .find(|(id, _)| *id == key)
+                .unwrap();
+            hashes.iter().all(|hash| index.contains(hash))
+        };
+
+        assert!(holds(&query(Some("/src/app"), "TOKEN=S3"), "old"));
+        assert!(holds(&query(None, "ex"), "old"));
+        assert!(!holds(&query(None, "token=s4"), "old"));
+        assert!(!holds(&query(Some("/src"), "token"), "old"));
+        assert!(!holds(&query(None, "token"), "new"));
+
+        let mut plain = new_history();
+        Keyring::default().seal(&mut plain);
+        assert!(plain.search_index.is_none());
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let mut plain = new_history();
+        Keyring::default().seal(&mut plain);
+        assert_eq!(plain.command, "export TOKEN=s3cret");
+        assert!(plain.key_id.is_none());
+        assert_eq!(
+            plain.content_hash,
+            content_hash(None, Some("/src/app"), "export TOKEN=s3cret")
+        );
+
+        let mut old = new_history();
+        Keyring::load(&config("old", false)).unwrap().seal(&mut old);
+        let mut new = new_history();
+        Keyring::load(&config("new", false)).unwrap().seal(&mut new);
+        assert_ne!(old.content_hash, plain.content_hash);
+        assert_ne!(old.content_hash, new.content_hash);
+
+        assert_ne!(
+            content_hash(None, Some("/src"), "app ls"),
+            content_hash(None, Some("/src app"), "ls")
+        );
+        assert_eq!(content_hash(None, None, "ls"), None);
+    }
+
+    #[test]
+    fn test_load() {
+        let mut config = config("missing", false);
+        let error = Keyring::load(&config).err().unwrap();
+        assert!(error.contains("encryption.current"), "{error}");
+
+        config.current = "old".to_string();
+        config
+            .keys
+            .insert("file".to_string(), "/nonexistent/clh.key".to_string());
+        let error = Keyring::load(&config).err().unwrap();
+        assert!(error.contains("cannot read encryption key file"), "{error}");
+
+        config.keys.remove("file");
+        let keys = Keyring::load(&config).unwrap();
+        let ids = ["old".to_string(), "retired".to_string()];
+        assert_eq!(keys.missing(&ids), ["retired"]);
+
+        let masked = config.masked();
+        assert!(masked.keys.values().all(|key| key == "********"));
+    }
+}
@@ -0,0 +1,219 @@
+use std::future::{ready, Ready};
+
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::{error, get, web, FromRequest, HttpRequest, HttpResponse, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::actions;
+use crate::models::{AuditEvent, AuditQuery, NewAuditEvent};
+use crate::telemetry;
+use crate::tls::ClientIdentity;
+use crate::DbPool;
+
+/// Hex digits of the token hash that name token actors.
+const TOKEN_PREFIX_LEN: usize = 12;
+
+/// Who may read the audit log, read from the `[admin]` section of the
+/// configuration. Nobody may unless it is configured:
+///
+/// ```toml
+/// [admin]
+/// tokens = ["0f3c9e..."]
+/// clients = ["ops.example.com"]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer tokens of admins.
+    pub tokens: Vec<String>,
+    /// Common names of client certificates of admins.
+    pub clients: Vec<String>,
+}
+
+impl AdminConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tokens.iter().any(|t| t.is_empty()) {
+            return Err("admin.tokens must not be empty strings".to_string());
+        }
+        Ok(())
+    }
+
+    /// Returns the configuration with the tokens replaced by asterisks.
+    pub fn masked(&self) -> Self {
+        Self {
+            tokens: self.tokens.iter().map(|_| "********".to_string()).collect(),
+            clients: self.clients.clone(),
+        }
+    }
+
+    fn is_admin(&self, req: &HttpRequest) -> bool {
+        if let Some(identity) = req.conn_data::<ClientIdentity>() {
+            if self.clients.contains(&identity.common_name) {
+                return true;
+            }
+        }
+        // Hashes are compared so that the time taken leaks nothing useful.
+        bearer(req).is_some_and(|token| {
+            let hash = Sha256::digest(token);
+            self.tokens.iter().any(|t| Sha256::digest(t) == hash)
+        })
+    }
+}
+
+fn bearer(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// The client making a request, as recorded in the audit log. Tokens are
+/// only recorded as a prefix of their SHA-256 hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Actor {
+    pub name: String,
+    pub ip: Option<String>,
+}
+
+impl Actor {
+    /// Actor of maintenance commands run on the server.
+    pub fn cli() -> Self {
+        Self {
+            name: "cli".to_string(),
+            ip: None,
+        }
+    }
+
+    fn of(req: &HttpRequest) -> Self {
+        let name = if let Some(identity) = req.conn_data::<ClientIdentity>() {
+            format!("cert:{}", identity.common_name)
+        } else if let Some(token) = bearer(req) {
+            let hash = hex::encode(Sha256::digest(token));
+            format!("token:{}", &hash[..TOKEN_PREFIX_LEN])
+        } else {
+            "anonymous".to_string()
+        };
+        let ip = req
+            .peer_addr()
+            .map_or_else(|| "local".to_string(), |addr| addr.ip().to_string());
+        Self { name, ip: Some(ip) }
+    }
+
+    /// An event of this actor doing `action` to `history_ids`.
+    pub fn event(&self, action: &str, history_ids: Vec<i32>) -> NewAuditEvent {
+        NewAuditEvent {
+            action: action.to_string(),
+            actor: self.name.clone(),
+            ip: self.ip.clone(),
+            history_ids,
+            filters: None,
+            details: serde_json::json!({}),
+        }
+    }
+}
+
+impl NewAuditEvent {
+    /// Records the query the affected histories were selected by.
+    pub fn filters(mut self, filters: impl Serialize) -> Self {
+        self.filters = serde_json::to_value(filters).ok();
+        self
+    }
+
+    pub fn details(mut self, details: serde_json::Value) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+impl FromRequest for Actor {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(Self::of(req)))
+    }
+}
+
+/// Registers the audit log endpoint.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(index);
+}
+
+/// Lists audit events matching the query, newest first, with the total in
+/// `X-Total-Count`. Only admins may, see `AdminConfig`.
+#[utoipa::path(
+    tag = "audit",
+    operation_id = "list_audit_events",
+    params(AuditQuery),
+    responses(
+        (
+            status = 200,
+            body = [AuditEvent],
+            headers(("X-Total-Count" = i64, description = "Number of matches"))
+        ),
+        (status = 403, description = "The client is not an admin")
+    )
+)]
+#[get("/audit")]
+async fn index(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    admins: Option<web::Data<AdminConfig>>,
+    q: web::Query<AuditQuery>,
+) -> Result<HttpResponse> {
+    if !admins.is_some_and(|admins| admins.is_admin(&req)) {
+        return Err(error::ErrorForbidden("the audit log is for admins only"));
+    }
+
+    let mut conn = pool.get().expect("cannot get db connection from pool");
+
+    match telemetry::block(move || actions::list_audit(&mut conn, &q)).await {
+        Ok(response) => match response {
+            Ok((events, total)) => Ok(HttpResponse::Ok()
+                .insert_header(("X-Total-Count", total.to_string()))
+                .json(events)),
+            Err(e) => Err(error::ErrorInternalServerError(e)),
+        },
+        Err(e) => Err(error::ErrorInternalServerError(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_actor() {
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(Actor::of(&req).name, "anonymous");
+
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_http_request();
+        let actor = Actor::of(&req);
+        assert_eq!(actor.name, "token:2bb80d537b1d");
+        assert!(!actor.name.contains("secret"));
+    }
+
+    #[test]
+    fn test_is_admin() {
+        let admins = AdminConfig {
+            tokens: vec!["admin-token".to_string()],
+            clients: Vec::new(),
+        };
+        let with_token = |token: &str| {
+            TestRequest::default()
+                .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+                .to_http_request()
+        };
+        assert!(admins.is_admin(&with_token("admin-token")));
+        assert!(!admins.is_admin(&with_token("other-token")));
+        assert!(!admins.is_admin(&TestRequest::default().to_http_request()));
+        assert!(!AdminConfig::default().is_admin(&with_token("admin-token")));
+
+        assert_eq!(admins.masked().tokens, vec!["********"]);
+    }
+}
@@ -0,0 +1,457 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::config::Feature;
+use crate::integration::Shell;
+use crate::models::{SearchQuery, StatsQuery};
+use crate::telemetry::LogFormat;
+
+/// Command line history server, and a client to talk to it.
+#[derive(Debug, Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Server options, when run without a subcommand
+    #[command(flatten)]
+    pub serve: ServeArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the history server (the default)
+    Serve(ServeArgs),
+    /// Inspect the server configuration
+    Config(ConfigArgs),
+    /// Re-encrypt stored histories with the current encryption key
+    RotateKeys(RotateKeysArgs),
+    /// Normalize again the working directories of stored histories, after
+    /// the path mappings changed
+    Renormalize(RenormalizeArgs),
+    /// Talk to a history server
+    Client(Box<ClientArgs>),
+    /// Print the shell integration script, to be evaluated by the shell
+    Init(InitArgs),
+    /// Search interactively, printing the chosen command
+    Tui(TuiArgs),
+    /// Probe the configured listener, exiting non-zero unless the server
+    /// is ready
+    Health(HealthArgs),
+}
+
+/// Server settings. Each one overrides its counterpart in the
+/// configuration file, see `Config`.
+#[derive(Debug, Clone, Default, Args)]
+pub struct ServeArgs {
+    /// TOML configuration file
+    #[arg(long, env = "CLH_CONFIG")]
+    pub config: Option<String>,
+    #[arg(long, env = "DATABASE_URL", hide_env_values = true)]
+    pub database_url: Option<String>,
+    /// Addresses to listen on, comma separated: `host:port` or `unix:<path>`
+    #[arg(long, env = "CLH_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<String>,
+    /// Permissions of Unix domain sockets, in octal such as `0600`
+    #[arg(long, env = "CLH_SOCKET_MODE")]
+    pub socket_mode: Option<String>,
+    /// PEM certificate chain, to serve HTTPS
+    #[arg(long, env = "CLH_TLS_CERT")]
+    pub tls_cert: Option<String>,
+    /// PEM private key of `--tls-cert`
+    #[arg(long, env = "CLH_TLS_KEY")]
+    pub tls_key: Option<String>,
+    /// PEM certificates of the authorities clients must present a
+    /// certificate from
+    #[arg(long, env = "CLH_TLS_CLIENT_CA")]
+    pub tls_client_ca: Option<String>,
+    /// Worker threads, one per CPU by default
+    #[arg(long, env = "CLH_WORKERS")]
+    pub workers: Option<usize>,
+    /// Largest number of database connections
+    #[arg(long, env = "CLH_POOL_MAX_SIZE")]
+    pub pool_max_size: Option<u32>,
+    /// Idle database connections to keep open
+    #[arg(long, env = "CLH_POOL_MIN_IDLE")]
+    pub pool_min_idle: Option<u32>,
+    /// Seconds to wait for a database connection
+    #[arg(long, env = "CLH_POOL_CONNECTION_TIMEOUT")]
+    pub pool_connection_timeout: Option<u64>,
+    /// Page size of searches that don't give one
+    #[arg(long, env = "CLH_DEFAULT_LIMIT")]
+    pub default_limit: Option<i64>,
+    /// Largest page size a search may ask for
+    #[arg(long, env = "CLH_MAX_LIMIT")]
+    pub max_limit: Option<i64>,
+    /// Largest request body, in bytes
+    #[arg(long, env = "CLH_MAX_BODY_SIZE")]
+    pub max_body_size: Option<usize>,
+    /// Longest command recorded, in bytes
+    #[arg(long, env = "CLH_MAX_COMMAND_LENGTH")]
+    pub max_command_length: Option<usize>,
+    #[arg(long, env = "CLH_LOG_FORMAT", value_enum)]
+    pub log_format: Option<LogFormat>,
+    /// Log filter, such as `info,clh_server=debug`
+    #[arg(long, env = "RUST_LOG")]
+    pub log_filter: Option<String>,
+    /// Path mapping table, see `PathMappings`
+    #[arg(long, env = "CLH_PATH_MAPPINGS")]
+    pub path_mappings: Option<String>,
+    /// Features to turn off, comma separated
+    #[arg(long, env = "CLH_DISABLE", value_enum, value_delimiter = ',')]
+    pub disable: Vec<Feature>,
+}
+
+#[derive(Debug, Args)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Validate the configuration and print the effective settings
+    Check(ServeArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct RenormalizeArgs {
+    /// Histories to normalize per transaction
+    #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(i64).range(1..))]
+    pub batch_size: i64,
+    #[command(flatten)]
+    pub serve: ServeArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct HealthArgs {
+    /// Only check that the server is up (`/healthz`), not that it is ready
+    /// (`/readyz`)
+    #[arg(long)]
+    pub live: bool,
+    /// Seconds to wait for the server to answer
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout: u64,
+    #[command(flatten)]
+    pub serve: ServeArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct RotateKeysArgs {
+    /// Histories to re-encrypt per transaction
+    #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(i64).range(1..))]
+    pub batch_size: i64,
+    /// Re-encrypt every history, not only those under other keys, e.g.
+    /// after turning on `encryption.working_directory`
+    #[arg(long)]
+    pub all: bool,
+    #[command(flatten)]
+    pub serve: ServeArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct ConnectionArgs {
+    /// URL of the history server
+    #[arg(long, env = "CLH_SERVER", default_value = "http://localhost:8088")]
+    pub server: String,
+    /// Token sent as `Authorization: Bearer <token>`
+    #[arg(long, env = "CLH_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+    #[command(flatten)]
+    pub tls: ClientTlsArgs,
+    /// Key to encrypt commands with end to end, see `client keygen`
+    #[arg(long, env = "CLH_KEY_FILE")]
+    pub key_file: Option<String>,
+}
+
+/// Certificates for talking to a server over HTTPS.
+#[derive(Debug, Clone, Default, Args)]
+pub struct ClientTlsArgs {
+    /// PEM certificate of the authority to trust besides the system ones
+    #[arg(long, env = "CLH_CA_CERT", global = true)]
+    pub ca_cert: Option<String>,
+    /// PEM client certificate, for servers that authenticate clients
+    #[arg(long, env = "CLH_CLIENT_CERT", requires = "client_key", global = true)]
+    pub client_cert: Option<String>,
+    /// PEM private key of `--client-cert`
+    #[arg(long, env = "CLH_CLIENT_KEY", requires = "client_cert", global = true)]
+    pub client_key: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct InitArgs {
+    pub shell: Shell,
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct TuiArgs {
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+    /// Initial search text
+    #[arg(long, default_value = "")]
+    pub query: String,
+    /// Initial filter scope, switched with Tab
+    #[arg(long, value_enum, default_value_t = Scope::Global)]
+    pub scope: Scope,
+}
+
+/// Which histories the interactive search looks at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Scope {
+    Global,
+    Host,
+    Directory,
+}
+
+impl Scope {
+    pub fn next(self) -> Self {
+        match self {
+            Scope::Global => Scope::Host,
+            Scope::Host => Scope::Directory,
+            Scope::Directory => Scope::Global,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ClientArgs {
+    /// URL of the history server
+    #[arg(
+        long,
+        env = "CLH_SERVER",
+        default_value = "http://localhost:8088",
+        global = true
+    )]
+    pub server: String,
+    /// Token sent as `Authorization: Bearer <token>`
+    #[arg(long, env = "CLH_TOKEN", hide_env_values = true, global = true)]
+    pub token: Option<String>,
+    #[command(flatten)]
+    pub tls: ClientTlsArgs,
+    /// Key to encrypt commands with end to end, see `keygen`
+    #[arg(long, env = "CLH_KEY_FILE", global = true)]
+    pub key_file: Option<String>,
+    /// File runs are kept in while the server can't be reached, defaults
+    /// to `$XDG_STATE_HOME/clh/spool.jsonl`
+    #[arg(long, env = "CLH_SPOOL", global = true)]
+    pub spool: Option<String>,
+    /// Output format
+    #[arg(long, value_enum, default_value_t = Format::Table, global = true)]
+    pub format: Format,
+    #[command(subcommand)]
+    pub command: ClientCommand,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+    /// Commands only, one per line
+    Plain,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ClientCommand {
+    /// Record a command, read from stdin when no words are given
+    Add(AddArgs),
+    /// Search recorded commands
+    Search(SearchArgs),
+    /// Show a recorded command
+    Show { id: i32 },
+    /// Move a recorded command to the trash
+    Delete {
+        id: i32,
+        /// Remove it for good instead
+        #[arg(long)]
+        purge: bool,
+    },
+    /// Take a deleted command back out of the trash
+    Restore { id: i32 },
+    /// Send the runs kept while the server couldn't be reached
+    Replay,
+    /// Show usage statistics
+    Stats(StatsArgs),
+    /// Write a new key to `--key-file`, to encrypt commands with
+    Keygen {
+        /// Replace an existing key, making what it encrypted unreadable
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct AddArgs {
+    /// Hostname to record, defaults to this machine's
+    #[arg(long)]
+    pub hostname: Option<String>,
+    /// Working directory to record, defaults to the current one
+    #[arg(long)]
+    pub pwd: Option<String>,
+    #[arg(long, allow_negative_numbers = true)]
+    pub exit_status: Option<i32>,
+    /// Record the git repository the working directory belongs to
+    #[arg(long)]
+    pub git: bool,
+    /// When the command was run, like `2026-10-19T08:00:00Z`, for
+    /// importing older history; defaults to now
+    #[arg(long)]
+    pub executed_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct FilterArgs {
+    #[arg(long)]
+    pub pwd: Option<String>,
+    /// Only commands run in the current directory
+    #[arg(long, conflicts_with = "pwd")]
+    pub here: bool,
+    #[arg(long)]
+    pub hostname: Option<String>,
+    /// Host `--pwd` was taken on, for per-host path mappings
+    #[arg(long)]
+    pub origin: Option<String>,
+    /// Git remote URL of the repository
+    #[arg(long)]
+    pub repo: Option<String>,
+    #[arg(long)]
+    pub branch: Option<String>,
+    /// Text the command contains
+    #[arg(long)]
+    pub command: Option<String>,
+}
+
+impl FilterArgs {
+    pub fn to_query(&self) -> std::io::Result<SearchQuery> {
+        let pwd = if self.here {
+            Some(std::env::current_dir()?.to_string_lossy().into_owned())
+        } else {
+            self.pwd.clone()
+        };
+
+        Ok(SearchQuery {
+            pwd,
+            hostname: self.hostname.clone(),
+            origin: self.origin.clone(),
+            repo: self.repo.clone(),
+            branch: self.branch.clone(),
+            command: self.command.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct SearchArgs {
+    #[command(flatten)]
+    pub filters: FilterArgs,
+    #[arg(long)]
+    pub limit: Option<i64>,
+    #[arg(long)]
+    pub offset: Option<i64>,
+    /// Search the trash instead
+    #[arg(long)]
+    pub deleted: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct StatsArgs {
+    #[command(flatten)]
+    pub filters: FilterArgs,
+    /// Length of the ranked lists
+    #[arg(long)]
+    pub top: Option<i64>,
+}
+
+impl StatsArgs {
+    pub fn to_stats_query(&self) -> StatsQuery {
+        StatsQuery { top: self.top }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_client_add() {
+        let cli = Cli::parse_from([
+            "clh-server",
+            "client",
+            "--server",
+            "http://example.com:8088",
+            "add",
+            "--exit-status",
+            "-1",
+            "--executed-at",
+            "2026-10-19T08:00:00Z",
+            "ls",
+            "-la",
+        ]);
+        let Some(Command::Client(args)) = cli.command else {
+            panic!("expected client subcommand");
+        };
+        assert_eq!(args.server, "http://example.com:8088");
+        let ClientCommand::Add(add) = args.command else {
+            panic!("expected add subcommand");
+        };
+        assert_eq!(add.exit_status, Some(-1));
+        assert_eq!(
+            add.executed_at.map(|t| t.to_rfc3339()).as_deref(),
+            Some("2026-10-19T08:00:00+00:00")
+        );
+        assert_eq!(add.command, vec!["ls", "-la"]);
+    }
+
+    #[test]
+    fn test_parse_init() {
+        let cli = Cli::parse_from(["clh-server", "init", "fish", "--token", "t"]);
+        let Some(Command::Init(args)) = cli.command else {
+            panic!("expected init subcommand");
+        };
+        assert_eq!(args.shell, Shell::Fish);
+        assert_eq!(args.connection.token.as_deref(), Some("t"));
+    }
+
+    #[test]
+    fn test_parse_without_subcommand_serves() {
+        let cli = Cli::parse_from(["clh-server"]);
+        assert!(cli.command.is_none());
+    }
+
+    #[test]
+    fn test_parse_serve_args() {
+        let cli = Cli::parse_from([
+            "clh-server",
+            "--listen",
+            "127.0.0.1:8088,unix:/run/clh/clh.sock",
+            "--log-format",
+            "json",
+            "--disable",
+            "ui,metrics",
+        ]);
+        assert!(cli.command.is_none());
+        assert_eq!(
+            cli.serve.listen,
+            ["127.0.0.1:8088", "unix:/run/clh/clh.sock"]
+        );
+        assert_eq!(cli.serve.log_format, Some(LogFormat::Json));
+        assert_eq!(cli.serve.disable, [Feature::Ui, Feature::Metrics]);
+
+        let cli = Cli::parse_from(["clh-server", "config", "check", "--max-limit", "500"]);
+        let Some(Command::Config(ConfigArgs {
+            command: ConfigCommand::Check(args),
+        })) = cli.command
+        else {
+            panic!("expected config check subcommand");
+        };
+        assert_eq!(args.max_limit, Some(500));
+    }
+}
@@ -0,0 +1,523 @@
+use std::error::Error;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+use reqwest::StatusCode;
+
+use crate::cli::{AddArgs, ClientArgs, ClientCommand, ClientTlsArgs, Format};
+use crate::e2e;
+use crate::models::*;
+use crate::spool::Spool;
+
+pub type ClientResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// How long to wait for the server, so that shell hooks recording runs do
+/// not hang the shell when it cannot be reached: the run is spooled instead.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Blocking HTTP client for the history server, encrypting and decrypting
+/// histories end to end when given a key.
+pub struct Client {
+    http: clh_client::blocking::Client,
+    key: Option<e2e::Key>,
+}
+
+/// Points at the token when the server turns the client away.
+fn explain(e: clh_client::Error) -> Box<dyn Error + Send + Sync> {
+    match e {
+        clh_client::Error::Status {
+            status: status @ (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN),
+            ..
+        } => format!("{status}: check the token given by --token or CLH_TOKEN").into(),
+        e => e.into(),
+    }
+}
+
+/// Whether the server couldn't be reached or answer for now, so that the
+/// request may succeed later.
+fn is_transient(e: &(dyn Error + Send + Sync + 'static)) -> bool {
+    e.downcast_ref::<clh_client::Error>()
+        .is_some_and(clh_client::Error::is_transient)
+}
+
+impl Client {
+    pub fn new(
+        server: &str,
+        token: Option<String>,
+        tls: &ClientTlsArgs,
+        retry: clh_client::Retry,
+    ) -> ClientResult<Self> {
+        let mut builder = clh_client::Builder::new(server)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(TIMEOUT)
+            .retry(retry);
+        if let Some(token) = token {
+            builder = builder.token(token);
+        }
+        if let Some(ref path) = tls.ca_cert {
+            let pem = std::fs::read(path).map_err(|e| format!("cannot read {path}: {e}"))?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        if let (Some(cert), Some(key)) = (&tls.client_cert, &tls.client_key) {
+            let mut pem = std::fs::read(cert).map_err(|e| format!("cannot read {cert}: {e}"))?;
+            pem.extend(std::fs::read(key).map_err(|e| format!("cannot read {key}: {e}"))?);
+            builder = builder.identity(reqwest::Identity::from_pem(&pem)?);
+        }
+
+        Ok(Client {
+            http: builder.build_blocking()?,
+            key: None,
+        })
+    }
+
+    /// Encrypts recorded commands with the key in `key_file`, and decrypts
+    /// them on retrieval.
+    pub fn with_key_file(mut self, key_file: Option<&str>) -> ClientResult<Self> {
+        self.key = key_file.map(e2e::Key::load).transpose()?;
+        Ok(self)
+    }
+
+    fn decrypt(&self, histories: &mut [History]) -> ClientResult<()> {
+        if let Some(ref key) = self.key {
+            for history in histories {
+                key.decrypt_history(history)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the matching histories and the total number of matches.
+    ///
+    /// With a key, the `pwd` and `command` filters only find encrypted
+    /// histories.
+    pub fn search(&self, q: &SearchQuery) -> ClientResult<(Vec<History>, i64)> {
+        let mut blind = q.clone();
+        let text = match self.key {
+            Some(ref key) => key.blind_query(&mut blind),
+            None => None,
+        };
+        let q = if self.key.is_some() { &blind } else { q };
+
+        let page = self.http.search(q).map_err(explain)?;
+        let mut histories = page.histories;
+        self.decrypt(&mut histories)?;
+        if let Some(text) = text.map(|t| t.to_lowercase()) {
+            histories.retain(|h| !h.encrypted || h.command.to_lowercase().contains(&text));
+        }
+        Ok((histories, page.total))
+    }
+
+    pub fn find(&self, id: i32) -> ClientResult<Option<History>> {
+        let mut history = self.http.find(id).map_err(explain)?;
+        self.decrypt(history.as_mut_slice())?;
+        Ok(history)
+    }
+
+    pub fn create(&self, new_history: &NewHistory) -> ClientResult<NewHistory> {
+        let mut new_history = new_history.clone();
+        if let Some(ref key) = self.key {
+            key.encrypt_history(&mut new_history);
+        }
+
+        let mut created = self.http.create(&new_history).map_err(explain)?;
+        if let Some(ref key) = self.key {
+            key.decrypt_new_history(&mut created)?;
+        }
+        Ok(created)
+    }
+
+    pub fn delete(&self, id: i32, purge: bool) -> ClientResult<DeletedHistoryCount> {
+        self.http.delete(id, purge).map_err(explain)
+    }
+
+    pub fn restore(&self, id: i32) -> ClientResult<Option<History>> {
+        let mut history = self.http.restore(id).map_err(explain)?;
+        self.decrypt(history.as_mut_slice())?;
+        Ok(history)
+    }
+
+    pub fn stats(&self, q: &SearchQuery, stats_query: &StatsQuery) -> ClientResult<Stats> {
+        let mut stats = self.http.stats(q, stats_query).map_err(explain)?;
+        if let Some(ref key) = self.key {
+            key.decrypt_stats(&mut stats);
+        }
+        Ok(stats)
+    }
+}
+
+/// Runs a `client` subcommand.
+pub fn run(args: ClientArgs) -> ClientResult<()> {
+    if let ClientCommand::Keygen { force } = args.command {
+        let path = args
+            .key_file
+            .ok_or("no --key-file or CLH_KEY_FILE to write to")?;
+        return keygen(&path, force);
+    }
+
+    // Shell hooks record runs with `add`, which spools them rather than
+    // keeping the shell waiting for the server.
+    let retry = match args.command {
+        ClientCommand::Add(_) => clh_client::Retry::none(),
+        _ => clh_client::Retry::default(),
+    };
+    let client = Client::new(&args.server, args.token, &args.tls, retry)?
+        .with_key_file(args.key_file.as_deref())?;
+    let format = args.format;
+    let spool = args
+        .spool
+        .map(PathBuf::from)
+        .or_else(Spool::default_path)
+        .map(Spool::new);
+
+    match args.command {
+        ClientCommand::Add(add) => {
+            let new_history = new_history(add)?;
+            let mut replayed = Ok(());
+            if let Some(ref spool) = spool {
+                if !spool.is_empty() {
+                    if let Err(e) = replay(&client, spool, false) {
+                        eprintln!(
+                            "cannot replay the runs kept in {}: {e}",
+                            spool.path().display()
+                        );
+                        replayed = Err(e);
+                    }
+                }
+            }
+            // The server was just found unreachable, so the run is spooled
+            // without trying again.
+            let created = match replayed {
+                Err(e) if is_transient(&*e) => Err(e),
+                _ => client.create(&new_history),
+            };
+            match created {
+                Ok(created) => {
+                    if format == Format::Json {
+                        println!("{}", serde_json::to_string_pretty(&created)?);
+                    }
+                }
+                Err(e) if is_transient(&*e) => {
+                    let Some(spool) = spool else {
+                        return Err(e);
+                    };
+                    spool
+                        .push(&new_history)
+                        .map_err(|err| format!("{e}, and cannot keep the run for later: {err}"))?;
+                    eprintln!(
+                        "{e}; kept the run in {} to send later",
+                        spool.path().display()
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        ClientCommand::Replay => {
+            let spool = spool.ok_or("no --spool or CLH_SPOOL to replay")?;
+            let sent = replay(&client, &spool, true)?;
+            eprintln!("sent {sent} runs");
+        }
+        ClientCommand::Search(search) => {
+            let q = SearchQuery {
+                limit: search.limit,
+                offset: search.offset,
+                deleted: Some(search.deleted),
+                ..search.filters.to_query()?
+            };
+            let (histories, total) = client.search(&q)?;
+            print_histories(&histories, format)?;
+            if format == Format::Table {
+                eprintln!("{} of {total}", histories.len());
+            }
+        }
+        ClientCommand::Show { id } => match client.find(id)? {
+            Some(history) => print_histories(&[history], format)?,
+            None => return Err(format!("history {id} not found").into()),
+        },
+        ClientCommand::Restore { id } => match client.restore(id)? {
+            Some(history) => print_histories(&[history], format)?,
+            None => return Err(format!("history {id} is not in the trash").into()),
+        },
+        ClientCommand::Delete { id, purge } => {
+            let deleted = client.delete(id, purge)?;
+            if deleted.count == 0 {
+                return Err(format!("history {id} not found").into());
+            }
+            match format {
+                Format::Json => println!("{}", serde_json::to_string_pretty(&deleted)?),
+                Format::Table | Format::Plain => println!("{}", deleted.message),
+            }
+        }
+        ClientCommand::Stats(stats) => {
+            let result = client.stats(&stats.filters.to_query()?, &stats.to_stats_query())?;
+            print_stats(&result, format)?;
+        }
+        ClientCommand::Keygen { .. } => unreachable!("handled above"),
+    }
+
+    Ok(())
+}
+
+/// Sends the runs kept in `spool`, see `Spool::replay`. Runs the server
+/// turns down for good are dropped rather than holding up the others.
+fn replay(client: &Client, spool: &Spool, wait: bool) -> ClientResult<usize> {
+    spool.replay(wait, |new_history| match client.create(new_history) {
+        Ok(_) => Ok(()),
+        Err(e) => match e.downcast_ref::<clh_client::Error>() {
+            Some(clh_client::Error::Status { status, .. })
+                if status.is_client_error() && !is_transient(&*e) =>
+            {
+                eprintln!("dropping a run of {:?}: {e}", new_history.command);
+                Ok(())
+            }
+            _ => Err(e),
+        },
+    })
+}
+
+/// Writes a new key to `path`, readable by its owner only.
+fn keygen(path: &str, force: bool) -> ClientResult<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).mode(0o600);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = options
+        .open(path)
+        .map_err(|e| format!("cannot write {path}: {e}"))?;
+    writeln!(file, "{}", e2e::Key::generate())?;
+    eprintln!(
+        "wrote a new key to {path}; keep a copy, histories it encrypts can't be read without it"
+    );
+    Ok(())
+}
+
+fn new_history(add: AddArgs) -> ClientResult<NewHistory> {
+    let command = if add.command.is_empty() || add.command == ["-"] {
+        let mut command = String::new();
+        std::io::stdin().read_to_string(&mut command)?;
+        command.trim_end_matches('\n').to_string()
+    } else {
+        add.command.join(" ")
+    };
+    if command.trim().is_empty() {
+        return Err("no command given".into());
+    }
+
+    let hostname = match add.hostname {
+        Some(hostname) => hostname,
+        None => gethostname::gethostname().to_string_lossy().into_owned(),
+    };
+    let working_directory = match add.pwd {
+        Some(pwd) => pwd,
+        None => std::env::current_dir()?.to_string_lossy().into_owned(),
+    };
+
+    let mut new_history = NewHistory {
+        hostname,
+        working_directory,
+        command,
+        exit_status: add.exit_status,
+        executed_at: Some(add.executed_at.unwrap_or_else(chrono::Utc::now)),
+        client_event_id: Some(event_id()),
+        ..Default::default()
+    };
+    if add.git {
+        let git = |args: &[&str]| git_output(&new_history.working_directory, args);
+        new_history.git_root = git(&["rev-parse", "--show-toplevel"]);
+        if new_history.git_root.is_some() {
+            new_history.git_branch = git(&["rev-parse", "--abbrev-ref", "HEAD"]);
+            new_history.git_remote = git(&["config", "--get", "remote.origin.url"]);
+        }
+    }
+
+    Ok(new_history)
+}
+
+/// A random id for a run, for the server to record it once only.
+fn event_id() -> String {
+    use ring::rand::{SecureRandom, SystemRandom};
+
+    let mut bytes = [0; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("cannot generate an event id");
+    hex::encode(bytes)
+}
+
+/// Runs git in `dir`, returning its trimmed output if it succeeded.
+fn git_output(dir: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    let stdout = stdout.trim();
+
+    if output.status.success() && !stdout.is_empty() {
+        Some(stdout.to_string())
+    } else {
+        None
+    }
+}
+
+fn print_histories(histories: &[History], format: Format) -> ClientResult<()> {
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(histories)?),
+        Format::Plain => {
+            for history in histories {
+                println!("{}", history.command);
+            }
+        }
+        Format::Table => {
+            let rows = histories
+                .iter()
+                .map(|h| {
+                    vec![
+                        h.id.to_string(),
+                        h.updated_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                        h.hostname.clone(),
+                        h.working_directory.clone().unwrap_or_default(),
+                        h.exit_status.map(|s| s.to_string()).unwrap_or_default(),
+                        h.command.clone(),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            print!(
+                "{}",
+                table(
+                    &["ID", "UPDATED", "HOST", "DIRECTORY", "STATUS", "COMMAND"],
+                    &rows
+                )
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn print_stats(stats: &Stats, format: Format) -> ClientResult<()> {
+    if format == Format::Json {
+        println!("{}", serde_json::to_string_pretty(stats)?);
+        return Ok(());
+    }
+    if format == Format::Plain {
+        for entry in &stats.top_commands {
+            println!("{}", entry.key);
+        }
+        return Ok(());
+    }
+
+    let entries = |entries: &[StatsEntry]| {
+        entries
+            .iter()
+            .map(|e| {
+                vec![
+                    e.count.to_string(),
+                    format!("{:.1}%", e.failure_rate * 100.0),
+                    e.key.clone(),
+                ]
+            })
+            .collect::<Vec<_>>()
+    };
+
+    println!(
+        "{} runs, {:.1}% failed\n",
+        stats.total.count,
+        stats.total.failure_rate * 100.0
+    );
+    for (title, list) in [
+        ("COMMAND", &stats.top_commands),
+        ("PROGRAM", &stats.top_programs),
+        ("HOST", &stats.per_host),
+        ("DIRECTORY", &stats.per_directory),
+    ] {
+        println!("{}", table(&["RUNS", "FAILED", title], &entries(list)));
+    }
+
+    let days = stats
+        .per_day
+        .iter()
+        .map(|d| vec![d.day.to_string(), d.count.to_string()])
+        .collect::<Vec<_>>();
+    print!("{}", table(&["DAY", "RUNS"], &days));
+
+    Ok(())
+}
+
+/// Lays out `rows` in columns. The last column is not padded, so that long
+/// commands do not push every line to the same width.
+fn table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths = headers.iter().map(|h| h.len()).collect::<Vec<_>>();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: Vec<&str>| {
+        let last = cells.len() - 1;
+        let mut line = String::new();
+        for (i, cell) in cells.into_iter().enumerate() {
+            if i == last {
+                line.push_str(cell);
+            } else {
+                line.push_str(&format!("{cell:<width$}  ", width = widths[i]));
+            }
+        }
+        line.trim_end().to_string() + "\n"
+    };
+
+    let mut out = line(headers.to_vec());
+    for row in rows {
+        out.push_str(&line(row.iter().map(String::as_str).collect()));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_aligns_columns() {
+        let rows = vec![
+            vec!["1".to_string(), "host-a".to_string(), "ls".to_string()],
+            vec!["10".to_string(), "h".to_string(), "git status".to_string()],
+        ];
+        assert_eq!(
+            table(&["ID", "HOST", "COMMAND"], &rows),
+            "ID  HOST    COMMAND\n1   host-a  ls\n10  h       git status\n"
+        );
+    }
+
+    #[test]
+    fn test_new_history_from_args() {
+        let add = AddArgs {
+            hostname: Some("host".to_string()),
+            pwd: Some("/tmp".to_string()),
+            exit_status: Some(1),
+            git: false,
+            executed_at: None,
+            command: vec!["echo".to_string(), "hello".to_string()],
+        };
+        let history = new_history(add).expect("valid arguments");
+        assert_eq!(history.hostname, "host");
+        assert_eq!(history.working_directory, "/tmp");
+        assert_eq!(history.command, "echo hello");
+        assert_eq!(history.exit_status, Some(1));
+        assert!(history.git_remote.is_none());
+        assert!(history.executed_at.is_some());
+        assert_eq!(history.client_event_id.as_ref().map(String::len), Some(32));
+        assert_ne!(history.client_event_id, Some(event_id()));
+    }
+}
@@ -0,0 +1,309 @@
+//! End-to-end encryption of recorded commands.
+//!
+//! With a key file, the client encrypts `command` and `working_directory`
+//! before sending them, so that the server and its database only ever see
+//! ciphertext. To still be searchable, each history carries a blind index:
+//! keyed hashes of its working directory and of the prefixes of each word
+//! of its command, which search filters are hashed into as well.
+//!
+//! Encryption is deterministic, so that running the same command again in
+//! the same directory still updates the existing history rather than
+//! recording a new one. This reveals which histories are equal, which the
+//! blind index reveals anyway, but nothing about their contents.
+
+use std::collections::BTreeSet;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::Sha256;
+
+use crate::models::{History, NewHistory, SearchQuery, Stats};
+
+/// Marks encrypted values, and the scheme they were encrypted with.
+pub const PREFIX: &str = "clh1:";
+pub const COMMAND: &str = "command";
+pub const WORKING_DIRECTORY: &str = "working_directory";
+
+/// Longest word prefix indexed; longer search words are cut to it, and
+/// results filtered once decrypted.
+const MAX_PREFIX_CHARS: usize = 16;
+/// Bytes of each keyed hash kept in the index.
+const HASH_LEN: usize = 16;
+
+/// A user's key, from which the encryption, nonce and index keys are
+/// derived.
+pub struct Key {
+    cipher: LessSafeKey,
+    nonce_key: [u8; 32],
+    index_key: [u8; 32],
+}
+
+pub(crate) fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Lowercased words of `text`, the unit commands are searched by.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split_whitespace().map(str::to_lowercase)
+}
+
+/// Decodes a key as written in key files: 64 hex digits.
+pub(crate) fn decode_key(s: &str) -> Result<[u8; 32], String> {
+    hex::decode(s.trim())
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| "a key is 64 hex digits".to_string())
+}
+
+fn truncate(word: &str, chars: usize) -> &str {
+    word.char_indices()
+        .nth(chars)
+        .map_or(word, |(end, _)| &word[..end])
+}
+
+impl Key {
+    /// Returns a new random key, hex encoded as in key files.
+    pub fn generate() -> String {
+        let mut bytes = [0; 32];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .expect("cannot generate a random key");
+        hex::encode(bytes)
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, String> {
+        let bytes = decode_key(s)?;
+
+        let cipher_key = hmac(&bytes, &[b"clh e2e encryption"]);
+        let cipher = UnboundKey::new(&CHACHA20_POLY1305, &cipher_key)
+            .expect("ChaCha20-Poly1305 takes 32 byte keys");
+        Ok(Self {
+            cipher: LessSafeKey::new(cipher),
+            nonce_key: hmac(&bytes, &[b"clh e2e nonce"]),
+            index_key: hmac(&bytes, &[b"clh e2e index"]),
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| format!("cannot read {path}: {e}"))?;
+        Self::from_hex(&content).map_err(|e| format!("invalid key in {path}: {e}"))
+    }
+
+    /// Encrypts the value of `field`, which is authenticated along with it
+    /// so that values can't be swapped between fields.
+    pub fn encrypt(&self, field: &str, plaintext: &str) -> String {
+        let nonce: [u8; NONCE_LEN] = hmac(
+            &self.nonce_key,
+            &[field.as_bytes(), b"\0", plaintext.as_bytes()],
+        )[..NONCE_LEN]
+            .try_into()
+            .unwrap();
+
+        let mut sealed = plaintext.as_bytes().to_vec();
+        self.cipher
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(field.as_bytes()),
+                &mut sealed,
+            )
+            .expect("commands are far below the ChaCha20-Poly1305 size limit");
+        format!("{PREFIX}{}", BASE64.encode([&nonce[..], &sealed].concat()))
+    }
+
+    pub fn decrypt(&self, field: &str, value: &str) -> Result<String, String> {
+        let data = value
+            .strip_prefix(PREFIX)
+            .and_then(|v| BASE64.decode(v).ok())
+            .filter(|data| data.len() > NONCE_LEN)
+            .ok_or("not an encrypted value")?;
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+
+        let mut sealed = sealed.to_vec();
+        let nonce = Nonce::try_assume_unique_for_key(nonce).expect("nonce has the right length");
+        let plaintext = self
+            .cipher
+            .open_in_place(nonce, Aad::from(field.as_bytes()), &mut sealed)
+            .map_err(|_| "cannot decrypt, was it encrypted with another key?")?;
+        String::from_utf8(plaintext.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn hash(&self, kind: &str, value: &str) -> String {
+        hex::encode(&hmac(&self.index_key, &[kind.as_bytes(), b"\0", value.as_bytes()])[..HASH_LEN])
+    }
+
+    /// Returns the blind index of a history.
+    pub fn index(&self, working_directory: &str, command: &str) -> Vec<String> {
+        let mut hashes = BTreeSet::from([self.hash("pwd", working_directory)]);
+        for word in words(command) {
+            for (end, c) in word.char_indices().take(MAX_PREFIX_CHARS) {
+                hashes.insert(self.hash("word", &word[..end + c.len_utf8()]));
+            }
+        }
+        hashes.into_iter().collect()
+    }
+
+    pub fn encrypt_history(&self, h: &mut NewHistory) {
+        h.blind_index = self.index(&h.working_directory, &h.command);
+        h.command = self.encrypt(COMMAND, &h.command);
+        h.working_directory = self.encrypt(WORKING_DIRECTORY, &h.working_directory);
+        h.encrypted = true;
+    }
+
+    pub fn decrypt_new_history(&self, h: &mut NewHistory) -> Result<(), String> {
+        if h.encrypted {
+            h.command = self.decrypt(COMMAND, &h.command)?;
+            h.working_directory = self.decrypt(WORKING_DIRECTORY, &h.working_directory)?;
+            h.normalized_directory = Some(h.working_directory.clone());
+        }
+        Ok(())
+    }
+
+    pub fn decrypt_history(&self, h: &mut History) -> Result<(), String> {
+        if !h.encrypted {
+            return Ok(());
+        }
+        let error = |e| format!("history {}: {e}", h.id);
+        h.command = self.decrypt(COMMAND, &h.command).map_err(error)?;
+        if let Some(ref dir) = h.working_directory {
+            let dir = self.decrypt(WORKING_DIRECTORY, dir).map_err(error)?;
+            h.normalized_directory = Some(dir.clone());
+            h.working_directory = Some(dir);
+        }
+        Ok(())
+    }
+
+    /// Decrypts the commands and directories ranked in `stats`. Programs
+    /// are ranked by the first word of each ciphertext, so they can't be.
+    pub fn decrypt_stats(&self, stats: &mut Stats) {
+        for (field, entries) in [
+            (COMMAND, &mut stats.top_commands),
+            (WORKING_DIRECTORY, &mut stats.per_directory),
+        ] {
+            for entry in entries {
+                if let Ok(key) = self.decrypt(field, &entry.key) {
+                    entry.key = key;
+                }
+            }
+        }
+    }
+
+    /// Moves the `pwd` and `command` filters of `q` into its blind index
+    /// filter. Returns the command text, which results should be checked
+    /// against once decrypted: the index only matches word prefixes.
+    pub fn blind_query(&self, q: &mut SearchQuery) -> Option<String> {
+        let mut hashes = Vec::new();
+        if let Some(pwd) = q.pwd.take() {
+            hashes.push(self.hash("pwd", &pwd));
+        }
+        let text = q.command.take();
+        for word in text.iter().flat_map(|t| words(t)) {
+            hashes.push(self.hash("word", truncate(&word, MAX_PREFIX_CHARS)));
+        }
+        if !hashes.is_empty() {
+            q.blind_index = Some(hashes.join(","));
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> Key {
+        Key::from_hex(&"0123456789abcdef".repeat(4)).unwrap()
+    }
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let key = key();
+        let encrypted = key.encrypt(COMMAND, "export TOKEN=s3cret");
+        assert!(encrypted.starts_with(PREFIX));
+        assert!(!encrypted.contains("s3cret"));
+        assert_eq!(encrypted, key.encrypt(COMMAND, "export TOKEN=s3cret"));
+        assert_ne!(
+            encrypted,
+            key.encrypt(WORKING_DIRECTORY, "export TOKEN=s3cret")
+        );
+        assert_eq!(
+            key.decrypt(COMMAND, &encrypted).unwrap(),
+            "export TOKEN=s3cret"
+        );
+
+        assert!(key.decrypt(WORKING_DIRECTORY, &encrypted).is_err());
+        let other = Key::from_hex(&Key::generate()).unwrap();
+        assert!(other.decrypt(COMMAND, &encrypted).is_err());
+        assert!(key.decrypt(COMMAND, "ls -la").is_err());
+    }
+
+    #[test]
+    fn test_from_hex() {
+        assert!(Key::from_hex(&format!("{}\n", Key::generate())).is_ok());
+        assert!(Key::from_hex("abcd").is_err());
+        assert!(Key::from_hex(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_blind_query_matches_index() {
+        let key = key();
+        let index = key.index("/src/app", "Cargo build --release");
+
+        for (pwd, command) in [
+            (Some("/src/app"), None),
+            (None, Some("carg")),
+            (Some("/src/app"), Some("build --rel")),
+            (None, Some("cargo-build-with-a-very-long-name")),
+        ] {
+            let mut q = SearchQuery {
+                pwd: pwd.map(str::to_string),
+                command: command.map(str::to_string),
+                ..Default::default()
+            };
+            let text = key.blind_query(&mut q);
+            assert_eq!(text.as_deref(), command);
+            assert!(q.pwd.is_none() && q.command.is_none());
+
+            let hashes = q.blind_index.unwrap();
+            let matched = hashes.split(',').all(|h| index.iter().any(|i| i == h));
+            assert_eq!(
+                matched,
+                command != Some("cargo-build-with-a-very-long-name"),
+                "{pwd:?} {command:?}"
+            );
+        }
+
+        let mut q = SearchQuery {
+            pwd: Some("/src".to_string()),
+            ..Default::default()
+        };
+        key.blind_query(&mut q);
+        assert!(!index.contains(&q.blind_index.unwrap()));
+    }
+
+    #[test]
+    fn test_encrypt_history() {
+        let key = key();
+        let mut h = NewHistory {
+            hostname: "host".to_string(),
+            working_directory: "/src/app".to_string(),
+            command: "ls".to_string(),
+            ..Default::default()
+        };
+        key.encrypt_history(&mut h);
+        assert!(h.encrypted);
+        assert_eq!(h.hostname, "host");
+        assert_eq!(h.blind_index, key.index("/src/app", "ls"));
+
+        key.decrypt_new_history(&mut h).unwrap();
+        assert_eq!(h.command, "ls");
+        assert_eq!(h.working_directory, "/src/app");
+    }
+}
@@ -0,0 +1,261 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{get, web, HttpResponse, Responder};
+use diesel_migrations::MigrationHarness;
+
+use crate::config::Config;
+use crate::listen::Address;
+use crate::models::Health;
+use crate::{telemetry, tls};
+use crate::{DbPool, MIGRATIONS};
+
+/// How long readiness and metrics wait for a connection, well below
+/// typical probe and scrape timeouts.
+pub(crate) const CHECKOUT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Registers the health endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz).service(readyz);
+}
+
+/// Liveness: the process is up and serving requests.
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(Health::ok())
+}
+
+/// Readiness: a database connection can be checked out and every
+/// migration has been applied. Answers 503 otherwise.
+#[get("/readyz")]
+async fn readyz(pool: web::Data<DbPool>) -> impl Responder {
+    let pool = pool.get_ref().clone();
+    let health = telemetry::block(move || {
+        let mut health = Health::ok();
+        let mut conn = match pool.get_timeout(CHECKOUT_TIMEOUT) {
+            Ok(conn) => conn,
+            Err(e) => return health.fail(format!("cannot get db connection from pool: {e}")),
+        };
+        match conn.pending_migrations(MIGRATIONS) {
+            Ok(pending) if pending.is_empty() => health,
+            Ok(pending) => {
+                health.pending_migrations = pending.iter().map(|m| m.name().to_string()).collect();
+                health.fail("migrations pending".to_string())
+            }
+            Err(e) => health.fail(format!("cannot check migrations: {e}")),
+        }
+    })
+    .await;
+
+    match health {
+        Ok(health) if health.error.is_none() => HttpResponse::Ok().json(health),
+        Ok(health) => HttpResponse::ServiceUnavailable().json(health),
+        Err(e) => HttpResponse::ServiceUnavailable().json(Health::ok().fail(e.to_string())),
+    }
+}
+
+/// Requests `path` from the server `config` describes, for `clh-server
+/// health`. Unix sockets are probed first, as they are served without TLS
+/// and so never ask for a client certificate. Wildcard TCP addresses are
+/// probed over loopback, with HTTPS when `[tls]` is set. Returns the body
+/// of a 2xx answer.
+///
+/// With socket activation, the server ignores `server.listen` in favor of
+/// the sockets systemd passes it, so `server.listen` has to name one of
+/// them for the probe to find the server.
+pub fn probe(config: &Config, path: &str, timeout: Duration) -> Result<String, String> {
+    let mut addresses = config.server.addresses()?;
+    addresses.sort_by_key(|address| !matches!(address, Address::Unix(_)));
+    let address = addresses.first().ok_or("server.listen must not be empty")?;
+
+    let (status, body) = match address {
+        Address::Unix(path_name) => {
+            let stream = UnixStream::connect(path_name)
+                .map_err(|e| format!("cannot connect to {}: {e}", path_name.display()))?;
+            stream
+                .set_read_timeout(Some(timeout))
+                .map_err(|e| e.to_string())?;
+            stream
+                .set_write_timeout(Some(timeout))
+                .map_err(|e| e.to_string())?;
+            get(stream, path)
+        }
+        Address::Tcp(addr) => {
+            let stream = connect(addr, timeout)?;
+            match config.tls {
+                Some(_) => {
+                    let server_name = "localhost".try_into().expect("localhost is a valid name");
+                    let conn =
+                        rustls::ClientConnection::new(Arc::new(tls::probe_config()), server_name)
+                            .map_err(|e| e.to_string())?;
+                    get(rustls::StreamOwned::new(conn, stream), path)
+                }
+                None => get(stream, path),
+            }
+        }
+    }
+    .map_err(|e| format!("cannot get {path}: {e}"))?;
+
+    if (200..300).contains(&status) {
+        Ok(body)
+    } else {
+        Err(format!("{path} answered {status}: {body}"))
+    }
+}
+
+/// Connects to the first address `addr` resolves to, over loopback if it
+/// is a wildcard address.
+fn connect(addr: &str, timeout: Duration) -> Result<TcpStream, String> {
+    let mut resolved = addr
+        .to_socket_addrs()
+        .map_err(|e| format!("invalid listen address {addr}: {e}"))?
+        .map(|mut socket_addr: SocketAddr| {
+            match socket_addr.ip() {
+                IpAddr::V4(ip) if ip.is_unspecified() => {
+                    socket_addr.set_ip(Ipv4Addr::LOCALHOST.into())
+                }
+                IpAddr::V6(ip) if ip.is_unspecified() => {
+                    socket_addr.set_ip(Ipv6Addr::LOCALHOST.into())
+                }
+                _ => {}
+            }
+            socket_addr
+        });
+    let socket_addr = resolved
+        .next()
+        .ok_or_else(|| format!("invalid listen address {addr}: no address"))?;
+    let stream = TcpStream::connect_timeout(&socket_addr, timeout)
+        .map_err(|e| format!("cannot connect to {socket_addr}: {e}"))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+    stream
+        .set_write_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+    Ok(stream)
+}
+
+/// Sends a bodiless GET and returns the status and body of the answer.
+fn get(mut stream: impl Read + Write, path: &str) -> io::Result<(u16, String)> {
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+    )?;
+    stream.flush()?;
+    let mut response = Vec::new();
+    // TLS peers may close the connection without notifying, once the
+    // answer is out.
+    if let Err(e) = stream.read_to_end(&mut response) {
+        if response.is_empty() {
+            return Err(e);
+        }
+    }
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an HTTP answer"))?;
+    Ok((status, body.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+
+    /// Answers a single request with `status`, returning the request line.
+    fn answer_once<S: Read + Write + Send + 'static>(
+        accept: impl FnOnce() -> S + Send + 'static,
+        status: &'static str,
+    ) -> std::thread::JoinHandle<String> {
+        std::thread::spawn(move || {
+            let mut stream = accept();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 0, "connection closed mid-request");
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body = r#"{"status":"ok"}"#;
+            write!(
+                stream,
+                "HTTP/1.1 {status}\r\ncontent-length: {}\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+            stream.flush().unwrap();
+            let request = String::from_utf8_lossy(&request).to_string();
+            request.lines().next().unwrap_or_default().to_string()
+        })
+    }
+
+    fn config(listen: Vec<String>) -> Config {
+        let mut config = Config::default();
+        config.server.listen = listen;
+        config
+    }
+
+    #[test]
+    fn test_probe_prefers_unix_sockets_and_reaches_wildcard_addresses() {
+        let timeout = Duration::from_secs(5);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = answer_once(move || listener.accept().unwrap().0, "200 OK");
+        let body = probe(&config(vec![format!("0.0.0.0:{port}")]), "/readyz", timeout).unwrap();
+        assert_eq!(body, r#"{"status":"ok"}"#);
+        assert_eq!(server.join().unwrap(), "GET /readyz HTTP/1.1");
+
+        let unique = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let socket = std::env::temp_dir().join(format!("clh-health-{unique}.sock"));
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = answer_once(
+            move || listener.accept().unwrap().0,
+            "503 Service Unavailable",
+        );
+        // The TCP address no longer answers, the socket is probed first.
+        let listen = vec![
+            format!("127.0.0.1:{port}"),
+            format!("unix:{}", socket.display()),
+        ];
+        let err = probe(&config(listen), "/healthz", timeout).unwrap_err();
+        assert!(err.starts_with("/healthz answered 503"), "{err}");
+        assert_eq!(server.join().unwrap(), "GET /healthz HTTP/1.1");
+        std::fs::remove_file(&socket).unwrap();
+    }
+
+    #[test]
+    fn test_probe_speaks_tls_when_configured() {
+        let pki = tls::tests::TestPki::new("health");
+        let mut config = config(Vec::new());
+        config.tls = Some(pki.config(None));
+        let server_config = Arc::new(
+            tls::Tls::load(config.tls.as_ref().unwrap())
+                .unwrap()
+                .server_config(),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        config.server.listen = vec![listener.local_addr().unwrap().to_string()];
+        let server = answer_once(
+            move || {
+                let conn = rustls::ServerConnection::new(server_config).unwrap();
+                rustls::StreamOwned::new(conn, listener.accept().unwrap().0)
+            },
+            "200 OK",
+        );
+        probe(&config, "/readyz", Duration::from_secs(5)).unwrap();
+        assert_eq!(server.join().unwrap(), "GET /readyz HTTP/1.1");
+        std::fs::remove_dir_all(&pki.dir).unwrap();
+    }
+}
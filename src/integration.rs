@@ -0,0 +1,75 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// Shells for which integration scripts can be generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// Returns a script that records every command run in `shell` to `server`
+/// and binds Ctrl-R to a search against it. `bin` is how the script invokes
+/// `clh-server`.
+pub fn script(shell: Shell, server: &str, token: Option<&str>, bin: &str) -> String {
+    let template = match shell {
+        Shell::Bash => include_str!("integration/bash.sh"),
+        Shell::Zsh => include_str!("integration/zsh.sh"),
+        Shell::Fish => include_str!("integration/fish.fish"),
+    };
+
+    let token_export = match (shell, token) {
+        (_, None) => String::new(),
+        (Shell::Fish, Some(token)) => format!("set -gx CLH_TOKEN {}\n", quote(shell, token)),
+        (_, Some(token)) => format!("export CLH_TOKEN={}\n", quote(shell, token)),
+    };
+
+    template
+        .replace("@CLH_SERVER@", &quote(shell, server))
+        .replace("@CLH_TOKEN_EXPORT@\n", &token_export)
+        .replace("@CLH_BIN@", &quote(shell, bin))
+}
+
+/// Quotes `value` as a single word for `shell`.
+fn quote(shell: Shell, value: &str) -> String {
+    match shell {
+        Shell::Bash | Shell::Zsh => format!("'{}'", value.replace('\'', r"'\''")),
+        Shell::Fish => format!("'{}'", value.replace('\\', r"\\").replace('\'', r"\'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_fills_in_server_and_token() {
+        let script = script(
+            Shell::Zsh,
+            "http://clh.example.com:8088",
+            Some("s3cr'et"),
+            "clh-server",
+        );
+        assert!(script.contains("export CLH_SERVER='http://clh.example.com:8088'\n"));
+        assert!(script.contains("export CLH_TOKEN='s3cr'\\''et'\n"));
+        assert!(script.contains("CLH_BIN=${CLH_BIN:-'clh-server'}"));
+        assert!(script.contains("bindkey '^R' __clh_search_widget"));
+        assert!(!script.contains('@'));
+    }
+
+    #[test]
+    fn test_script_without_token() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let script = script(shell, "http://localhost:8088", None, "/usr/bin/clh-server");
+            assert!(!script.contains("CLH_TOKEN"));
+            assert!(!script.contains('@'));
+        }
+    }
+
+    #[test]
+    fn test_quote_fish() {
+        assert_eq!(quote(Shell::Fish, r"it's a \ test"), r"'it\'s a \\ test'");
+    }
+}
@@ -0,0 +1,32 @@
+# clh-server integration for bash: eval "$(clh-server init bash)"
+export CLH_SERVER=@CLH_SERVER@
+@CLH_TOKEN_EXPORT@
+CLH_BIN=${CLH_BIN:-@CLH_BIN@}
+
+__clh_record() {
+  local exit_status=$?
+  local entry number command
+  entry=$(HISTTIMEFORMAT= builtin history 1)
+  [[ $entry =~ ^\ *([0-9]+)\*?\ +(.*)$ ]] || return $exit_status
+  number=${BASH_REMATCH[1]}
+  command=${BASH_REMATCH[2]}
+  if [[ -n $command && $number != "$__clh_last_number" ]]; then
+    __clh_last_number=$number
+    ("$CLH_BIN" client add --git --exit-status "$exit_status" -- "$command" >/dev/null 2>&1 &)
+  fi
+  return $exit_status
+}
+
+__clh_search() {
+  local selected
+  selected=$("$CLH_BIN" tui --query "$READLINE_LINE") || return
+  READLINE_LINE=$selected
+  READLINE_POINT=${#selected}
+}
+
+# Do not record the last command of the previous session again.
+[[ $(HISTTIMEFORMAT= builtin history 1) =~ ^\ *([0-9]+) ]] && __clh_last_number=${BASH_REMATCH[1]}
+if [[ ";${PROMPT_COMMAND};" != *";__clh_record;"* ]]; then
+  PROMPT_COMMAND="__clh_record${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+fi
+bind -x '"\C-r": __clh_search'
@@ -0,0 +1,19 @@
+# clh-server integration for fish: clh-server init fish | source
+set -gx CLH_SERVER @CLH_SERVER@
+@CLH_TOKEN_EXPORT@
+set -q CLH_BIN; or set -g CLH_BIN @CLH_BIN@
+
+function __clh_record --on-event fish_postexec
+    set -l exit_status $status
+    test -n "$argv[1]"; or return
+    command $CLH_BIN client add --git --exit-status $exit_status -- $argv[1] >/dev/null 2>&1 &
+    disown 2>/dev/null
+end
+
+function __clh_search
+    set -l selected (command $CLH_BIN tui --query (commandline))
+    and commandline --replace -- $selected
+    commandline --function repaint
+end
+
+bind \cr __clh_search
@@ -0,0 +1,31 @@
+# clh-server integration for zsh: eval "$(clh-server init zsh)"
+export CLH_SERVER=@CLH_SERVER@
+@CLH_TOKEN_EXPORT@
+CLH_BIN=${CLH_BIN:-@CLH_BIN@}
+
+__clh_preexec() {
+  __clh_command=$1
+}
+
+__clh_precmd() {
+  local exit_status=$?
+  [[ -z $__clh_command ]] && return
+  "$CLH_BIN" client add --git --exit-status "$exit_status" -- "$__clh_command" >/dev/null 2>&1 &!
+  __clh_command=
+}
+
+__clh_search_widget() {
+  local selected
+  selected=$("$CLH_BIN" tui --query "$BUFFER")
+  if [[ -n $selected ]]; then
+    BUFFER=$selected
+    CURSOR=${#BUFFER}
+  fi
+  zle reset-prompt
+}
+
+autoload -Uz add-zsh-hook
+add-zsh-hook preexec __clh_preexec
+add-zsh-hook precmd __clh_precmd
+zle -N __clh_search_widget
+bindkey '^R' __clh_search_widget
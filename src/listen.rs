@@ -0,0 +1,216 @@
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+
+use listenfd::ListenFd;
+
+/// Prefix of `server.listen` entries naming a Unix domain socket.
+const UNIX_PREFIX: &str = "unix:";
+
+/// An address the server listens on: `host:port`, or `unix:<path>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Address {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(format!("invalid listen address {s}: missing socket path")),
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => {
+                use std::net::ToSocketAddrs;
+                s.to_socket_addrs()
+                    .map_err(|e| format!("invalid listen address {s}: {e}"))?;
+                Ok(Self::Tcp(s.to_string()))
+            }
+        }
+    }
+}
+
+/// Parses a socket mode written in octal, such as `0600`.
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("invalid socket mode {s:?}, expected octal such as 0600"))
+}
+
+/// A listening socket, ready to be handed to the server.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Socket files created by `open`, removed when dropped so that the next
+/// start doesn't find them in the way.
+#[derive(Debug, Default)]
+pub struct SocketFiles(Vec<PathBuf>);
+
+impl Drop for SocketFiles {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Opens the listeners the server should accept connections on.
+///
+/// Sockets passed by systemd (or `systemfd`) take precedence; when there
+/// are any, `addresses` is ignored. Otherwise each address is bound, with
+/// Unix domain sockets given `mode` when set.
+pub fn open(addresses: &[Address], mode: Option<u32>) -> io::Result<(Vec<Listener>, SocketFiles)> {
+    let inherited = inherited(&mut ListenFd::from_env())?;
+    if !inherited.is_empty() {
+        return Ok((inherited, SocketFiles::default()));
+    }
+
+    let mut listeners = Vec::new();
+    let mut files = SocketFiles::default();
+    for address in addresses {
+        match address {
+            Address::Tcp(addr) => listeners.push(Listener::Tcp(TcpListener::bind(addr)?)),
+            Address::Unix(path) => {
+                listeners.push(Listener::Unix(bind_unix(path, mode)?));
+                files.0.push(path.clone());
+            }
+        }
+    }
+    Ok((listeners, files))
+}
+
+/// Takes every TCP and Unix stream socket passed through the `LISTEN_FDS`
+/// protocol.
+fn inherited(listenfd: &mut ListenFd) -> io::Result<Vec<Listener>> {
+    let mut listeners = Vec::new();
+    for idx in 0..listenfd.len() {
+        // A socket of the wrong kind is left in place, so try both.
+        if let Ok(Some(l)) = listenfd.take_tcp_listener(idx) {
+            listeners.push(Listener::Tcp(l));
+        } else if let Some(l) = listenfd.take_unix_listener(idx)? {
+            listeners.push(Listener::Unix(l));
+        }
+    }
+    Ok(listeners)
+}
+
+/// Binds a Unix domain socket at `path`, replacing a stale socket left by a
+/// previous run.
+///
+/// The socket is bound under a temporary name and only moved into place
+/// once it has its final permissions, so there is no window in which
+/// clients other than those `mode` allows can connect.
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let Some(mode) = mode else {
+        return UnixListener::bind(path);
+    };
+
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(format!(".{}", std::process::id()));
+    let staging = PathBuf::from(staging);
+    let _ = fs::remove_file(&staging);
+
+    let listener = UnixListener::bind(&staging)?;
+    let placed = fs::set_permissions(&staging, fs::Permissions::from_mode(mode))
+        .and_then(|()| fs::rename(&staging, path));
+    if let Err(e) = placed {
+        let _ = fs::remove_file(&staging);
+        return Err(e);
+    }
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    fn socket_path(label: &str) -> PathBuf {
+        let unique = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!("clh-{label}-{unique}.sock"))
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(
+            Address::parse("127.0.0.1:8088"),
+            Ok(Address::Tcp("127.0.0.1:8088".to_string()))
+        );
+        assert_eq!(
+            Address::parse("unix:/run/clh/clh.sock"),
+            Ok(Address::Unix(PathBuf::from("/run/clh/clh.sock")))
+        );
+        assert!(Address::parse("unix:").is_err());
+        assert!(Address::parse("8088").is_err());
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("0600"), Ok(0o600));
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert!(parse_mode("0800").is_err());
+        assert!(parse_mode("01777").is_err());
+    }
+
+    #[test]
+    fn test_open_unix_socket() {
+        let path = socket_path("open");
+        let addresses = [Address::Unix(path.clone())];
+
+        let (listeners, files) = open(&addresses, Some(0o600)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let [Listener::Unix(listener)] = &listeners[..] else {
+            panic!("expected one unix listener, got {listeners:?}");
+        };
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"ping").unwrap();
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut buf = [0; 4];
+        conn.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // A socket left behind by a previous run is replaced.
+        drop(listeners);
+        std::mem::forget(files);
+        let (_listeners, files) = open(&addresses, None).unwrap();
+        UnixStream::connect(&path).unwrap();
+
+        drop(files);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_open_refuses_to_replace_other_files() {
+        let path = socket_path("file");
+        fs::write(&path, "not a socket").unwrap();
+
+        let error = open(&[Address::Unix(path.clone())], None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+        fs::remove_file(&path).unwrap();
+    }
+}
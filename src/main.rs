@@ -1,26 +1,92 @@
-use actix_web::middleware::Logger;
-use actix_web::{delete, error, get, post, web, HttpResponse};
-use actix_web::{App, HttpServer, Responder, Result};
-use listenfd::ListenFd;
+use actix_web::middleware::{from_fn, Condition};
+use actix_web::{delete, error, get, post, put, web, HttpResponse};
+use actix_web::{App, HttpRequest, HttpServer, Responder, Result};
+use tracing_actix_web::TracingLogger;
 
+use clap::Parser;
 use diesel::pg::PgConnection;
 use diesel::r2d2::{self, ConnectionManager};
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use dotenv::dotenv;
 
 mod actions;
+mod api;
+mod at_rest;
+mod audit;
+mod cli;
+mod client;
+mod config;
+mod e2e;
+mod health;
+mod integration;
+mod listen;
+mod metrics;
 mod models;
-mod schema;
+mod paths;
+mod rate_limit;
+mod spool;
+mod stream;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod tui;
+mod ui;
+mod webhooks;
+
+use clh_types::schema;
 
+use crate::at_rest::Keyring;
+use crate::cli::{Cli, Command, ConfigCommand, RenormalizeArgs, RotateKeysArgs, ServeArgs};
+use crate::config::{Config, Features, Limits};
 use crate::models::*;
+use crate::paths::PathMappings;
 
-type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
+pub(crate) type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
+
+/// Rewrites the `pwd` filter the same way `create` rewrites working
+/// directories, so that it can be compared with `normalized_directory`.
+fn normalize_pwd(mut q: SearchQuery, path_mappings: &PathMappings) -> SearchQuery {
+    if let Some(ref pwd) = q.pwd {
+        let origin = q.origin.as_deref().or(q.hostname.as_deref());
+        q.pwd = Some(path_mappings.normalize(origin, pwd));
+    }
+    q
+}
 
+/// Searches histories, most recently run first, with the total number of
+/// matches in `X-Total-Count`.
+#[utoipa::path(
+    tag = "histories",
+    operation_id = "search",
+    params(SearchQuery),
+    responses((
+        status = 200,
+        body = [History],
+        headers(("X-Total-Count" = i64, description = "Number of matches"))
+    ))
+)]
 #[get("/")]
-async fn index(pool: web::Data<DbPool>, q: web::Query<SearchQuery>) -> Result<impl Responder> {
+async fn index(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    path_mappings: web::Data<PathMappings>,
+    limits: web::Data<Limits>,
+    features: web::Data<Features>,
+    keys: web::Data<Keyring>,
+    q: web::Query<SearchQuery>,
+) -> Result<HttpResponse> {
+    if features.ui && ui::wants_html(&req) && req.query_string().is_empty() {
+        return Ok(HttpResponse::SeeOther()
+            .insert_header(("Location", "/ui/"))
+            .finish());
+    }
+
     let mut conn = pool.get().expect("cannot get db connection from pool");
 
-    match web::block(move || actions::search(&mut conn, &q)).await {
+    let q = limits.apply(normalize_pwd(q.into_inner(), &path_mappings));
+
+    match telemetry::block(move || actions::search_decrypted(&mut conn, &q, &keys)).await {
         Ok(response) => match response {
             Ok((histories, total)) => Ok(HttpResponse::Ok()
                 .insert_header(("X-Total-Count", total.to_string()))
@@ -31,11 +97,28 @@ async fn index(pool: web::Data<DbPool>, q: web::Query<SearchQuery>) -> Result<im
     }
 }
 
+/// Returns a history, or `null` if there is none with this id.
+#[utoipa::path(
+    tag = "histories",
+    operation_id = "show",
+    responses((status = 200, body = Option<History>))
+)]
 #[get("/{id}")]
-async fn show(pool: web::Data<DbPool>, id: web::Path<i32>) -> Result<impl Responder> {
+async fn show(
+    pool: web::Data<DbPool>,
+    keys: web::Data<Keyring>,
+    id: web::Path<i32>,
+) -> Result<impl Responder> {
     let mut conn = pool.get().expect("cannot get db connection from pool");
 
-    match web::block(move || actions::find(&mut conn, *id)).await {
+    let wrapped_response = telemetry::block(move || {
+        let mut history = actions::find(&mut conn, *id)?;
+        actions::decrypt(&keys, &mut history)?;
+        Ok::<_, diesel::result::Error>(history)
+    })
+    .await;
+
+    match wrapped_response {
         Ok(response) => match response {
             Ok(r) => Ok(web::Json(r)),
             Err(e) => Err(error::ErrorInternalServerError(e)),
@@ -44,37 +127,534 @@ async fn show(pool: web::Data<DbPool>, id: web::Path<i32>) -> Result<impl Respon
     }
 }
 
+/// Header naming a request, for it to be processed once only however many
+/// times it is sent.
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+fn check_idempotency_key(key: &str, name: &str) -> Result<()> {
+    if key.is_empty() || key.len() > NewHistory::MAX_IDEMPOTENCY_KEY {
+        return Err(error::ErrorBadRequest(format!(
+            "{name} must be 1 to {} bytes long",
+            NewHistory::MAX_IDEMPOTENCY_KEY
+        )));
+    }
+    Ok(())
+}
+
+fn check_new_history(new_history: &NewHistory, limits: &Limits) -> Result<()> {
+    if new_history.command.len() > limits.max_command_length {
+        return Err(error::ErrorPayloadTooLarge(format!(
+            "command is longer than {} bytes",
+            limits.max_command_length
+        )));
+    }
+    if let Some(ref event_id) = new_history.client_event_id {
+        check_idempotency_key(event_id, "client_event_id")?;
+    }
+    if let Some(executed_at) = new_history.executed_at {
+        let ahead = (executed_at - chrono::Utc::now()).num_seconds();
+        if ahead > limits.max_clock_skew as i64 {
+            return Err(error::ErrorBadRequest(format!(
+                "executed_at is {ahead} seconds ahead of the server's clock, at most {} are allowed",
+                limits.max_clock_skew
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The `Idempotency-Key` of `req`, or else the `client_event_id` of the
+/// history it records.
+fn idempotency_key(req: &HttpRequest, new_history: &NewHistory) -> Result<Option<String>> {
+    match req.headers().get(IDEMPOTENCY_KEY) {
+        Some(value) => {
+            let key = value
+                .to_str()
+                .map_err(|_| error::ErrorBadRequest("Idempotency-Key must be ASCII"))?;
+            check_idempotency_key(key, "Idempotency-Key")?;
+            Ok(Some(key.to_string()))
+        }
+        None => Ok(new_history.client_event_id.clone()),
+    }
+}
+
+/// Fields of recorded histories not kept with idempotency keys, so that
+/// commands are only stored in histories, encrypted if so configured.
+/// Requests sent again must hold the same ones, as their fingerprint is
+/// checked, so they are taken from there.
+const UNKEPT_FIELDS: [&str; 3] = ["command", "working_directory", "normalized_directory"];
+
+/// Identifies a run as submitted, for requests sent again with its
+/// idempotency key to be told apart from other runs reusing it. Unlike
+/// `content_hash`, it covers when and how the command ran, and doesn't
+/// depend on the at-rest key.
+fn run_fingerprint(new_history: &NewHistory) -> String {
+    use sha2::{Digest, Sha256};
+
+    let executed_at = new_history.executed_at.map(|t| t.to_rfc3339());
+    let exit_status = new_history.exit_status.map(|s| s.to_string());
+    let mut hasher = Sha256::new();
+    for field in [
+        Some(new_history.hostname.as_str()),
+        Some(new_history.working_directory.as_str()),
+        Some(new_history.command.as_str()),
+        executed_at.as_deref(),
+        exit_status.as_deref(),
+    ] {
+        // Length prefixed, so that no two runs hash the same fields.
+        match field {
+            Some(value) => {
+                hasher.update((value.len() as u64).to_be_bytes());
+                hasher.update(value.as_bytes());
+            }
+            None => hasher.update(u64::MAX.to_be_bytes()),
+        }
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// A history as recorded, or as recorded by the first request with the
+/// same idempotency key.
+struct Recorded {
+    body: serde_json::Value,
+    /// Webhooks to notify of the history, if it was just recorded.
+    hooks: Vec<Webhook>,
+    /// The history the run was recorded in, `None` if it was replayed.
+    history_id: Option<i32>,
+}
+
+#[derive(Debug)]
+enum RecordError {
+    Database(diesel::result::Error),
+    /// The idempotency key was claimed for another run.
+    KeyReused(String),
+}
+
+impl From<diesel::result::Error> for RecordError {
+    fn from(e: diesel::result::Error) -> Self {
+        Self::Database(e)
+    }
+}
+
+impl From<RecordError> for error::Error {
+    fn from(e: RecordError) -> Self {
+        match e {
+            RecordError::Database(e) => error::ErrorInternalServerError(e),
+            RecordError::KeyReused(key) => error::ErrorUnprocessableEntity(format!(
+                "idempotency key {key:?} was used for another run"
+            )),
+        }
+    }
+}
+
+/// Records `new_history`, unless a request with the same idempotency `key`
+/// from the same host already did, in which case its response is returned.
+/// Must be called in a transaction.
+fn record(
+    conn: &mut PgConnection,
+    path_mappings: &PathMappings,
+    keys: &Keyring,
+    key: Option<&str>,
+    mut new_history: NewHistory,
+    notify: bool,
+) -> Result<Recorded, RecordError> {
+    let to_json = |h: &NewHistory| {
+        serde_json::to_value(h).map_err(|e| diesel::result::Error::SerializationError(e.into()))
+    };
+
+    new_history.hostname = actions::resolve_host(conn, &new_history.hostname)?;
+    let fingerprint = run_fingerprint(&new_history);
+    // Clocks a little ahead of the server's don't move histories into the
+    // future.
+    let now = chrono::Utc::now();
+    new_history.executed_at = new_history.executed_at.map(|t| t.min(now));
+    // Encrypted directories can't be mapped, only matched exactly.
+    if !new_history.encrypted {
+        new_history.normalized_directory = Some(
+            path_mappings.normalize(Some(&new_history.hostname), &new_history.working_directory),
+        );
+    }
+    let submitted = to_json(&new_history)?;
+
+    keys.seal(&mut new_history);
+    if let Some(key) = key {
+        let claimed =
+            actions::claim_idempotency_key(conn, &new_history.hostname, key, &fingerprint)?;
+        if let Some((claimed_for, response)) = claimed {
+            if claimed_for != fingerprint {
+                return Err(RecordError::KeyReused(key.to_string()));
+            }
+            let mut body = submitted;
+            if let (Some(body), Some(serde_json::Value::Object(kept))) =
+                (body.as_object_mut(), response)
+            {
+                body.extend(kept);
+            }
+            return Ok(Recorded {
+                body,
+                hooks: Vec::new(),
+                history_id: None,
+            });
+        }
+    }
+
+    let (history_id, mut created) = actions::create_history(conn, &new_history)?;
+    keys.open_new_history(&mut created)
+        .map_err(|e| diesel::result::Error::DeserializationError(e.into()))?;
+    let body = to_json(&created)?;
+    if let Some(key) = key {
+        let mut kept = body.clone();
+        if let Some(kept) = kept.as_object_mut() {
+            for field in UNKEPT_FIELDS {
+                kept.remove(field);
+            }
+        }
+        actions::store_idempotent_response(conn, &created.hostname, key, &kept)?;
+    }
+    let hooks = if notify {
+        actions::matching_webhooks(conn, "created", &created.command)?
+    } else {
+        Vec::new()
+    };
+    Ok(Recorded {
+        body,
+        hooks,
+        history_id: Some(history_id),
+    })
+}
+
+/// Forgets the idempotency keys older than `limits.idempotency_window`,
+/// before claiming new ones.
+fn forget_idempotency_keys(
+    conn: &mut PgConnection,
+    limits: &Limits,
+) -> Result<(), diesel::result::Error> {
+    let window = chrono::Duration::seconds(limits.idempotency_window as i64);
+    actions::forget_idempotency_keys(conn, window)?;
+    Ok(())
+}
+
+/// Records a run of a command, counting it against the existing history
+/// of the command if there is one, as run at `executed_at` if given.
+///
+/// A request sent again with the same `Idempotency-Key` header, or else
+/// `client_event_id`, from the same host within `limits.idempotency_window`
+/// is not recorded again, but answered as the first one was, with
+/// `Idempotent-Replayed: true`. Reusing the key for another run is an
+/// error.
+#[utoipa::path(
+    tag = "histories",
+    operation_id = "create",
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Names the request, for it to be recorded once only")
+    ),
+    request_body(content = NewHistory, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 201, body = NewHistory),
+        (status = 400, description = "The idempotency key is invalid, or executed_at is in the future"),
+        (status = 413, description = "The command is too long"),
+        (status = 422, description = "The idempotency key was used for another run")
+    )
+)]
 #[post("/")]
 async fn create(
+    req: HttpRequest,
     pool: web::Data<DbPool>,
+    path_mappings: web::Data<PathMappings>,
+    dispatcher: web::Data<webhooks::Dispatcher>,
+    keys: web::Data<Keyring>,
+    limits: web::Data<Limits>,
     new_history: web::Form<NewHistory>,
 ) -> Result<impl Responder> {
+    use diesel::Connection;
+
+    let mut new_history = new_history.into_inner();
+    check_new_history(&new_history, &limits)?;
+    let key = idempotency_key(&req, &new_history)?;
+
     let mut conn = pool.get().expect("cannot get db connection from pool");
 
-    let wrapped_response = web::block(move || {
-        actions::create_history(
-            &mut conn,
-            &new_history.hostname,
-            &new_history.working_directory,
-            &new_history.command,
-        )
+    // Clients authenticated by certificate can only record as themselves.
+    if let Some(identity) = req.conn_data::<tls::ClientIdentity>() {
+        new_history.hostname = identity.hostname.clone();
+    }
+    let notify = dispatcher.enabled();
+
+    let wrapped_response = telemetry::block(move || {
+        if key.is_some() {
+            forget_idempotency_keys(&mut conn, &limits)?;
+        }
+        conn.transaction(|conn| {
+            let key = key.as_deref();
+            record(conn, &path_mappings, &keys, key, new_history, notify)
+        })
+    })
+    .await;
+
+    match wrapped_response {
+        Ok(response) => match response {
+            Ok(r) if r.history_id.is_none() => Ok(HttpResponse::Created()
+                .insert_header(("Idempotent-Replayed", "true"))
+                .json(r.body)),
+            Ok(r) => {
+                let payload = serde_json::json!({ "history": r.body });
+                dispatcher.dispatch(r.hooks, "created", payload);
+                Ok(HttpResponse::Created().json(r.body))
+            }
+            Err(e) => Err(e.into()),
+        },
+        Err(e) => Err(error::ErrorInternalServerError(e)),
+    }
+}
+
+/// Records many runs at once, all or none of them, returning them in the
+/// order given. Each is recorded as run at its `executed_at` if given.
+/// Runs whose `client_event_id` was already recorded are not recorded
+/// again, and returned as they were then.
+#[utoipa::path(
+    tag = "histories",
+    operation_id = "create_bulk",
+    request_body = [NewHistory],
+    responses(
+        (status = 201, body = [NewHistory]),
+        (status = 400, description = "Too many histories, or one is invalid"),
+        (status = 413, description = "A command is too long"),
+        (status = 422, description = "A client_event_id was used for another run")
+    )
+)]
+#[post("/bulk")]
+#[allow(clippy::too_many_arguments)]
+async fn bulk(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    path_mappings: web::Data<PathMappings>,
+    dispatcher: web::Data<webhooks::Dispatcher>,
+    keys: web::Data<Keyring>,
+    limits: web::Data<Limits>,
+    actor: audit::Actor,
+    new_histories: web::Json<Vec<NewHistory>>,
+) -> Result<impl Responder> {
+    use diesel::Connection;
+
+    let mut new_histories = new_histories.into_inner();
+    if new_histories.len() > NewHistory::MAX_BULK {
+        return Err(error::ErrorBadRequest(format!(
+            "at most {} histories can be recorded at once",
+            NewHistory::MAX_BULK
+        )));
+    }
+    for new_history in &new_histories {
+        check_new_history(new_history, &limits)?;
+    }
+
+    let mut conn = pool.get().expect("cannot get db connection from pool");
+
+    if let Some(identity) = req.conn_data::<tls::ClientIdentity>() {
+        for new_history in &mut new_histories {
+            new_history.hostname = identity.hostname.clone();
+        }
+    }
+    let notify = dispatcher.enabled();
+
+    let wrapped_response = telemetry::block(move || {
+        if new_histories.iter().any(|h| h.client_event_id.is_some()) {
+            forget_idempotency_keys(&mut conn, &limits)?;
+        }
+        conn.transaction(|conn| {
+            let recorded = new_histories
+                .into_iter()
+                .map(|h| {
+                    let key = h.client_event_id.clone();
+                    record(conn, &path_mappings, &keys, key.as_deref(), h, notify)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut ids = recorded
+                .iter()
+                .filter_map(|r| r.history_id)
+                .collect::<Vec<_>>();
+            let count = ids.len();
+            if count > 0 {
+                ids.sort_unstable();
+                ids.dedup();
+                let event = actor
+                    .event("history.bulk_create", ids)
+                    .details(serde_json::json!({ "count": count }));
+                actions::record_audit(conn, &event)?;
+            }
+            Ok::<_, RecordError>(recorded)
+        })
     })
     .await;
 
     match wrapped_response {
         Ok(response) => match response {
-            Ok(r) => Ok(HttpResponse::Created().json(r)),
+            Ok(recorded) => {
+                let mut created = Vec::with_capacity(recorded.len());
+                for r in recorded {
+                    if r.history_id.is_some() {
+                        let payload = serde_json::json!({ "history": r.body });
+                        dispatcher.dispatch(r.hooks, "created", payload);
+                    }
+                    created.push(r.body);
+                }
+                Ok(HttpResponse::Created().json(created))
+            }
+            Err(e) => Err(e.into()),
+        },
+        Err(e) => Err(error::ErrorInternalServerError(e)),
+    }
+}
+
+#[utoipa::path(
+    tag = "histories",
+    operation_id = "stats",
+    params(SearchQuery, StatsQuery),
+    responses((status = 200, body = Stats))
+)]
+#[get("/stats")]
+async fn stats(
+    pool: web::Data<DbPool>,
+    path_mappings: web::Data<PathMappings>,
+    keys: web::Data<Keyring>,
+    q: web::Query<SearchQuery>,
+    stats_query: web::Query<StatsQuery>,
+) -> Result<impl Responder> {
+    let mut conn = pool.get().expect("cannot get db connection from pool");
+
+    let q = normalize_pwd(q.into_inner(), &path_mappings);
+    let top = stats_query.effective_top();
+
+    match telemetry::block(move || actions::stats_decrypted(&mut conn, &q, top, &keys)).await {
+        Ok(response) => match response {
+            Ok(r) => Ok(web::Json(r)),
             Err(e) => Err(error::ErrorInternalServerError(e)),
         },
         Err(e) => Err(error::ErrorInternalServerError(e)),
     }
 }
 
-#[delete("/{id}")]
-async fn delete(pool: web::Data<DbPool>, id: web::Path<i32>) -> Result<impl Responder> {
+#[utoipa::path(
+    tag = "hosts",
+    operation_id = "list_hosts",
+    responses((status = 200, body = [HostSummary]))
+)]
+#[get("/hosts")]
+async fn hosts(pool: web::Data<DbPool>) -> Result<impl Responder> {
+    let mut conn = pool.get().expect("cannot get db connection from pool");
+
+    match telemetry::block(move || actions::list_hosts(&mut conn)).await {
+        Ok(response) => match response {
+            Ok(r) => Ok(web::Json(r)),
+            Err(e) => Err(error::ErrorInternalServerError(e)),
+        },
+        Err(e) => Err(error::ErrorInternalServerError(e)),
+    }
+}
+
+#[utoipa::path(
+    tag = "hosts",
+    operation_id = "update_host",
+    request_body = HostUpdate,
+    responses((status = 200, body = Host))
+)]
+#[put("/hosts/{name}")]
+async fn update_host(
+    pool: web::Data<DbPool>,
+    actor: audit::Actor,
+    name: web::Path<String>,
+    update: web::Json<HostUpdate>,
+) -> Result<impl Responder> {
+    use diesel::Connection;
+
+    let mut conn = pool.get().expect("cannot get db connection from pool");
+
+    let wrapped_response = telemetry::block(move || {
+        conn.transaction(|conn| {
+            let before = actions::find_host(conn, &name)?;
+            let (host, merged) = actions::update_host(conn, &name, &update)?;
+            let event = actor
+                .event("host.update", Vec::new())
+                .details(serde_json::json!({
+                    "name": host.name,
+                    "aliases_before": before.as_ref().map(|h| &h.aliases),
+                    "aliases_after": host.aliases,
+                    "tags_before": before.as_ref().map(|h| &h.tags),
+                    "tags_after": host.tags,
+                    "merged_hosts": merged,
+                }));
+            actions::record_audit(conn, &event)?;
+            Ok::<_, diesel::result::Error>(host)
+        })
+    })
+    .await;
+
+    match wrapped_response {
+        Ok(response) => match response {
+            Ok(r) => Ok(web::Json(r)),
+            Err(e) => Err(error::ErrorInternalServerError(e)),
+        },
+        Err(e) => Err(error::ErrorInternalServerError(e)),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct IntegrationQuery {
+    token: Option<String>,
+}
+
+/// Serves the shell integration script, pointing at this server as it was
+/// reached by the client. The token is taken from `?token=` or the
+/// `Authorization` header.
+#[get("/integration/{shell}")]
+async fn integration_script(
+    req: HttpRequest,
+    shell: web::Path<integration::Shell>,
+    q: web::Query<IntegrationQuery>,
+) -> impl Responder {
+    let info = req.connection_info();
+    let server = format!("{}://{}", info.scheme(), info.host());
+    let bearer = req
+        .headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let token = q.token.as_deref().or(bearer);
+
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(integration::script(*shell, &server, token, "clh-server"))
+}
+
+/// Takes a history out of the trash, returning `null` if it wasn't there.
+#[utoipa::path(
+    tag = "histories",
+    operation_id = "restore",
+    responses((status = 200, body = Option<History>))
+)]
+#[post("/{id}/restore")]
+async fn restore(
+    pool: web::Data<DbPool>,
+    keys: web::Data<Keyring>,
+    actor: audit::Actor,
+    id: web::Path<i32>,
+) -> Result<impl Responder> {
+    use diesel::Connection;
+
     let mut conn = pool.get().expect("cannot get db connection from pool");
 
-    match web::block(move || actions::delete_history(&mut conn, *id)).await {
+    let wrapped_response = telemetry::block(move || {
+        conn.transaction(|conn| {
+            let mut history = actions::restore_history(conn, *id)?;
+            if history.is_some() {
+                actions::record_audit(conn, &actor.event("history.restore", vec![*id]))?;
+            }
+            actions::decrypt(&keys, &mut history)?;
+            Ok::<_, diesel::result::Error>(history)
+        })
+    })
+    .await;
+
+    match wrapped_response {
         Ok(response) => match response {
             Ok(r) => Ok(web::Json(r)),
             Err(e) => Err(error::ErrorInternalServerError(e)),
@@ -83,38 +663,383 @@ async fn delete(pool: web::Data<DbPool>, id: web::Path<i32>) -> Result<impl Resp
     }
 }
 
-const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations");
+/// Moves a history to the trash, or removes it for good with `purge`.
+#[utoipa::path(
+    tag = "histories",
+    operation_id = "delete",
+    params(DeleteQuery),
+    responses((status = 200, body = DeletedHistoryCount))
+)]
+#[delete("/{id}")]
+async fn delete(
+    pool: web::Data<DbPool>,
+    dispatcher: web::Data<webhooks::Dispatcher>,
+    keys: web::Data<Keyring>,
+    actor: audit::Actor,
+    id: web::Path<i32>,
+    q: web::Query<DeleteQuery>,
+) -> Result<impl Responder> {
+    use diesel::Connection;
+
+    let mut conn = pool.get().expect("cannot get db connection from pool");
+    let purge = q.purge.unwrap_or(false);
+    let notify = dispatcher.enabled();
+
+    let wrapped_response = telemetry::block(move || {
+        let mut history = actions::find_including_deleted(&mut conn, *id)?;
+        actions::decrypt(&keys, &mut history)?;
+        // A deletion nobody can account for is never committed.
+        let deleted = conn.transaction(|conn| {
+            let deleted = actions::delete_history(conn, *id, purge)?;
+            if deleted.count > 0 {
+                let action = if purge {
+                    "history.purge"
+                } else {
+                    "history.delete"
+                };
+                let event = actor
+                    .event(action, vec![*id])
+                    .filters(&*q)
+                    .details(serde_json::json!({ "count": deleted.count }));
+                actions::record_audit(conn, &event)?;
+            }
+            Ok::<_, diesel::result::Error>(deleted)
+        })?;
+        let hooks = match history {
+            Some(ref h) if notify && deleted.count > 0 => {
+                actions::matching_webhooks(&mut conn, "deleted", &h.command)?
+            }
+            _ => Vec::new(),
+        };
+        Ok::<_, diesel::result::Error>((deleted, history, hooks))
+    })
+    .await;
+
+    match wrapped_response {
+        Ok(response) => match response {
+            Ok((r, history, hooks)) => {
+                let fields = serde_json::json!({ "history": history, "purged": purge });
+                dispatcher.dispatch(hooks, "deleted", fields);
+                Ok(web::Json(r))
+            }
+            Err(e) => Err(error::ErrorInternalServerError(e)),
+        },
+        Err(e) => Err(error::ErrorInternalServerError(e)),
+    }
+}
+
+/// Registers the JSON API, for mounting under `api::PREFIX` and, as
+/// deprecated aliases, at the root.
+fn routes(cfg: &mut web::ServiceConfig, features: Features) {
+    cfg.service(index)
+        .service(stats)
+        .service(hosts)
+        .service(update_host)
+        .configure(|cfg| {
+            if features.stream {
+                stream::configure(cfg);
+            }
+            if features.webhooks {
+                webhooks::configure(cfg);
+            }
+        })
+        .configure(audit::configure)
+        .service(bulk)
+        .service(show)
+        .service(create)
+        .service(restore)
+        .service(delete);
+}
+
+/// Registers everything served, the API last as its aliases at the root
+/// would shadow the rest.
+fn configure_app(cfg: &mut web::ServiceConfig, features: Features) {
+    cfg.service(
+        web::scope(api::PREFIX)
+            .configure(api::configure)
+            .configure(|cfg| routes(cfg, features)),
+    )
+    .service(integration_script)
+    .configure(|cfg| {
+        if features.ui {
+            ui::configure(cfg);
+        }
+        if features.metrics {
+            metrics::configure(cfg);
+        }
+    })
+    .configure(health::configure)
+    .service(
+        web::scope("")
+            .wrap(from_fn(api::deprecated))
+            .configure(|cfg| routes(cfg, features)),
+    );
+}
+
+pub(crate) const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations");
 
-#[actix_rt::main]
-async fn main() -> std::io::Result<()> {
+fn main() -> std::io::Result<()> {
     dotenv().ok();
-    env_logger::init();
+    let cli = Cli::parse();
+
+    match cli.command {
+        None => run_server(&cli.serve),
+        Some(Command::Serve(ref args)) => run_server(args),
+        Some(Command::Config(args)) => match args.command {
+            ConfigCommand::Check(args) => match Config::load(&args) {
+                Ok(config) => {
+                    print!("{}", config.to_toml());
+                    Ok(())
+                }
+                Err(e) => {
+                    eprintln!("clh-server: {e}");
+                    std::process::exit(1);
+                }
+            },
+        },
+        Some(Command::RotateKeys(args)) => rotate_keys(&args),
+        Some(Command::Renormalize(args)) => renormalize(&args),
+        Some(Command::Client(args)) => {
+            if let Err(e) = client::run(*args) {
+                eprintln!("clh-server: {e}");
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Some(Command::Tui(args)) => match tui::run(args) {
+            Ok(Some(command)) => {
+                println!("{command}");
+                Ok(())
+            }
+            Ok(None) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("clh-server: {e}");
+                std::process::exit(1);
+            }
+        },
+        Some(Command::Health(args)) => {
+            let path = if args.live { "/healthz" } else { "/readyz" };
+            let probed = Config::load(&args.serve).and_then(|config| {
+                health::probe(&config, path, std::time::Duration::from_secs(args.timeout))
+            });
+            match probed {
+                Ok(body) => {
+                    println!("{body}");
+                    Ok(())
+                }
+                Err(e) => {
+                    eprintln!("clh-server: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Command::Init(args)) => {
+            let bin = std::env::current_exe()?;
+            print!(
+                "{}",
+                integration::script(
+                    args.shell,
+                    &args.connection.server,
+                    args.connection.token.as_deref(),
+                    &bin.to_string_lossy()
+                )
+            );
+            Ok(())
+        }
+    }
+}
+
+fn run_server(args: &ServeArgs) -> std::io::Result<()> {
+    let config = Config::load(args).unwrap_or_else(|e| {
+        eprintln!("clh-server: {e}");
+        std::process::exit(2);
+    });
+    let _telemetry = telemetry::init(config.log.format, config.log.filter.as_deref());
+    actix_rt::System::new().block_on(serve(config))
+}
+
+/// Re-encrypts stored histories with the current key, in batches so that
+/// the server can keep running meanwhile.
+fn rotate_keys(args: &RotateKeysArgs) -> std::io::Result<()> {
+    use diesel::Connection;
+
+    let config = Config::load(&args.serve).unwrap_or_else(|e| {
+        eprintln!("clh-server: {e}");
+        std::process::exit(2);
+    });
+    let Some(ref encryption) = config.encryption else {
+        eprintln!("clh-server: rotate-keys needs an [encryption] section in the configuration");
+        std::process::exit(2);
+    };
+    let keys = Keyring::load(encryption).map_err(std::io::Error::other)?;
+    let database_url = config.database_url.expect("validated by Config::load");
+    let mut conn = PgConnection::establish(&database_url).map_err(std::io::Error::other)?;
+    conn.run_pending_migrations(MIGRATIONS)
+        .map_err(std::io::Error::other)?;
+
+    let (mut after, mut reencrypted, mut merged) = (0, 0, 0);
+    loop {
+        let batch = actions::rotate_keys(&mut conn, &keys, after, args.batch_size, args.all)
+            .map_err(std::io::Error::other)?;
+        let Some(last_id) = batch.last_id else {
+            break;
+        };
+        after = last_id;
+        reencrypted += batch.reencrypted;
+        merged += batch.merged;
+        eprintln!("clh-server: re-encrypted {reencrypted} histories, up to id {last_id}");
+    }
+    let event = audit::Actor::cli()
+        .event("keys.rotate", Vec::new())
+        .details(serde_json::json!({
+            "key_id": encryption.current,
+            "all": args.all,
+            "reencrypted": reencrypted,
+            "merged": merged,
+        }));
+    actions::record_audit(&mut conn, &event).map_err(std::io::Error::other)?;
+    println!(
+        "Re-encrypted {reencrypted} histories with key {}, merged {merged} duplicates",
+        encryption.current
+    );
+    Ok(())
+}
+
+fn renormalize(args: &RenormalizeArgs) -> std::io::Result<()> {
+    use diesel::Connection;
+
+    let config = Config::load(&args.serve).unwrap_or_else(|e| {
+        eprintln!("clh-server: {e}");
+        std::process::exit(2);
+    });
+    let keys = match config.encryption {
+        Some(ref encryption) => Keyring::load(encryption).map_err(std::io::Error::other)?,
+        None => Keyring::default(),
+    };
+    let path_mappings = config.load_path_mappings().map_err(std::io::Error::other)?;
+    let database_url = config.database_url.expect("validated by Config::load");
+    let mut conn = PgConnection::establish(&database_url).map_err(std::io::Error::other)?;
+    conn.run_pending_migrations(MIGRATIONS)
+        .map_err(std::io::Error::other)?;
+
+    let (mut after, mut updated) = (0, 0);
+    loop {
+        let batch = actions::renormalize(&mut conn, &keys, &path_mappings, after, args.batch_size)
+            .map_err(std::io::Error::other)?;
+        let Some(last_id) = batch.last_id else {
+            break;
+        };
+        after = last_id;
+        updated += batch.updated;
+        eprintln!("clh-server: normalized {updated} directories again, up to id {last_id}");
+    }
+    let event = audit::Actor::cli()
+        .event("history.renormalize", Vec::new())
+        .details(serde_json::json!({ "updated": updated }));
+    actions::record_audit(&mut conn, &event).map_err(std::io::Error::other)?;
+    println!("Normalized the working directories of {updated} histories again");
+    Ok(())
+}
 
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL is required");
-    let manager = ConnectionManager::<PgConnection>::new(database_url);
+async fn serve(config: Config) -> std::io::Result<()> {
+    let database_url = config
+        .database_url
+        .clone()
+        .expect("validated by Config::load");
+    let path_mappings = web::Data::new(config.load_path_mappings().expect("invalid path mappings"));
+    let manager = ConnectionManager::<PgConnection>::new(database_url.clone());
     let pool = r2d2::Pool::builder()
+        .max_size(config.pool.max_size)
+        .min_idle(config.pool.min_idle)
+        .connection_timeout(config.pool.connection_timeout())
+        .event_handler(Box::new(metrics::PoolEvents))
         .build(manager)
         .expect("Failed to create pool");
 
     let mut conn = pool.get().expect("cannot get db connection from pool");
     conn.run_pending_migrations(MIGRATIONS).unwrap();
 
-    let mut listenfd = ListenFd::from_env();
+    let keys = web::Data::new(match config.encryption {
+        Some(ref encryption) => Keyring::load(encryption).map_err(std::io::Error::other)?,
+        None => Keyring::default(),
+    });
+    let key_ids = actions::key_ids(&mut conn).map_err(std::io::Error::other)?;
+    let missing = keys.missing(&key_ids);
+    if !missing.is_empty() {
+        return Err(std::io::Error::other(format!(
+            "histories are encrypted with keys missing from [encryption]: {}",
+            missing.join(", ")
+        )));
+    }
+    drop(conn);
+
+    let features = config.features;
+    let broadcaster = web::Data::new(stream::Broadcaster::default());
+    if features.stream {
+        stream::spawn_listener(database_url, keys.clone().into_inner(), &broadcaster);
+    }
+
+    let dispatcher = web::Data::new(if features.webhooks {
+        webhooks::Dispatcher::default()
+    } else {
+        webhooks::Dispatcher::disabled()
+    });
+    let limits = web::Data::new(config.limits);
+    let admins = web::Data::new(config.admin.clone());
+    let rate_limiter = web::Data::new(rate_limit::RateLimiter::new(config.rate_limits.clone()));
+    let tls = match config.tls {
+        Some(ref tls) => Some(tls::Tls::load(tls).map_err(std::io::Error::other)?),
+        None => None,
+    };
+    if let Some(ref tls) = tls {
+        tls::spawn_reloader(tls, tls::RELOAD_INTERVAL);
+    }
+
+    let addresses = config
+        .server
+        .addresses()
+        .expect("validated by Config::load");
+    let socket_mode = config
+        .server
+        .socket_mode()
+        .expect("validated by Config::load");
     let mut server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone()))
-            .wrap(Logger::default())
-            .service(index)
-            .service(show)
-            .service(create)
-            .service(delete)
+            .app_data(path_mappings.clone())
+            .app_data(limits.clone())
+            .app_data(web::Data::new(features))
+            .app_data(broadcaster.clone())
+            .app_data(dispatcher.clone())
+            .app_data(keys.clone())
+            .app_data(admins.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(web::PayloadConfig::new(limits.max_body_size))
+            .app_data(web::FormConfig::default().limit(limits.max_body_size))
+            .app_data(web::JsonConfig::default().limit(limits.max_body_size))
+            .wrap(from_fn(rate_limit::limit))
+            .wrap(Condition::new(features.metrics, from_fn(metrics::track)))
+            .wrap(from_fn(telemetry::request_id_header))
+            .wrap(TracingLogger::default())
+            .configure(|cfg| configure_app(cfg, features))
     });
+    if let Some(workers) = config.server.workers {
+        server = server.workers(workers);
+    }
+    if let Some(ref tls) = tls {
+        server = server.on_connect(tls.on_connect());
+    }
 
-    server = if let Some(l) = listenfd.take_tcp_listener(0).unwrap() {
-        server.listen(l)?
-    } else {
-        server.bind("0.0.0.0:8088")?
-    };
+    let (listeners, _socket_files) = listen::open(&addresses, socket_mode)?;
+    for listener in listeners {
+        server = match listener {
+            listen::Listener::Tcp(l) => match tls {
+                Some(ref tls) => server.listen_rustls_0_23(l, tls.server_config())?,
+                None => server.listen(l)?,
+            },
+            listen::Listener::Unix(l) => server.listen_uds(l)?,
+        };
+    }
     server.run().await
 }
 
@@ -142,15 +1067,33 @@ mod tests {
         pool
     }
 
+    const TEST_ADMIN_TOKEN: &str = "test-admin-token";
+
     macro_rules! init_test_app {
         ($pool:expr) => {
+            init_test_app!($pool, PathMappings::default())
+        };
+        ($pool:expr, $path_mappings:expr) => {
+            init_test_app!($pool, $path_mappings, Keyring::default())
+        };
+        ($pool:expr, $path_mappings:expr, $keys:expr) => {
             test::init_service(
                 App::new()
                     .app_data(web::Data::new($pool.clone()))
-                    .service(index)
-                    .service(show)
-                    .service(create)
-                    .service(delete),
+                    .app_data(web::Data::new($path_mappings))
+                    .app_data(web::Data::new(Limits::default()))
+                    .app_data(web::Data::new(Features::default()))
+                    .app_data(web::Data::new(stream::Broadcaster::default()))
+                    .app_data(web::Data::new(webhooks::Dispatcher::default()))
+                    .app_data(web::Data::new($keys))
+                    .app_data(web::Data::new(audit::AdminConfig {
+                        tokens: vec![TEST_ADMIN_TOKEN.to_string()],
+                        clients: Vec::new(),
+                    }))
+                    .wrap(from_fn(metrics::track))
+                    .wrap(from_fn(telemetry::request_id_header))
+                    .wrap(TracingLogger::default())
+                    .configure(|cfg| configure_app(cfg, Features::default())),
             )
             .await
         };
@@ -176,11 +1119,13 @@ mod tests {
             hostname: format!("handler-test-{label}-{unique}"),
             working_directory: format!("pwd-{label}-{unique}"),
             command: format!("command-{label}-{unique}"),
+            ..Default::default()
         }
     }
 
     fn cleanup_history(pool: &DbPool, history: &NewHistory) -> Result<(), diesel::result::Error> {
         use crate::schema::histories::dsl::*;
+        use crate::schema::hosts;
 
         let mut conn = pool.get().expect("cannot get db connection from pool");
         diesel::delete(
@@ -190,6 +1135,8 @@ mod tests {
                 .filter(command.eq(&history.command)),
         )
         .execute(&mut conn)?;
+        diesel::delete(hosts::table.filter(hosts::name.eq(&history.hostname)))
+            .execute(&mut conn)?;
 
         Ok(())
     }
@@ -255,6 +1202,11 @@ mod tests {
             };
             if let Err(error) = diesel::delete(dsl::histories.filter(dsl::hostname.eq(&self.hostname)))
                 .execute(&mut conn)
+                    .and_then(|_| {
+                        use crate::schema::hosts;
+                        diesel::delete(hosts::table.filter(hosts::name.eq(&self.hostname)))
+                            .execute(&mut conn)
+                    })
             {
                 if std::thread::panicking() {
                     eprintln!("failed to cleanup hostname {}: {error}", self.hostname);
@@ -267,13 +1219,7 @@ mod tests {
 
     fn seed_history(pool: &DbPool, history: &NewHistory) -> History {
         let mut conn = pool.get().expect("cannot get db connection from pool");
-        actions::create_history(
-            &mut conn,
-            &history.hostname,
-            &history.working_directory,
-            &history.command,
-        )
-        .expect("failed to seed history");
+        actions::create_history(&mut conn, history).expect("failed to seed history");
 
         let query = SearchQuery {
             pwd: Some(history.working_directory.clone()),
@@ -417,24 +1363,337 @@ mod tests {
     }
 
     #[actix_rt::test]
-    async fn test_delete_removes_history() {
-        let pool = setup_pool();
-        let history = TestHistoryGuard::new(&pool, "delete");
+    async fn test_create_is_limited_per_hostname() {
+        use actix_web::http::header::RETRY_AFTER;
 
-        let seeded = seed_history(&pool, history.history());
+        let pool = setup_pool();
+        let history = test_history("rate-limit");
+        let other = NewHistory {
+            hostname: format!("{}-other", history.hostname),
+            ..history.clone()
+        };
+        let long = NewHistory {
+            hostname: format!("{}-long", history.hostname),
+            command: "x".repeat(65),
+            ..history.clone()
+        };
+        let _guards = [&history, &other, &long].map(|h| HostnameGuard::new(&pool, &h.hostname));
+
+        let limiter = rate_limit::RateLimiter::new(rate_limit::RateLimits {
+            read: None,
+            write: Some(rate_limit::Budget {
+                per_second: 0.01,
+                burst: 2,
+                by: rate_limit::KeyBy::Hostname,
+            }),
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(PathMappings::default()))
+                .app_data(web::Data::new(Limits {
+                    max_command_length: 64,
+                    ..Default::default()
+                }))
+                .app_data(web::Data::new(Features::default()))
+                .app_data(web::Data::new(webhooks::Dispatcher::disabled()))
+                .app_data(web::Data::new(Keyring::default()))
+                .app_data(web::Data::new(limiter))
+                .wrap(from_fn(rate_limit::limit))
+                .service(index)
+                .service(create),
+        )
+        .await;
+        let post = |h: &NewHistory| test::TestRequest::post().uri("/").set_form(h).to_request();
 
-        let app = init_test_app!(pool);
+        for _ in 0..2 {
+            let resp = test::call_service(&app, post(&history)).await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        }
+        let resp = test::call_service(&app, post(&history)).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "100");
 
-        let req = test::TestRequest::delete()
-            .uri(&format!("/{}", seeded.id))
+        // Other hosts, and reads, have budgets of their own.
+        let resp = test::call_service(&app, post(&other)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let req = test::TestRequest::get()
+            .uri(&format!("/?hostname={}", history.hostname))
             .to_request();
-        let resp = test::call_service(&app, req).await;
+        let results: Vec<History> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(results[0].run_count, 2);
 
-        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, post(&long)).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
 
-        let body: serde_json::Value = test::read_body_json(resp).await;
-        assert_eq!(body["count"], 1);
-        assert_eq!(body["message"], "Successfully deleted");
+    #[actix_rt::test]
+    async fn test_encrypted_history_is_found_by_blind_index() {
+        let pool = setup_pool();
+        let plain = test_history("e2e");
+        let _guard = HostnameGuard::new(&pool, &plain.hostname);
+        let key = e2e::Key::from_hex(&e2e::Key::generate()).unwrap();
+        let mut encrypted = plain.clone();
+        key.encrypt_history(&mut encrypted);
+
+        let app = init_test_app!(pool);
+        for _ in 0..2 {
+            let req = test::TestRequest::post()
+                .uri("/")
+                .set_form(&encrypted)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        }
+
+        let mut q = SearchQuery {
+            hostname: Some(plain.hostname.clone()),
+            pwd: Some(plain.working_directory.clone()),
+            command: Some("COMMAND-e2e".to_string()),
+            ..Default::default()
+        };
+        key.blind_query(&mut q);
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/?hostname={}&blind_index={}",
+                plain.hostname,
+                q.blind_index.as_deref().unwrap()
+            ))
+            .to_request();
+        let mut results: Vec<History> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(results.len(), 1);
+        let history = &mut results[0];
+        assert!(history.encrypted);
+        assert_eq!(history.run_count, 2);
+        assert_eq!(history.command, encrypted.command);
+        assert_eq!(
+            history.normalized_directory,
+            Some(encrypted.working_directory.clone())
+        );
+
+        key.decrypt_history(history).unwrap();
+        assert_eq!(history.command, plain.command);
+        assert_eq!(
+            history.working_directory,
+            Some(plain.working_directory.clone())
+        );
+
+        q.blind_index = Some(key.index("/elsewhere", "")[0].clone());
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/?hostname={}&blind_index={}",
+                plain.hostname,
+                q.blind_index.as_deref().unwrap()
+            ))
+            .to_request();
+        let results: Vec<History> = test::call_and_read_body_json(&app, req).await;
+        assert!(results.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_history_encrypted_at_rest() {
+        use crate::schema::histories::dsl;
+
+        let pool = setup_pool();
+        let plain = test_history("at-rest");
+        let _guard = HostnameGuard::new(&pool, &plain.hostname);
+        let hex_keys = [e2e::Key::generate(), e2e::Key::generate()];
+        let config = |current: &str| at_rest::EncryptionConfig {
+            current: current.to_string(),
+            working_directory: true,
+            keys: ["k1", "k2"]
+                .into_iter()
+                .map(str::to_string)
+                .zip(hex_keys.clone())
+                .collect(),
+        };
+        let stored = || {
+            let mut conn = pool.get().expect("cannot get db connection from pool");
+            dsl::histories
+                .filter(dsl::hostname.eq(&plain.hostname))
+                .order(dsl::id)
+                .load::<History>(&mut conn)
+                .expect("failed to load stored histories")
+        };
+
+        let app = init_test_app!(
+            pool,
+            PathMappings::default(),
+            Keyring::load(&config("k1")).unwrap()
+        );
+        for _ in 0..2 {
+            let req = test::TestRequest::post()
+                .uri("/")
+                .set_form(&plain)
+                .to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body["command"], plain.command);
+        }
+
+        let [ref history] = stored()[..] else {
+            panic!("the command should be recorded once");
+        };
+        assert_eq!(history.run_count, 2);
+        assert_eq!(history.key_id.as_deref(), Some("k1"));
+        assert!(history.command.starts_with(at_rest::PREFIX));
+        assert!(history
+            .working_directory
+            .as_ref()
+            .unwrap()
+            .starts_with(at_rest::PREFIX));
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/?hostname={}&pwd={}&command=COMMAND-AT-REST",
+                plain.hostname, plain.working_directory
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(parse_total_count(resp.headers()), 1);
+        let results: Vec<History> = test::read_body_json(resp).await;
+        assert_eq!(results[0].command, plain.command);
+        assert_eq!(
+            results[0].working_directory,
+            Some(plain.working_directory.clone())
+        );
+
+        // The database only hands over the histories whose search index
+        // matches, or that have none yet.
+        assert!(history.search_index.as_ref().is_some_and(|i| !i.is_empty()));
+        let search = |command: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/?hostname={}&command={command}", plain.hostname))
+                .to_request()
+        };
+        let mut conn = pool.get().expect("cannot get db connection from pool");
+        for (search_index, total) in [(Some(Vec::<String>::new()), 0), (None, 1)] {
+            diesel::update(dsl::histories.filter(dsl::id.eq(history.id)))
+                .set(dsl::search_index.eq(search_index))
+                .execute(&mut conn)
+                .unwrap();
+            let resp = test::call_service(&app, search("at-rest")).await;
+            assert_eq!(parse_total_count(resp.headers()), total);
+        }
+        diesel::update(dsl::histories.filter(dsl::id.eq(history.id)))
+            .set(dsl::search_index.eq(&history.search_index))
+            .execute(&mut conn)
+            .unwrap();
+        drop(conn);
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/stats?hostname={}&command=at-rest",
+                plain.hostname
+            ))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["total"]["count"], 2);
+        assert_eq!(body["top_commands"][0]["key"], plain.command);
+        assert_eq!(body["per_directory"][0]["key"], plain.working_directory);
+        for (bound, count) in [("since", 2), ("until", 0)] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/stats?hostname={}&command=at-rest&{bound}=2000-01-01T00:00:00Z",
+                    plain.hostname
+                ))
+                .to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body["total"]["count"], count);
+            assert_eq!(body["top_commands"].as_array().unwrap().len(), count / 2);
+        }
+
+        // Once the key is rotated, the command is recorded anew until
+        // `rotate-keys` merges the two.
+        let keys = Keyring::load(&config("k2")).unwrap();
+        let app = init_test_app!(
+            pool,
+            PathMappings::default(),
+            Keyring::load(&config("k2")).unwrap()
+        );
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_form(&plain)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(stored().len(), 2);
+
+        // Merging the live history into its twin in the trash keeps it
+        // live, with the context of either.
+        let mut conn = pool.get().expect("cannot get db connection from pool");
+        diesel::update(dsl::histories.filter(dsl::id.eq(history.id)))
+            .set(dsl::git_branch.eq("main"))
+            .execute(&mut conn)
+            .unwrap();
+        diesel::update(dsl::histories.filter(dsl::id.eq(stored()[1].id)))
+            .set(dsl::deleted_at.eq(diesel::dsl::now))
+            .execute(&mut conn)
+            .unwrap();
+        let batch = actions::rotate_keys(&mut conn, &keys, history.id - 1, 2, false).unwrap();
+        assert_eq!(batch.last_id, Some(history.id));
+        assert_eq!((batch.reencrypted, batch.merged), (0, 1));
+
+        let [ref rotated] = stored()[..] else {
+            panic!("the histories should be merged");
+        };
+        assert_eq!(rotated.run_count, 3);
+        assert_eq!(rotated.created_at, history.created_at);
+        assert!(rotated.updated_at > history.updated_at);
+        assert!(rotated.deleted_at.is_none());
+        assert_eq!(rotated.git_branch.as_deref(), Some("main"));
+        let mut rotated = rotated.clone();
+        actions::decrypt(&keys, [&mut rotated]).unwrap();
+        assert_eq!(rotated.command, plain.command);
+
+        // A run replayed late still replaces the directories sealed with the
+        // previous data key.
+        let late = NewHistory {
+            executed_at: Some(chrono::Utc::now() - chrono::Duration::hours(1)),
+            ..plain.clone()
+        };
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_form(&late)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CREATED
+        );
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/?hostname={}&pwd={}",
+                plain.hostname, plain.working_directory
+            ))
+            .to_request();
+        let results: Vec<History> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(results[0].run_count, 4);
+        assert_eq!(
+            results[0].normalized_directory,
+            Some(plain.working_directory.clone())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_delete_removes_history() {
+        let pool = setup_pool();
+        let history = TestHistoryGuard::new(&pool, "delete");
+
+        let seeded = seed_history(&pool, history.history());
+
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}", seeded.id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["count"], 1);
+        assert_eq!(body["message"], "Successfully deleted");
 
         let mut conn = pool.get().expect("cannot get db connection from pool");
         let found = actions::find(&mut conn, seeded.id).expect("failed to look up deleted history");
@@ -442,29 +1701,1163 @@ mod tests {
     }
 
     #[actix_rt::test]
-    async fn test_index_filters_by_hostname() {
+    async fn test_bulk_records_all_histories() {
         let pool = setup_pool();
-        let target = TestHistoryGuard::new(&pool, "hostname-target");
-        let other = TestHistoryGuard::new(&pool, "hostname-other");
+        let first = TestHistoryGuard::new(&pool, "bulk-first");
+        let second = TestHistoryGuard::new(&pool, "bulk-second");
 
-        seed_history(&pool, target.history());
-        seed_history(&pool, other.history());
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/bulk")
+            .set_json([first.history(), second.history(), first.history()])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let created: Vec<NewHistory> = test::read_body_json(resp).await;
+        let commands: Vec<&str> = created.iter().map(|h| h.command.as_str()).collect();
+        assert_eq!(
+            commands,
+            [
+                &first.history().command,
+                &second.history().command,
+                &first.history().command
+            ]
+        );
+
+        let mut conn = pool.get().expect("cannot get db connection from pool");
+        let found = seed_history(&pool, first.history());
+        let found = actions::find(&mut conn, found.id).unwrap().unwrap();
+        assert_eq!(found.run_count, 3, "two runs in bulk and one seeded");
+
+        let too_many = vec![first.history().clone(); NewHistory::MAX_BULK + 1];
+        let req = test::TestRequest::post()
+            .uri("/api/v1/bulk")
+            .set_json(too_many)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_replayed_runs_are_counted_once() {
+        use crate::schema::idempotency_keys;
+
+        let pool = setup_pool();
+        let history = TestHistoryGuard::new(&pool, "replayed");
+        let executed_at = chrono::Utc::now() - chrono::Duration::hours(1);
+        let event_id = format!("event-{}", history.history().hostname);
+        let replayed = NewHistory {
+            executed_at: Some(executed_at),
+            client_event_id: Some(event_id.clone()),
+            ..history.history().clone()
+        };
+
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/")
+            .set_form(&replayed)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let req = test::TestRequest::post()
+            .uri("/api/v1/")
+            .set_form(&replayed)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.headers().get("idempotent-replayed").unwrap(), "true");
+        let req = test::TestRequest::post()
+            .uri("/api/v1/bulk")
+            .set_json([&replayed])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let mut conn = pool.get().expect("cannot get db connection from pool");
+        let query = SearchQuery {
+            pwd: Some(history.history().working_directory.clone()),
+            ..Default::default()
+        };
+        let (found, _) = actions::search(&mut conn, &query).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].run_count, 1);
+        assert_eq!(found[0].created_at.timestamp(), executed_at.timestamp());
+        assert_eq!(found[0].updated_at.timestamp(), executed_at.timestamp());
+
+        // An earlier run replayed late doesn't move the history back.
+        let earlier = NewHistory {
+            executed_at: Some(executed_at - chrono::Duration::hours(1)),
+            client_event_id: Some(format!("{event_id}-earlier")),
+            ..replayed.clone()
+        };
+        let req = test::TestRequest::post()
+            .uri("/api/v1/")
+            .set_form(&earlier)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let (found, _) = actions::search(&mut conn, &query).unwrap();
+        assert_eq!(found[0].run_count, 2);
+        assert_eq!(found[0].updated_at.timestamp(), executed_at.timestamp());
+
+        let invalid = NewHistory {
+            client_event_id: Some("x".repeat(NewHistory::MAX_IDEMPOTENCY_KEY + 1)),
+            ..replayed
+        };
+        let req = test::TestRequest::post()
+            .uri("/api/v1/")
+            .set_form(&invalid)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        diesel::delete(
+            idempotency_keys::table.filter(idempotency_keys::id.like(format!("{event_id}%"))),
+        )
+        .execute(&mut conn)
+        .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_bulk_keeps_the_chronology_of_runs() {
+        let pool = setup_pool();
+        let history = TestHistoryGuard::new(&pool, "backfilled");
+        let now = chrono::Utc::now();
+        let run = |hours_ago| NewHistory {
+            executed_at: Some(now - chrono::Duration::hours(hours_ago)),
+            exit_status: Some(hours_ago as i32),
+            git_branch: Some(format!("branch-{hours_ago}")),
+            ..history.history().clone()
+        };
+
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/bulk")
+            .set_json([run(2), run(5), run(3)])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let mut conn = pool.get().expect("cannot get db connection from pool");
+        let query = SearchQuery {
+            pwd: Some(history.history().working_directory.clone()),
+            ..Default::default()
+        };
+        let (found, _) = actions::search(&mut conn, &query).unwrap();
+        assert_eq!(found[0].run_count, 3);
+        assert_eq!(found[0].failure_count, 3);
+        assert_eq!(
+            found[0].created_at.timestamp(),
+            run(5).executed_at.unwrap().timestamp()
+        );
+        assert_eq!(
+            found[0].updated_at.timestamp(),
+            run(2).executed_at.unwrap().timestamp()
+        );
+        // The latest run's outcome and context stand.
+        assert_eq!(found[0].exit_status, Some(2));
+        assert_eq!(found[0].git_branch.as_deref(), Some("branch-2"));
+
+        // Clocks a little ahead are tolerated, but not beyond the limit.
+        let ahead = |seconds| NewHistory {
+            executed_at: Some(chrono::Utc::now() + chrono::Duration::seconds(seconds)),
+            ..history.history().clone()
+        };
+        let req = test::TestRequest::post()
+            .uri("/api/v1/")
+            .set_form(ahead(60))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let (found, _) = actions::search(&mut conn, &query).unwrap();
+        assert!(found[0].updated_at <= chrono::Utc::now());
+        let req = test::TestRequest::post()
+            .uri("/api/v1/bulk")
+            .set_json([ahead(0), ahead(3600)])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let (found, _) = actions::search(&mut conn, &query).unwrap();
+        assert_eq!(
+            found[0].run_count, 4,
+            "none of the runs in bulk are recorded"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_idempotency_key_replays_the_response() {
+        use crate::schema::idempotency_keys;
+
+        let pool = setup_pool();
+        let history = TestHistoryGuard::new(&pool, "idempotent");
+        let key = format!("key-{}", history.history().hostname);
+
+        let app = init_test_app!(pool);
+
+        let post = || {
+            test::TestRequest::post()
+                .uri("/api/v1/")
+                .insert_header((IDEMPOTENCY_KEY, key.as_str()))
+                .set_form(history.history())
+                .to_request()
+        };
+        let resp = test::call_service(&app, post()).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert!(resp.headers().get("idempotent-replayed").is_none());
+        let first: serde_json::Value = test::read_body_json(resp).await;
+        let resp = test::call_service(&app, post()).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.headers().get("idempotent-replayed").unwrap(), "true");
+        let replayed: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(replayed, first);
+
+        let mut conn = pool.get().expect("cannot get db connection from pool");
+        let kept = idempotency_keys::table
+            .filter(idempotency_keys::id.eq(&key))
+            .select(idempotency_keys::response)
+            .first::<Option<serde_json::Value>>(&mut conn)
+            .unwrap()
+            .expect("the response should be kept");
+        assert_eq!(kept["hostname"], first["hostname"]);
+        assert!(kept.get("command").is_none(), "{kept}");
+        let found = seed_history(&pool, history.history());
+        assert_eq!(found.run_count, 2, "one run posted twice and one seeded");
+
+        // Keys are forgotten after the window.
+        diesel::update(idempotency_keys::table.filter(idempotency_keys::id.eq(&key)))
+            .set(idempotency_keys::created_at.eq(chrono::Utc::now() - chrono::Duration::days(8)))
+            .execute(&mut conn)
+            .unwrap();
+        let resp = test::call_service(&app, post()).await;
+        assert!(resp.headers().get("idempotent-replayed").is_none());
+        let found = seed_history(&pool, history.history());
+        assert_eq!(found.run_count, 4);
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/")
+            .insert_header((IDEMPOTENCY_KEY, ""))
+            .set_form(history.history())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // The key can't be reused for another run of the host, but other
+        // hosts have keys of their own.
+        let other_command = NewHistory {
+            command: format!("{}-other", history.history().command),
+            ..history.history().clone()
+        };
+        let req = test::TestRequest::post()
+            .uri("/api/v1/")
+            .insert_header((IDEMPOTENCY_KEY, key.as_str()))
+            .set_form(&other_command)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        // Nor for another run of the same command.
+        let other_run = NewHistory {
+            exit_status: Some(history.history().exit_status.unwrap_or(0) + 1),
+            ..history.history().clone()
+        };
+        let req = test::TestRequest::post()
+            .uri("/api/v1/")
+            .insert_header((IDEMPOTENCY_KEY, key.as_str()))
+            .set_form(&other_run)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let other_host = TestHistoryGuard::new(&pool, "idempotent-other");
+        let req = test::TestRequest::post()
+            .uri("/api/v1/")
+            .insert_header((IDEMPOTENCY_KEY, key.as_str()))
+            .set_form(other_host.history())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert!(resp.headers().get("idempotent-replayed").is_none());
+
+        diesel::delete(idempotency_keys::table.filter(idempotency_keys::id.eq(&key)))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_api_is_versioned_with_deprecated_aliases() {
+        let pool = setup_pool();
+        let history = TestHistoryGuard::new(&pool, "versioned");
+        let seeded = seed_history(&pool, history.history());
 
         let app = init_test_app!(pool);
 
         let req = test::TestRequest::get()
-            .uri(&format!("/?hostname={}", target.history().hostname))
+            .uri(&format!("/api/v1/{}", seeded.id))
             .to_request();
         let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get("deprecation").is_none());
+        let body: History = test::read_body_json(resp).await;
+        assert_eq!(body.id, seeded.id);
 
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}?x=1", seeded.id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().contains_key("deprecation"));
+        assert_eq!(
+            resp.headers().get("link").unwrap(),
+            &format!("</api/v1/{}?x=1>; rel=\"successor-version\"", seeded.id)
+        );
 
-        let total = parse_total_count(resp.headers());
-        assert_eq!(total, 1);
+        // Routes outside the API are neither versioned nor deprecated.
+        let resp =
+            test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get("deprecation").is_none());
+    }
 
-        let body: Vec<History> = test::read_body_json(resp).await;
-        assert_eq!(body.len(), 1);
-        assert_eq!(body[0].hostname, target.history().hostname);
+    #[actix_rt::test]
+    async fn test_openapi_document() {
+        let pool = setup_pool();
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/openapi.json")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let doc: serde_json::Value = test::read_body_json(resp).await;
+        assert!(doc["openapi"].as_str().unwrap().starts_with("3."));
+        assert_eq!(doc["servers"][0]["url"], "/api/v1");
+        for path in [
+            "/",
+            "/{id}",
+            "/{id}/restore",
+            "/stats",
+            "/hosts",
+            "/webhooks",
+            "/audit",
+        ] {
+            assert!(doc["paths"][path].is_object(), "{path} is missing");
+        }
+        assert!(doc["paths"]["/"]["post"]["requestBody"]["content"]
+            ["application/x-www-form-urlencoded"]
+            .is_object());
+        let history = &doc["components"]["schemas"]["History"]["properties"];
+        assert!(history["command"].is_object());
+        assert!(
+            history["key_id"].is_null(),
+            "fields kept from clients are left out"
+        );
+
+        let req = test::TestRequest::get().uri("/api/v1/docs").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_delete_is_audited() {
+        use crate::schema::audit_events::dsl::*;
+
+        let pool = setup_pool();
+        let history = TestHistoryGuard::new(&pool, "audit");
+        let seeded = seed_history(&pool, history.history());
+
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}?purge=true", seeded.id))
+            .insert_header(("Authorization", "Bearer audited-token"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let uri = format!("/audit?history_id={}", seeded.id);
+        for authorization in [None, Some("Bearer audited-token")] {
+            let mut req = test::TestRequest::get().uri(&uri);
+            if let Some(authorization) = authorization {
+                req = req.insert_header(("Authorization", authorization));
+            }
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("Authorization", format!("Bearer {TEST_ADMIN_TOKEN}")))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(parse_total_count(resp.headers()), 1);
+        let events: Vec<AuditEvent> = test::read_body_json(resp).await;
+        assert_eq!(events[0].action, "history.purge");
+        assert!(events[0].actor.starts_with("token:"));
+        assert!(!events[0].actor.contains("audited-token"));
+        assert_eq!(events[0].history_ids, vec![seeded.id]);
+        assert_eq!(
+            events[0].filters,
+            Some(serde_json::json!({ "purge": true }))
+        );
+
+        // Nothing may rewrite history, not even the database owner.
+        let mut conn = pool.get().expect("cannot get db connection from pool");
+        let event = audit_events.filter(id.eq(events[0].id));
+        assert!(diesel::update(event)
+            .set(actor.eq("someone-else"))
+            .execute(&mut conn)
+            .is_err());
+        assert!(diesel::delete(event).execute(&mut conn).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_bulk_and_host_updates_are_audited() {
+        let pool = setup_pool();
+        let history = TestHistoryGuard::new(&pool, "audit-bulk");
+        let hostname = history.history().hostname.clone();
+        let app = init_test_app!(pool);
+
+        let audited = |action: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/audit?action={action}&limit=1000"))
+                .insert_header(("Authorization", format!("Bearer {TEST_ADMIN_TOKEN}")))
+                .to_request()
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/bulk")
+            .set_json([history.history(), history.history()])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let seeded = seed_history(&pool, history.history());
+
+        let resp = test::call_service(&app, audited("history.bulk_create")).await;
+        let events: Vec<AuditEvent> = test::read_body_json(resp).await;
+        let event = events
+            .iter()
+            .find(|e| e.history_ids == [seeded.id])
+            .expect("bulk should be audited");
+        assert_eq!(event.details["count"], 2);
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/hosts/{hostname}"))
+            .set_json(HostUpdate {
+                aliases: vec![format!("{hostname}.example.com")],
+                tags: Vec::new(),
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = test::call_service(&app, audited("host.update")).await;
+        let events: Vec<AuditEvent> = test::read_body_json(resp).await;
+        let event = events
+            .iter()
+            .find(|e| e.details["name"] == hostname)
+            .expect("host update should be audited");
+        assert!(event.history_ids.is_empty());
+        assert_eq!(event.details["aliases_before"], serde_json::json!([]));
+        assert_eq!(
+            event.details["aliases_after"],
+            serde_json::json!([format!("{hostname}.example.com")])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_index_filters_by_hostname() {
+        let pool = setup_pool();
+        let target = TestHistoryGuard::new(&pool, "hostname-target");
+        let other = TestHistoryGuard::new(&pool, "hostname-other");
+
+        seed_history(&pool, target.history());
+        seed_history(&pool, other.history());
+
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/?hostname={}", target.history().hostname))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let total = parse_total_count(resp.headers());
+        assert_eq!(total, 1);
+
+        let body: Vec<History> = test::read_body_json(resp).await;
+        assert_eq!(body.len(), 1);
+        assert_eq!(body[0].hostname, target.history().hostname);
+    }
+
+    #[actix_rt::test]
+    async fn test_create_accepts_git_context_and_filters_by_repo() {
+        let pool = setup_pool();
+        let history = TestHistoryGuard::new(&pool, "git-context");
+        let mut form = test_history("git-context");
+        form.hostname = history.history().hostname.clone();
+        form.working_directory = history.history().working_directory.clone();
+        form.command = history.history().command.clone();
+        form.git_remote = Some(format!("git@example.com:team/{}.git", form.hostname));
+        form.git_branch = Some("feature/git-context".to_string());
+
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_form(&form)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/?repo=https://example.com/team/{}&branch=feature/git-context",
+                form.hostname
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(parse_total_count(resp.headers()), 1);
+
+        let body: Vec<History> = test::read_body_json(resp).await;
+        assert_eq!(body[0].command, form.command);
+        assert_eq!(
+            body[0].git_remote,
+            Some(format!("example.com/team/{}", form.hostname))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_pwd_filter_matches_normalized_directory_across_hosts() {
+        let pool = setup_pool();
+        let history = test_history("normalize");
+        let linux = HostnameGuard::new(&pool, format!("{}-linux", history.hostname));
+        let mac = HostnameGuard::new(&pool, format!("{}-mac", history.hostname));
+        let project = format!("/src/{}", history.working_directory);
+        let path_mappings: PathMappings =
+            toml::from_str("canonicalize_home = true").expect("valid path mappings");
+
+        let app = init_test_app!(pool, path_mappings);
+
+        for (guard, home) in [(&linux, "/home/alice"), (&mac, "/Users/alice")] {
+            let form = NewHistory {
+                hostname: guard.hostname.clone(),
+                working_directory: format!("{home}{project}"),
+                command: history.command.clone(),
+                ..Default::default()
+            };
+            let req = test::TestRequest::post()
+                .uri("/")
+                .set_form(&form)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/?pwd=/Users/bob{project}"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(parse_total_count(resp.headers()), 2);
+
+        let body: Vec<History> = test::read_body_json(resp).await;
+        assert!(body
+            .iter()
+            .all(|h| h.normalized_directory == Some(format!("~{project}"))));
+        assert!(body
+            .iter()
+            .any(|h| h.working_directory == Some(format!("/Users/alice{project}"))));
+    }
+
+    #[actix_rt::test]
+    async fn test_create_resolves_host_aliases() {
+        let pool = setup_pool();
+        let history = TestHistoryGuard::new(&pool, "host-alias");
+        let alias = format!("{}.example.com", history.history().hostname);
+        let _alias_guard = HostnameGuard::new(&pool, &alias);
+
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/hosts/{}", history.history().hostname))
+            .set_json(HostUpdate {
+                aliases: vec![alias.clone()],
+                tags: vec!["laptop".to_string()],
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let form = NewHistory {
+            hostname: alias.clone(),
+            working_directory: history.history().working_directory.clone(),
+            command: history.history().command.clone(),
+            ..Default::default()
+        };
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_form(&form)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body: NewHistory = test::read_body_json(resp).await;
+        assert_eq!(body.hostname, history.history().hostname);
+
+        let req = test::TestRequest::get().uri("/hosts").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Vec<HostSummary> = test::read_body_json(resp).await;
+        let host = body
+            .iter()
+            .find(|h| h.name == history.history().hostname)
+            .expect("host should be listed");
+        assert_eq!(host.aliases, vec![alias]);
+        assert_eq!(host.tags, vec!["laptop"]);
+        assert_eq!(host.command_count, 1);
+        assert!(host.last_seen_at.is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_stats_aggregates_filtered_histories() {
+        let pool = setup_pool();
+        let history = test_history("stats");
+        let _guard = HostnameGuard::new(&pool, &history.hostname);
+
+        let app = init_test_app!(pool);
+
+        let days_ago = chrono::Utc::now() - chrono::Duration::days(2);
+        for (command, exit_status, executed_at) in [
+            ("git status", 1, Some(days_ago)),
+            ("git status", 0, None),
+            ("git push", 0, None),
+            ("ls -la", 2, None),
+        ] {
+            let form = NewHistory {
+                hostname: history.hostname.clone(),
+                working_directory: history.working_directory.clone(),
+                command: command.to_string(),
+                exit_status: Some(exit_status),
+                executed_at,
+                ..Default::default()
+            };
+            let req = test::TestRequest::post()
+                .uri("/")
+                .set_form(&form)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/stats?hostname={}&top=1", history.hostname))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: Stats = test::read_body_json(resp).await;
+        assert_eq!(body.total.count, 4);
+        assert_eq!(body.total.failures, 2);
+        assert_eq!(body.total.failure_rate, 0.5);
+
+        assert_eq!(body.top_commands.len(), 1);
+        assert_eq!(body.top_commands[0].key, "git status");
+        assert_eq!(body.top_commands[0].count, 2);
+        assert_eq!(body.top_commands[0].failures, 1);
+
+        assert_eq!(body.top_programs.len(), 1);
+        assert_eq!(body.top_programs[0].key, "git");
+        assert_eq!(body.top_programs[0].count, 3);
+
+        assert_eq!(body.per_host.len(), 1);
+        assert_eq!(body.per_host[0].key, history.hostname);
+        assert_eq!(body.per_directory[0].key, history.working_directory);
+
+        // Each run counts on the day it ran, not its history's latest.
+        assert_eq!(body.per_day.len(), 2);
+        assert_eq!(body.per_day[0].day, days_ago.date_naive());
+        assert_eq!(body.per_day[0].count, 1);
+        assert_eq!(body.per_day[1].count, 3);
+        assert_eq!(body.heatmap.iter().map(|c| c.count).sum::<i64>(), 4);
+
+        // Only runs within `since` and `until` count, whenever their
+        // histories last ran.
+        let yesterday = (chrono::Utc::now() - chrono::Duration::days(1)).to_rfc3339();
+        let window_stats = |bound: &str| {
+            test::TestRequest::get()
+                .uri(&format!(
+                    "/stats?hostname={}&{bound}={}",
+                    history.hostname,
+                    yesterday.replace('+', "%2B")
+                ))
+                .to_request()
+        };
+        let body: Stats = test::call_and_read_body_json(&app, window_stats("since")).await;
+        assert_eq!(body.total.count, 3);
+        assert_eq!(body.total.failures, 1);
+        assert_eq!(body.top_commands[0].count, 1);
+        assert_eq!(body.per_day.len(), 1);
+        assert_eq!(body.heatmap.iter().map(|c| c.count).sum::<i64>(), 3);
+
+        let body: Stats = test::call_and_read_body_json(&app, window_stats("until")).await;
+        assert_eq!(body.total.count, 1);
+        assert_eq!(body.total.failures, 1);
+        assert_eq!(body.top_commands.len(), 1);
+        assert_eq!(body.top_commands[0].key, "git status");
+        assert_eq!(body.per_day.len(), 1);
+        assert_eq!(body.per_day[0].day, days_ago.date_naive());
+    }
+
+    #[actix_rt::test]
+    async fn test_integration_script_is_prefilled() {
+        let pool = setup_pool();
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::get()
+            .uri("/integration/bash?token=abc")
+            .insert_header(("host", "clh.example.com:8088"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("export CLH_SERVER='http://clh.example.com:8088'"));
+        assert!(body.contains("export CLH_TOKEN='abc'"));
+        assert!(body.contains("bind -x"));
+
+        let req = test::TestRequest::get()
+            .uri("/integration/powershell")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_delete_and_restore_history() {
+        let pool = setup_pool();
+        let history = TestHistoryGuard::new(&pool, "restore");
+        let seeded = seed_history(&pool, history.history());
+
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}", seeded.id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/?pwd={}&deleted=true",
+                history.history().working_directory
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(parse_total_count(resp.headers()), 1);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/restore", seeded.id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Option<History> = test::read_body_json(resp).await;
+        assert_eq!(body.expect("history should be restored").id, seeded.id);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}", seeded.id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let body: Option<History> = test::read_body_json(resp).await;
+        assert!(body.is_some());
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}?purge=true", seeded.id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["count"], 1);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/restore", seeded.id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let body: Option<History> = test::read_body_json(resp).await;
+        assert!(body.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_stream_endpoints() {
+        let pool = setup_pool();
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::get()
+            .uri("/stream?hostname=some-host")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/stream/ws")
+            .insert_header(("upgrade", "websocket"))
+            .insert_header(("connection", "upgrade"))
+            .insert_header(("sec-websocket-version", "13"))
+            .insert_header(("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    struct WebhookGuard {
+        pool: DbPool,
+        id: i32,
+    }
+
+    impl Drop for WebhookGuard {
+        fn drop(&mut self) {
+            let mut conn = self.pool.get().expect("cannot get db connection from pool");
+            if let Err(error) = actions::delete_webhook(&mut conn, self.id) {
+                eprintln!("failed to cleanup webhook {}: {error}", self.id);
+            }
+        }
+    }
+
+    async fn wait_for(
+        rx: &std::sync::mpsc::Receiver<test_support::Received>,
+    ) -> test_support::Received {
+        // Deliveries run on this test's runtime, so it must not block.
+        for _ in 0..500 {
+            if let Ok(received) = rx.try_recv() {
+                return received;
+            }
+            actix_rt::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("webhook was not delivered");
+    }
+
+    #[actix_rt::test]
+    async fn test_webhooks() {
+        let pool = setup_pool();
+        let history = TestHistoryGuard::new(&pool, "webhook");
+        let app = init_test_app!(pool);
+        let (url, rx) = test_support::spawn_receiver(vec![200, 200]);
+
+        for body in [
+            serde_json::json!({ "url": "ftp://example.com" }),
+            serde_json::json!({ "url": url, "events": ["updated"] }),
+            serde_json::json!({ "url": url, "command_pattern": "(unclosed" }),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/webhooks")
+                .set_json(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let pattern = format!("^{}$", history.history().command);
+        let req = test::TestRequest::post()
+            .uri("/webhooks")
+            .set_json(serde_json::json!({
+                "url": url,
+                "secret": "s3cret",
+                "events": ["created"],
+                "command_pattern": pattern,
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let created: serde_json::Value = test::read_body_json(resp).await;
+        assert!(created.get("secret").is_none());
+        let id = created["id"].as_i64().unwrap() as i32;
+        let _webhook = WebhookGuard {
+            pool: pool.clone(),
+            id,
+        };
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/webhooks/{id}"))
+            .set_json(serde_json::json!({
+                "url": url,
+                "secret": "s3cret",
+                "command_pattern": pattern,
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let updated: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(updated["events"], serde_json::json!(["created", "deleted"]));
+
+        let req = test::TestRequest::get().uri("/webhooks").to_request();
+        let resp = test::call_service(&app, req).await;
+        let listed: Vec<serde_json::Value> = test::read_body_json(resp).await;
+        assert!(listed.iter().any(|w| w["id"] == id));
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_form(history.history())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let received = wait_for(&rx).await;
+        assert_eq!(
+            received.header(webhooks::SIGNATURE_HEADER),
+            Some(webhooks::sign("s3cret", &received.body).as_str())
+        );
+        let payload: serde_json::Value = serde_json::from_str(&received.body).unwrap();
+        assert_eq!(payload["event"], "created");
+        assert_eq!(payload["history"]["command"], history.history().command);
+
+        let seeded = seed_history(&pool, history.history());
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}", seeded.id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let received = wait_for(&rx).await;
+        let payload: serde_json::Value = serde_json::from_str(&received.body).unwrap();
+        assert_eq!(payload["event"], "deleted");
+        assert_eq!(payload["purged"], false);
+        assert_eq!(payload["history"]["id"], seeded.id);
+
+        // Leaving the secret out removes it.
+        let req = test::TestRequest::put()
+            .uri(&format!("/webhooks/{id}"))
+            .set_json(serde_json::json!({ "url": url }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut conn = pool.get().expect("cannot get db connection from pool");
+        let stored = actions::find_webhook(&mut conn, id).unwrap().unwrap();
+        assert_eq!(stored.secret, None);
+        assert_eq!(stored.command_pattern, None);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/webhooks/{id}"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/webhooks/{id}"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_metrics() {
+        let pool = setup_pool();
+        let history = TestHistoryGuard::new(&pool, "metrics");
+        let seeded = seed_history(&pool, history.history());
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}", seeded.id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+
+        assert!(
+            body.contains(r#"clh_http_requests_total{handler="show",method="GET",status="200"}"#)
+        );
+        assert!(body.contains(r#"clh_http_request_duration_seconds_count{handler="show"}"#));
+        assert!(body.contains(r#"clh_db_query_duration_seconds_count{function="find"}"#));
+        assert!(body.contains("clh_db_pool_connections "));
+        assert!(body.contains("clh_db_pool_idle_connections "));
+        assert!(body.contains("clh_db_pool_active_connections "));
+        let histories: i64 = body
+            .lines()
+            .find_map(|line| line.strip_prefix("clh_histories "))
+            .expect("history count should be exported")
+            .parse()
+            .unwrap();
+        assert!(histories >= 1);
+
+        // Histories are only counted again once the count is stale.
+        let counts = |body: &str| {
+            body.lines()
+                .find(|line| {
+                    line.starts_with(
+                        r#"clh_db_query_duration_seconds_count{function="count_histories"}"#,
+                    )
+                })
+                .map(str::to_string)
+        };
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let resp = test::call_service(&app, req).await;
+        let again = test::read_body(resp).await;
+        let again = std::str::from_utf8(&again).unwrap();
+        assert!(counts(body).is_some());
+        assert_eq!(counts(again), counts(body));
+    }
+
+    #[actix_rt::test]
+    async fn test_health_endpoints() {
+        let pool = setup_pool();
+        let app = init_test_app!(pool);
+
+        for uri in ["/healthz", "/readyz"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let health: Health = test::read_body_json(resp).await;
+            assert_eq!(health.status, "ok");
+            assert_eq!(health.version, env!("CARGO_PKG_VERSION"));
+            assert!(health.pending_migrations.is_empty());
+            assert!(health.error.is_none());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_readyz_without_database() {
+        let manager = ConnectionManager::<PgConnection>::new("postgres://clh@127.0.0.1:1/clh");
+        let pool = r2d2::Pool::builder()
+            .min_idle(Some(0))
+            .build_unchecked(manager);
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let health: Health = test::read_body_json(resp).await;
+        assert_eq!(health.status, "unavailable");
+        assert!(health.error.is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_request_id_header() {
+        let pool = setup_pool();
+        let app = init_test_app!(pool);
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let req = test::TestRequest::get().uri("/healthz").to_request();
+            let resp = test::call_service(&app, req).await;
+            let id = resp
+                .headers()
+                .get(telemetry::REQUEST_ID_HEADER)
+                .expect("request id should be echoed")
+                .to_str()
+                .unwrap()
+                .to_string();
+            ids.push(id);
+        }
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[actix_rt::test]
+    async fn test_client_certificate_sets_hostname() {
+        let pool = setup_pool();
+        let history = test_history("mtls");
+        let pki = tls::tests::TestPki::new("mtls");
+        let tls = tls::Tls::load(&pki.config(Some(tls::ClientAuth::Required))).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "https://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        let app_pool = pool.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(app_pool.clone()))
+                .app_data(web::Data::new(PathMappings::default()))
+                .app_data(web::Data::new(Limits::default()))
+                .app_data(web::Data::new(webhooks::Dispatcher::disabled()))
+                .app_data(web::Data::new(Keyring::default()))
+                .service(create)
+        })
+        .workers(1)
+        .on_connect(tls.on_connect())
+        .listen_rustls_0_23(listener, tls.server_config())
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_rt::spawn(server);
+
+        let ca = std::fs::read(pki.path("ca.pem")).unwrap();
+        let ca = reqwest::Certificate::from_pem(&ca).unwrap();
+        let (cert, key) = pki.issue("client", &history.hostname);
+        let identity = [std::fs::read(cert).unwrap(), std::fs::read(key).unwrap()].concat();
+        let client = reqwest::Client::builder()
+            .add_root_certificate(ca.clone())
+            .identity(reqwest::Identity::from_pem(&identity).unwrap())
+            .build()
+            .unwrap();
+        let form = [
+            ("hostname", "spoofed"),
+            ("working_directory", &history.working_directory),
+            ("command", &history.command),
+        ];
+
+        let resp = client.post(&url).form(&form).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::CREATED);
+        let created: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(created["hostname"], history.hostname);
+
+        let anonymous = reqwest::Client::builder()
+            .add_root_certificate(ca)
+            .build()
+            .unwrap();
+        assert!(anonymous.post(&url).form(&form).send().await.is_err());
+
+        handle.stop(false).await;
+        cleanup_history(&pool, &history).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_web_ui_is_served() {
+        let pool = setup_pool();
+        let app = init_test_app!(pool);
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("accept", "text/html,application/xhtml+xml"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(resp.headers().get("location").unwrap(), "/ui/");
+
+        let req = test::TestRequest::get().uri("/ui/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("app.js"));
+
+        for (asset, content_type) in [
+            ("app.js", "text/javascript; charset=utf-8"),
+            ("style.css", "text/css; charset=utf-8"),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/ui/{asset}"))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get("content-type").unwrap(), content_type);
+        }
+
+        let req = test::TestRequest::get().uri("/ui/missing.js").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
     }
 
     #[actix_rt::test]
@@ -483,7 +2876,13 @@ mod tests {
         {
             let mut conn = pool.get().expect("cannot get db connection from pool");
             for i in 0..3 {
-                actions::create_history(&mut conn, &hostname, &pwd, &format!("cmd-{i}"))
+                let history = NewHistory {
+                    hostname: hostname.clone(),
+                    working_directory: pwd.clone(),
+                    command: format!("cmd-{i}"),
+                    ..Default::default()
+                };
+                actions::create_history(&mut conn, &history)
                     .expect("failed to seed pagination history");
             }
         }
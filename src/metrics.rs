@@ -0,0 +1,199 @@
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{error, get, web, HttpResponse, Result};
+use diesel::r2d2::{self, HandleEvent};
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramTimer,
+    HistogramVec, IntCounterVec, IntGauge, TextEncoder,
+};
+
+use crate::telemetry;
+use crate::DbPool;
+use crate::{actions, health};
+
+/// How long the history count is exported before counting them again, as
+/// counting takes a scan of the table.
+const COUNT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// When histories were last counted.
+static COUNTED_AT: Mutex<Option<Instant>> = Mutex::new(None);
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "clh_http_requests_total",
+        "HTTP requests handled, by handler, method and status",
+        &["handler", "method", "status"]
+    )
+    .unwrap()
+});
+
+static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "clh_http_request_duration_seconds",
+        "Time taken to handle HTTP requests, by handler",
+        &["handler"]
+    )
+    .unwrap()
+});
+
+static RATE_LIMITED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "clh_rate_limited_total",
+        "Requests refused for exceeding their rate limit, by scope",
+        &["scope"]
+    )
+    .unwrap()
+});
+
+static DB_QUERY_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "clh_db_query_duration_seconds",
+        "Time spent in database queries, by actions function",
+        &["function"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]
+    )
+    .unwrap()
+});
+
+static POOL_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "clh_db_pool_connections",
+        "Connections currently open in the database pool"
+    )
+    .unwrap()
+});
+
+static POOL_IDLE_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "clh_db_pool_idle_connections",
+        "Open connections in the database pool that are not checked out"
+    )
+    .unwrap()
+});
+
+static POOL_ACTIVE_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "clh_db_pool_active_connections",
+        "Connections in the database pool that are checked out"
+    )
+    .unwrap()
+});
+
+static POOL_WAIT_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "clh_db_pool_wait_seconds",
+        "Time spent waiting to check a connection out of the pool, by outcome",
+        &["outcome"],
+        vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0]
+    )
+    .unwrap()
+});
+
+static HISTORIES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "clh_histories",
+        "Histories recorded, not counting those in the trash, as of the last minute"
+    )
+    .unwrap()
+});
+
+/// Starts timing a query made by the `actions` function `function`. The
+/// duration is recorded when the returned timer is dropped.
+pub fn query_timer(function: &str) -> HistogramTimer {
+    DB_QUERY_DURATION
+        .with_label_values(&[function])
+        .start_timer()
+}
+
+/// Counts a request refused by `rate_limit::limit`.
+pub fn rate_limited(scope: &str) {
+    RATE_LIMITED.with_label_values(&[scope]).inc();
+}
+
+/// Records how long connection checkouts wait, when registered as the
+/// pool's event handler.
+#[derive(Debug)]
+pub struct PoolEvents;
+
+impl HandleEvent for PoolEvents {
+    fn handle_checkout(&self, event: r2d2::event::CheckoutEvent) {
+        POOL_WAIT_DURATION
+            .with_label_values(&["checkout"])
+            .observe(event.duration().as_secs_f64());
+    }
+
+    fn handle_timeout(&self, event: r2d2::event::TimeoutEvent) {
+        POOL_WAIT_DURATION
+            .with_label_values(&["timeout"])
+            .observe(event.timeout().as_secs_f64());
+    }
+}
+
+/// Middleware counting and timing requests by the name of the handler
+/// that served them.
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    let method = req.method().to_string();
+    let timer = std::time::Instant::now();
+    let res = next.call(req).await?;
+
+    let handler = res
+        .request()
+        .match_name()
+        .unwrap_or("unmatched")
+        .to_string();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[&handler])
+        .observe(timer.elapsed().as_secs_f64());
+    HTTP_REQUESTS
+        .with_label_values(&[&handler, &method, res.status().as_str()])
+        .inc();
+
+    Ok(res)
+}
+
+/// Serves every metric in the Prometheus text format.
+#[get("/metrics")]
+async fn metrics(pool: web::Data<DbPool>) -> Result<HttpResponse> {
+    let state = pool.state();
+    POOL_CONNECTIONS.set(state.connections.into());
+    POOL_IDLE_CONNECTIONS.set(state.idle_connections.into());
+    POOL_ACTIVE_CONNECTIONS.set((state.connections - state.idle_connections).into());
+
+    let due = COUNTED_AT
+        .lock()
+        .unwrap()
+        .is_none_or(|at| at.elapsed() >= COUNT_INTERVAL);
+    if due {
+        let mut conn = pool
+            .get_timeout(health::CHECKOUT_TIMEOUT)
+            .map_err(error::ErrorServiceUnavailable)?;
+        let total = telemetry::block(move || actions::count_histories(&mut conn))
+            .await
+            .map_err(error::ErrorInternalServerError)?
+            .map_err(error::ErrorInternalServerError)?;
+        HISTORIES.set(total);
+        *COUNTED_AT.lock().unwrap() = Some(Instant::now());
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut body)
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(body))
+}
+
+/// Registers the metrics endpoint.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(metrics);
+}
@@ -0,0 +1,206 @@
+use serde::Deserialize;
+
+/// Rewrites working directories into a host independent form, so that the
+/// same project checked out at different locations compares equal.
+///
+/// Loaded from the TOML file named by `path_mappings` in the server
+/// configuration, or `CLH_PATH_MAPPINGS`:
+///
+/// ```toml
+/// canonicalize_home = true  # the default
+/// home_prefixes = ["/home/*", "/Users/*", "/root"]
+///
+/// [[mappings]]
+/// hostname = "wsl"
+/// prefix = "/mnt/c/Users/alice"
+/// replacement = "~"
+/// ```
+///
+/// Directories are normalized as histories are recorded. Those recorded
+/// before the mappings changed, or before servers normalized them at all,
+/// are normalized again by `clh-server renormalize`.
+///
+/// Without a file, or with an empty one, home directories are replaced
+/// with `~` and no other mapping applies.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathMappings {
+    #[serde(default = "default_canonicalize_home")]
+    pub canonicalize_home: bool,
+    #[serde(default = "default_home_prefixes")]
+    pub home_prefixes: Vec<String>,
+    #[serde(default)]
+    pub mappings: Vec<PathMapping>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathMapping {
+    /// Only apply this mapping to paths reported by this host.
+    pub hostname: Option<String>,
+    pub prefix: String,
+    pub replacement: String,
+}
+
+impl Default for PathMappings {
+    fn default() -> Self {
+        Self {
+            canonicalize_home: default_canonicalize_home(),
+            home_prefixes: default_home_prefixes(),
+            mappings: Vec::new(),
+        }
+    }
+}
+
+fn default_canonicalize_home() -> bool {
+    true
+}
+
+fn default_home_prefixes() -> Vec<String> {
+    vec![
+        "/home/*".to_string(),
+        "/Users/*".to_string(),
+        "/root".to_string(),
+    ]
+}
+
+impl PathMappings {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| format!("cannot read {path}: {e}"))?;
+        toml::from_str(&content).map_err(|e| format!("cannot parse {path}: {e}"))
+    }
+
+    /// Normalizes `path` as reported by `hostname`.
+    ///
+    /// The longest matching prefix mapping wins, host specific mappings
+    /// taking precedence over global ones. Home directories are then
+    /// replaced with `~` when `canonicalize_home` is enabled.
+    pub fn normalize(&self, hostname: Option<&str>, path: &str) -> String {
+        let mapping = self
+            .mappings
+            .iter()
+            .filter(|m| m.hostname.is_none() || m.hostname.as_deref() == hostname)
+            .filter_map(|m| strip_path_prefix(path, &m.prefix).map(|rest| (m, rest)))
+            .max_by_key(|(m, _)| (m.hostname.is_some(), m.prefix.len()));
+
+        if let Some((m, rest)) = mapping {
+            return join(&m.replacement, rest);
+        }
+
+        if self.canonicalize_home {
+            for pattern in &self.home_prefixes {
+                if let Some(rest) = strip_pattern_prefix(path, pattern) {
+                    return join("~", rest);
+                }
+            }
+        }
+
+        path.to_string()
+    }
+}
+
+/// Strips `prefix` from `path` on a path component boundary.
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    let rest = path.strip_prefix(prefix)?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Like [`strip_path_prefix`], but a `*` component in `pattern` matches any
+/// single component of `path`.
+fn strip_pattern_prefix<'a>(path: &'a str, pattern: &str) -> Option<&'a str> {
+    let mut rest = path;
+    for component in pattern.trim_end_matches('/').split('/').skip(1) {
+        rest = rest.strip_prefix('/')?;
+        let end = rest.find('/').unwrap_or(rest.len());
+        if component != "*" && component != &rest[..end] {
+            return None;
+        }
+        rest = &rest[end..];
+    }
+    Some(rest)
+}
+
+fn join(base: &str, rest: &str) -> String {
+    let base = base.trim_end_matches('/');
+    if rest.is_empty() {
+        base.to_string()
+    } else if base.is_empty() {
+        rest.to_string()
+    } else {
+        format!("{base}{rest}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mappings() -> PathMappings {
+        toml::from_str(
+            r#"
+            canonicalize_home = true
+
+            [[mappings]]
+            prefix = "/srv/projects"
+            replacement = "~/src"
+
+            [[mappings]]
+            hostname = "wsl"
+            prefix = "/mnt/c/Users/alice"
+            replacement = "~"
+
+            [[mappings]]
+            hostname = "wsl"
+            prefix = "/srv"
+            replacement = "/data"
+            "#,
+        )
+        .expect("valid mappings")
+    }
+
+    #[test]
+    fn test_normalize_canonicalizes_home() {
+        let m = mappings();
+        assert_eq!(m.normalize(Some("linux"), "/home/alice/src/x"), "~/src/x");
+        assert_eq!(m.normalize(Some("mac"), "/Users/alice/src/x"), "~/src/x");
+        assert_eq!(m.normalize(None, "/root"), "~");
+        assert_eq!(m.normalize(None, "/homestead/x"), "/homestead/x");
+    }
+
+    #[test]
+    fn test_normalize_applies_prefix_mappings() {
+        let m = mappings();
+        assert_eq!(m.normalize(None, "/srv/projects/x"), "~/src/x");
+        assert_eq!(m.normalize(None, "/srv/projects2/x"), "/srv/projects2/x");
+        assert_eq!(
+            m.normalize(Some("wsl"), "/mnt/c/Users/alice/src/x"),
+            "~/src/x"
+        );
+        assert_eq!(
+            m.normalize(Some("mac"), "/mnt/c/Users/alice/src/x"),
+            "/mnt/c/Users/alice/src/x"
+        );
+        // Host specific mappings win over longer global ones.
+        assert_eq!(
+            m.normalize(Some("wsl"), "/srv/projects/x"),
+            "/data/projects/x"
+        );
+    }
+
+    #[test]
+    fn test_default_mappings_canonicalize_home() {
+        let m = PathMappings::default();
+        assert_eq!(m.normalize(Some("linux"), "/home/alice/src/x"), "~/src/x");
+        assert_eq!(m.normalize(Some("linux"), "/srv/x"), "/srv/x");
+
+        let empty: PathMappings = toml::from_str("").unwrap();
+        assert_eq!(empty.canonicalize_home, m.canonicalize_home);
+        assert_eq!(empty.home_prefixes, m.home_prefixes);
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::web::{self, Bytes};
+use actix_web::{HttpMessage, HttpResponse, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::metrics;
+use crate::tls::ClientIdentity;
+
+/// Endpoints probes and scrapers poll, never limited.
+const EXEMPT_PATHS: [&str; 3] = ["/healthz", "/readyz", "/metrics"];
+const FORM: &str = "application/x-www-form-urlencoded";
+/// Buckets kept, those of the clients seen least recently being dropped
+/// to make room for new ones.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Request budgets, read from the `[rate_limits]` section of the
+/// configuration. Reads are `GET` and `HEAD` requests, writes everything
+/// else. Each scope is unlimited unless configured:
+///
+/// ```toml
+/// [rate_limits.write]
+/// per_second = 5.0
+/// burst = 50
+/// by = "hostname"
+///
+/// [rate_limits.read]
+/// per_second = 20.0
+/// burst = 200
+/// by = "token"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read: Option<Budget>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write: Option<Budget>,
+}
+
+/// A token bucket: `burst` requests at once, refilled at `per_second`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Budget {
+    pub per_second: f64,
+    pub burst: u32,
+    /// What clients are told apart by.
+    pub by: KeyBy,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self {
+            per_second: 10.0,
+            burst: 100,
+            by: KeyBy::Ip,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyBy {
+    /// The bearer token, or the address of clients without one.
+    Token,
+    /// The address of the peer. Requests over Unix domain sockets share a
+    /// budget.
+    #[default]
+    Ip,
+    /// The hostname of the client certificate, or else the `hostname`
+    /// recorded or searched along with the address of the peer, or else
+    /// the address alone.
+    Hostname,
+}
+
+impl RateLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        for (scope, budget) in [("read", &self.read), ("write", &self.write)] {
+            let Some(budget) = budget else { continue };
+            if !(budget.per_second > 0.0 && budget.per_second.is_finite()) {
+                return Err(format!("rate_limits.{scope}.per_second must be positive"));
+            }
+            if budget.burst == 0 {
+                return Err(format!("rate_limits.{scope}.burst must be at least 1"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Scope {
+    Read,
+    Write,
+}
+
+impl Scope {
+    fn of(method: &Method) -> Self {
+        if method == Method::GET || method == Method::HEAD {
+            Self::Read
+        } else {
+            Self::Write
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, budget: &Budget, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.per_second).min(budget.burst.into());
+        self.updated = now;
+    }
+}
+
+type BucketKey = (Scope, String);
+
+/// Token buckets by key, and their keys by when they were last updated.
+#[derive(Debug, Default)]
+struct Buckets {
+    by_key: HashMap<BucketKey, Bucket>,
+    by_update: BTreeSet<(Instant, BucketKey)>,
+}
+
+/// Token buckets of every client, shared by all workers.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::default(),
+        }
+    }
+
+    fn budget(&self, scope: Scope) -> Option<&Budget> {
+        match scope {
+            Scope::Read => self.limits.read.as_ref(),
+            Scope::Write => self.limits.write.as_ref(),
+        }
+    }
+
+    /// Takes a token from the bucket of `key`, or returns how long until
+    /// one is available.
+    fn take(&self, scope: Scope, key: String, now: Instant) -> Result<(), Duration> {
+        let Some(budget) = self.budget(scope) else {
+            return Ok(());
+        };
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+        let key = (scope, key);
+        let mut bucket = match buckets.by_key.remove(&key) {
+            Some(bucket) => {
+                buckets.by_update.remove(&(bucket.updated, key.clone()));
+                bucket
+            }
+            None => {
+                while buckets.by_key.len() >= MAX_BUCKETS {
+                    let Some((_, oldest)) = buckets.by_update.pop_first() else {
+                        break;
+                    };
+                    buckets.by_key.remove(&oldest);
+                }
+                Bucket {
+                    tokens: budget.burst.into(),
+                    updated: now,
+                }
+            }
+        };
+        bucket.refill(budget, now);
+        let taken = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / budget.per_second,
+            ))
+        };
+        buckets.by_update.insert((bucket.updated, key.clone()));
+        buckets.by_key.insert(key, bucket);
+        taken
+    }
+}
+
+#[derive(Deserialize)]
+struct HostnameField {
+    hostname: Option<String>,
+}
+
+/// Returns the key `req` is limited by. Finding the hostname of a form
+/// reads the body, which is then put back for the handler.
+async fn key(req: &mut ServiceRequest, by: KeyBy) -> Result<String> {
+    let peer = req
+        .peer_addr()
+        .map_or_else(|| "local".to_string(), |addr| addr.ip().to_string());
+    match by {
+        KeyBy::Ip => Ok(peer),
+        KeyBy::Token => {
+            let token = req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "));
+            Ok(token.map_or(peer, |token| format!("token:{token}")))
+        }
+        KeyBy::Hostname => {
+            if let Some(identity) = req.conn_data::<ClientIdentity>() {
+                return Ok(format!("host:{}", identity.hostname));
+            }
+            let mut hostname = web::Query::<HostnameField>::from_query(req.query_string())
+                .ok()
+                .and_then(|q| q.into_inner().hostname);
+            if hostname.is_none() && req.content_type() == FORM {
+                let body = req.extract::<Bytes>().await?;
+                // Forms are encoded like query strings.
+                hostname = std::str::from_utf8(&body)
+                    .ok()
+                    .and_then(|form| web::Query::<HostnameField>::from_query(form).ok())
+                    .and_then(|q| q.into_inner().hostname);
+                req.set_payload(Payload::from(body));
+            }
+            // Without a certificate the hostname is whatever the client
+            // says, so that it only splits the budget of its address.
+            Ok(hostname.map_or_else(
+                || peer.clone(),
+                |hostname| format!("host:{hostname}@{peer}"),
+            ))
+        }
+    }
+}
+
+/// Middleware answering 429 with `Retry-After` to clients that have used
+/// up their budget, see `RateLimits`.
+pub async fn limit(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>> {
+    let limiter = req.app_data::<web::Data<RateLimiter>>().cloned();
+    let scope = Scope::of(req.method());
+    let budget = limiter.as_ref().and_then(|l| l.budget(scope).cloned());
+    let (Some(limiter), Some(budget)) = (limiter, budget) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    if EXEMPT_PATHS.contains(&req.path()) {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    let key = key(&mut req, budget.by).await?;
+    match limiter.take(scope, key, Instant::now()) {
+        Ok(()) => Ok(next.call(req).await?.map_into_left_body()),
+        Err(wait) => {
+            metrics::rate_limited(scope.as_str());
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            let res = HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                .body("rate limit exceeded");
+            Ok(req.into_response(res).map_into_right_body())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            read: None,
+            write: Some(Budget {
+                per_second: 2.0,
+                burst: 3,
+                by: KeyBy::Ip,
+            }),
+        })
+    }
+
+    #[test]
+    fn test_take() {
+        let limiter = limiter();
+        let now = Instant::now();
+        let take = |key: &str, at: Duration| limiter.take(Scope::Write, key.to_string(), now + at);
+
+        for _ in 0..3 {
+            assert_eq!(take("a", Duration::ZERO), Ok(()));
+        }
+        assert_eq!(take("a", Duration::ZERO), Err(Duration::from_millis(500)));
+        assert_eq!(take("b", Duration::ZERO), Ok(()));
+        assert_eq!(take("a", Duration::from_millis(500)), Ok(()));
+        assert!(take("a", Duration::from_millis(600)).is_err());
+
+        // Refills stop at the burst.
+        for _ in 0..3 {
+            assert_eq!(take("a", Duration::from_secs(60)), Ok(()));
+        }
+        assert!(take("a", Duration::from_secs(60)).is_err());
+
+        for _ in 0..10 {
+            assert_eq!(limiter.take(Scope::Read, "a".to_string(), now), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_least_recently_used_buckets_are_dropped() {
+        let limiter = limiter();
+        let now = Instant::now();
+        let take = |key: &str, at: u64| {
+            limiter.take(
+                Scope::Write,
+                key.to_string(),
+                now + Duration::from_millis(at),
+            )
+        };
+
+        for i in 0..MAX_BUCKETS - 1 {
+            assert_eq!(take(&format!("client-{i}"), 0), Ok(()));
+        }
+        for _ in 0..3 {
+            assert_eq!(take("spent", 1), Ok(()));
+        }
+        assert!(take("spent", 1).is_err());
+
+        // New clients take the place of those seen least recently, not of
+        // spent ones.
+        for i in 0..3 {
+            assert_eq!(take(&format!("new-{i}"), 2), Ok(()));
+            assert!(take("spent", 2).is_err());
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.by_key.len(), MAX_BUCKETS);
+        assert_eq!(buckets.by_update.len(), MAX_BUCKETS);
+        let dropped = (0..MAX_BUCKETS - 1)
+            .filter(|i| {
+                !buckets
+                    .by_key
+                    .contains_key(&(Scope::Write, format!("client-{i}")))
+            })
+            .count();
+        assert_eq!(dropped, 3);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(limiter().limits.validate().is_ok());
+        for budget in [
+            Budget {
+                per_second: 0.0,
+                ..Default::default()
+            },
+            Budget {
+                burst: 0,
+                ..Default::default()
+            },
+        ] {
+            let limits = RateLimits {
+                read: Some(budget),
+                write: None,
+            };
+            assert!(limits.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Runs the client recorded while the server could not be reached.
+//!
+//! They are appended to a local file, one JSON object per line, and sent
+//! again the next time the client records a run or on `client replay`,
+//! oldest first. Each run keeps the time it was executed at and the event
+//! id it was generated with, so that the server records it as run back
+//! then, and only once even if an earlier attempt did get through.
+//!
+//! Runs are spooled before they are encrypted end to end, so the file is
+//! only readable by its owner, like a shell history file. Clients lock it
+//! while they read or write it, as shells record runs concurrently.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::models::NewHistory;
+
+pub struct Spool {
+    path: PathBuf,
+}
+
+impl Spool {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// `$XDG_STATE_HOME/clh/spool.jsonl`, or `~/.local/state/clh/spool.jsonl`.
+    pub fn default_path() -> Option<PathBuf> {
+        let state = match std::env::var_os("XDG_STATE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+        };
+        Some(state.join("clh").join("spool.jsonl"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn open(&self) -> io::Result<File> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&self.path)
+    }
+
+    /// Whether there may be runs to replay, without taking the lock.
+    pub fn is_empty(&self) -> bool {
+        std::fs::metadata(&self.path).map_or(true, |m| m.len() == 0)
+    }
+
+    /// Appends `new_history` to the runs to replay.
+    pub fn push(&self, new_history: &NewHistory) -> io::Result<()> {
+        let mut line = serde_json::to_string(new_history)?;
+        line.push('\n');
+
+        let mut file = self.open()?;
+        file.lock()?;
+        file.write_all(line.as_bytes())
+    }
+
+    /// Sends the spooled runs with `send`, oldest first, returning how many
+    /// were sent. Stops at the first one `send` fails to send, keeping it
+    /// and those after it for the next replay.
+    ///
+    /// Unless `wait`, returns `Ok(0)` right away if another client is
+    /// replaying them already.
+    pub fn replay<E: From<io::Error>>(
+        &self,
+        wait: bool,
+        mut send: impl FnMut(&NewHistory) -> Result<(), E>,
+    ) -> Result<usize, E> {
+        let mut file = self.open()?;
+        if wait {
+            file.lock()?;
+        } else {
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => return Ok(0),
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+        }
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let lines = contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .collect::<Vec<_>>();
+
+        let mut done = 0;
+        let mut sent = 0;
+        let mut result = Ok(());
+        for line in &lines {
+            match serde_json::from_str::<NewHistory>(line) {
+                Ok(new_history) => {
+                    if let Err(e) = send(&new_history) {
+                        result = Err(e);
+                        break;
+                    }
+                    sent += 1;
+                }
+                Err(e) => eprintln!("dropping unreadable run from {}: {e}", self.path.display()),
+            }
+            done += 1;
+        }
+
+        if done > 0 {
+            let rest = lines[done..]
+                .iter()
+                .map(|line| format!("{line}\n"))
+                .collect::<String>();
+            file.set_len(0)?;
+            file.write_all(rest.as_bytes())?;
+        }
+        result.map(|()| sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spool(label: &str) -> Spool {
+        let unique = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("clh-spool-{label}-{unique}"));
+        Spool::new(dir.join("spool.jsonl"))
+    }
+
+    fn run(command: &str) -> NewHistory {
+        NewHistory {
+            hostname: "host".to_string(),
+            working_directory: "/tmp".to_string(),
+            command: command.to_string(),
+            client_event_id: Some(format!("event-{command}")),
+            executed_at: Some(chrono::Utc::now()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_replay_keeps_what_was_not_sent() {
+        let spool = spool("replay");
+        assert!(spool.is_empty());
+        for command in ["first", "second", "third"] {
+            spool.push(&run(command)).unwrap();
+        }
+        assert!(!spool.is_empty());
+
+        let mut sent = Vec::new();
+        let result = spool.replay(true, |h| {
+            if h.command == "second" {
+                return Err(io::Error::other("unreachable"));
+            }
+            sent.push(h.clone());
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].client_event_id.as_deref(), Some("event-first"));
+        assert!(sent[0].executed_at.is_some());
+
+        spool.push(&run("fourth")).unwrap();
+        let mut commands = Vec::new();
+        let count = spool
+            .replay(false, |h| {
+                commands.push(h.command.clone());
+                Ok::<_, io::Error>(())
+            })
+            .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(commands, ["second", "third", "fourth"]);
+        assert!(spool.is_empty());
+
+        std::fs::remove_dir_all(spool.path().parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use actix_web::{get, web, HttpRequest, HttpResponse, Result};
+use diesel::pg::PgConnection;
+use diesel::{Connection, RunQueryDsl};
+use futures::Stream;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::actions;
+use crate::at_rest::Keyring;
+use crate::models::{History, StreamQuery};
+use crate::paths::PathMappings;
+
+/// Channel the `histories_notify_*` triggers notify with the id of every
+/// history recorded or restored.
+const CHANNEL: &str = "history_changes";
+/// libpq has no blocking wait for notifications that diesel exposes, so the
+/// listener polls for them.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long an idle event stream waits before sending a comment, so that
+/// proxies don't time it out.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// Histories kept for slow subscribers, which skip ahead when they fall
+/// further behind.
+const CAPACITY: usize = 256;
+
+/// Fans out changed histories to every connected stream.
+#[derive(Clone)]
+pub struct Broadcaster {
+    sender: broadcast::Sender<Arc<History>>,
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+}
+
+impl Broadcaster {
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<History>> {
+        self.sender.subscribe()
+    }
+}
+
+/// Starts a thread that listens for history changes on its own connection,
+/// so that changes made through other server instances are streamed too.
+/// The thread exits once every clone of `broadcaster` has been dropped.
+pub fn spawn_listener(
+    database_url: String,
+    keys: Arc<Keyring>,
+    broadcaster: &Broadcaster,
+) -> thread::JoinHandle<()> {
+    let sender = broadcaster.sender.downgrade();
+    thread::Builder::new()
+        .name("clh-listener".to_string())
+        .spawn(move || {
+            while sender.strong_count() > 0 {
+                if let Err(e) = listen(&database_url, &keys, &sender) {
+                    tracing::error!(error = %e, "history listener failed, reconnecting");
+                    thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        })
+        .expect("cannot spawn history listener")
+}
+
+fn listen(
+    database_url: &str,
+    keys: &Keyring,
+    sender: &broadcast::WeakSender<Arc<History>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut conn = PgConnection::establish(database_url)?;
+    diesel::sql_query(format!("LISTEN {CHANNEL}")).execute(&mut conn)?;
+
+    loop {
+        let payloads = conn
+            .notifications_iter()
+            .map(|n| n.map(|n| n.payload))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let Some(sender) = sender.upgrade() else {
+            return Ok(());
+        };
+        if payloads.is_empty() {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        for payload in payloads {
+            let Ok(id) = payload.parse::<i32>() else {
+                tracing::warn!(payload, "ignoring malformed {CHANNEL} notification");
+                continue;
+            };
+            // Skip the lookup when nobody is listening.
+            if sender.receiver_count() == 0 {
+                continue;
+            }
+            if let Some(mut history) = actions::find(&mut conn, id)? {
+                actions::decrypt(keys, [&mut history])?;
+                let _ = sender.send(Arc::new(history));
+            }
+        }
+    }
+}
+
+/// Registers the streaming endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(events).service(websocket);
+}
+
+/// Rewrites the `pwd` filter the same way `create` rewrites working
+/// directories, so that it can be compared with `normalized_directory`.
+fn normalize(mut q: StreamQuery, path_mappings: &PathMappings) -> StreamQuery {
+    if let Some(ref pwd) = q.pwd {
+        let origin = q.origin.as_deref().or(q.hostname.as_deref());
+        q.pwd = Some(path_mappings.normalize(origin, pwd));
+    }
+    q
+}
+
+/// Streams recorded histories as Server-Sent Events, one `history` event
+/// each, with the history as JSON data.
+#[utoipa::path(
+    tag = "stream",
+    operation_id = "stream",
+    params(StreamQuery),
+    responses((status = 200, content_type = "text/event-stream", body = History))
+)]
+#[get("/stream")]
+async fn events(
+    broadcaster: web::Data<Broadcaster>,
+    path_mappings: web::Data<PathMappings>,
+    q: web::Query<StreamQuery>,
+) -> HttpResponse {
+    let q = normalize(q.into_inner(), &path_mappings);
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(event_stream(broadcaster.subscribe(), q))
+}
+
+fn event_stream(
+    receiver: broadcast::Receiver<Arc<History>>,
+    q: StreamQuery,
+) -> impl Stream<Item = Result<Bytes>> {
+    futures::stream::unfold((receiver, q), |(mut receiver, q)| async move {
+        loop {
+            match actix_rt::time::timeout(KEEP_ALIVE, receiver.recv()).await {
+                Err(_) => {
+                    return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), (receiver, q)));
+                }
+                Ok(Ok(history)) if q.matches(&history) => {
+                    let event = match serde_json::to_string(&*history) {
+                        Ok(data) => format!("id: {}\nevent: history\ndata: {data}\n\n", history.id),
+                        Err(e) => return Some((Err(e.into()), (receiver, q))),
+                    };
+                    return Some((Ok(Bytes::from(event)), (receiver, q)));
+                }
+                Ok(Ok(_)) | Ok(Err(RecvError::Lagged(_))) => continue,
+                Ok(Err(RecvError::Closed)) => return None,
+            }
+        }
+    })
+}
+
+/// Streams recorded histories over a WebSocket, one JSON text message each.
+#[utoipa::path(
+    tag = "stream",
+    operation_id = "stream_websocket",
+    params(StreamQuery),
+    responses((status = 101, description = "Switching to the WebSocket protocol"))
+)]
+#[get("/stream/ws")]
+async fn websocket(
+    req: HttpRequest,
+    body: web::Payload,
+    broadcaster: web::Data<Broadcaster>,
+    path_mappings: web::Data<PathMappings>,
+    q: web::Query<StreamQuery>,
+) -> Result<HttpResponse> {
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    let q = normalize(q.into_inner(), &path_mappings);
+    let mut receiver = broadcaster.subscribe();
+
+    actix_rt::spawn(async move {
+        loop {
+            tokio::select! {
+                message = messages.recv() => match message {
+                    Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(actix_ws::Message::Close(reason))) => {
+                        let _ = session.close(reason).await;
+                        return;
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => return,
+                },
+                history = receiver.recv() => match history {
+                    Ok(history) if q.matches(&history) => {
+                        let Ok(text) = serde_json::to_string(&*history) else {
+                            continue;
+                        };
+                        if session.text(text).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => {
+                        let _ = session.close(None).await;
+                        return;
+                    }
+                },
+            }
+        }
+    });
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::NewHistory;
+    use diesel::prelude::*;
+    use diesel_migrations::MigrationHarness;
+    use futures::StreamExt;
+
+    fn history(id: i32, h: &str, dir: &str) -> History {
+        let now = chrono::Utc::now();
+        History {
+            id,
+            hostname: h.to_string(),
+            working_directory: Some(dir.to_string()),
+            command: format!("command {id}"),
+            created_at: now,
+            updated_at: now,
+            git_remote: None,
+            git_branch: None,
+            git_root: None,
+            normalized_directory: Some(dir.to_string()),
+            exit_status: None,
+            run_count: 1,
+            failure_count: 0,
+            deleted_at: None,
+            encrypted: false,
+            blind_index: Vec::new(),
+            key_id: None,
+            data_key: None,
+            content_hash: None,
+            search_index: None,
+        }
+    }
+
+    #[test]
+    fn test_stream_query_matches() {
+        let history = history(1, "host-a", "/src/app");
+        assert!(StreamQuery::default().matches(&history));
+
+        let q = StreamQuery {
+            hostname: Some("host-a".to_string()),
+            pwd: Some("/src/app".to_string()),
+            origin: None,
+        };
+        assert!(q.matches(&history));
+
+        let q = StreamQuery {
+            hostname: Some("host-b".to_string()),
+            ..Default::default()
+        };
+        assert!(!q.matches(&history));
+
+        let q = StreamQuery {
+            pwd: Some("/src".to_string()),
+            ..Default::default()
+        };
+        assert!(!q.matches(&history));
+    }
+
+    #[actix_rt::test]
+    async fn test_event_stream_sends_matching_histories() {
+        let broadcaster = Broadcaster::default();
+        let q = StreamQuery {
+            hostname: Some("host-a".to_string()),
+            ..Default::default()
+        };
+        let stream = event_stream(broadcaster.subscribe(), q);
+
+        for h in [history(1, "host-a", "/a"), history(2, "host-b", "/b")] {
+            broadcaster.sender.send(Arc::new(h)).unwrap();
+        }
+        drop(broadcaster);
+
+        let sent: Vec<Bytes> = stream.map(|e| e.unwrap()).collect().await;
+        assert_eq!(sent.len(), 1);
+        let event = std::str::from_utf8(&sent[0]).unwrap();
+        assert!(event.starts_with("id: 1\nevent: history\ndata: {"));
+        assert!(event.ends_with("}\n\n"));
+        assert!(event.contains(r#""hostname":"host-a""#));
+    }
+
+    #[actix_rt::test]
+    async fn test_listener_broadcasts_committed_histories() {
+        use crate::schema::histories::dsl::*;
+
+        dotenv::dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let mut conn = PgConnection::establish(&database_url).unwrap();
+        conn.run_pending_migrations(crate::MIGRATIONS).unwrap();
+
+        let broadcaster = Broadcaster::default();
+        let mut receiver = broadcaster.subscribe();
+        let listener = spawn_listener(database_url, Arc::default(), &broadcaster);
+
+        let new_history = NewHistory {
+            hostname: "stream-listener-host".to_string(),
+            working_directory: "/stream/listener".to_string(),
+            command: "streamed command".to_string(),
+            ..Default::default()
+        };
+
+        // Notifications sent before the listener has run LISTEN are lost,
+        // so keep recording the command until one comes through.
+        let mut received = None;
+        for _ in 0..50 {
+            actions::create_history(&mut conn, &new_history).unwrap();
+            if let Ok(Ok(h)) =
+                actix_rt::time::timeout(Duration::from_millis(200), receiver.recv()).await
+            {
+                received = Some(h);
+                break;
+            }
+        }
+
+        diesel::delete(histories.filter(hostname.eq(&new_history.hostname)))
+            .execute(&mut conn)
+            .unwrap();
+
+        let received = received.expect("history should be broadcast");
+        assert_eq!(received.hostname, new_history.hostname);
+        assert_eq!(received.command, new_history.command);
+
+        drop(receiver);
+        drop(broadcaster);
+        listener.join().unwrap();
+    }
+}
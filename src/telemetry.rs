@@ -0,0 +1,305 @@
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::BlockingError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, Result};
+use clap::ValueEnum;
+use diesel::connection::{Instrumentation, InstrumentationEvent};
+use serde::{Deserialize, Serialize};
+use tracing::Span;
+use tracing_actix_web::RequestId;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Header the request id is echoed back in, so that clients can quote it.
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// How log lines are written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Keeps the exporters running; flushes them when dropped.
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(ref provider) = self.tracer_provider {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("clh-server: cannot flush traces: {e}");
+            }
+        }
+    }
+}
+
+/// Installs the global subscriber, filtered by `filter` (`info` when not
+/// given), and traces every Diesel query. With the `otlp` feature, spans
+/// are also exported when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+///
+/// Must be called outside of the async runtime, as the OTLP exporter uses
+/// a blocking HTTP client.
+pub fn init(format: LogFormat, filter: Option<&str>) -> Telemetry {
+    let filter = EnvFilter::new(filter.unwrap_or("info"));
+    let fmt = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let fmt = match format {
+        LogFormat::Text => fmt.boxed(),
+        LogFormat::Json => fmt.json().with_current_span(false).boxed(),
+    };
+
+    #[cfg(feature = "otlp")]
+    let tracer_provider = otlp::provider_from_env();
+    #[cfg(feature = "otlp")]
+    let otlp_layer = tracer_provider.as_ref().map(otlp::layer);
+    #[cfg(not(feature = "otlp"))]
+    let otlp_layer: Option<Box<dyn Layer<_> + Send + Sync>> = None;
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt)
+        .with(otlp_layer)
+        .init();
+
+    diesel::connection::set_default_instrumentation(|| Some(Box::new(QueryTracing::default())))
+        .expect("cannot install query tracing");
+
+    Telemetry {
+        #[cfg(feature = "otlp")]
+        tracer_provider,
+    }
+}
+
+/// Runs `f` on the blocking thread pool like `web::block`, inside the
+/// caller's span so that its logs and queries carry the request id.
+pub async fn block<F, R>(f: F) -> Result<R, BlockingError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let span = Span::current();
+    web::block(move || span.in_scope(f)).await
+}
+
+/// Middleware echoing the request id assigned by `TracingLogger`.
+pub async fn request_id_header(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    let request_id = req.extensions().get::<RequestId>().copied();
+    let mut res = next.call(req).await?;
+
+    if let Some(id) = request_id.and_then(|id| HeaderValue::from_str(&id.to_string()).ok()) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, id);
+    }
+    Ok(res)
+}
+
+/// Opens a `db.query` span for each query, as a child of whatever span is
+/// current on the thread running it, and logs failed queries.
+#[derive(Default)]
+pub struct QueryTracing {
+    query: Option<(Span, Instant)>,
+}
+
+impl Instrumentation for QueryTracing {
+    fn on_connection_event(&mut self, event: InstrumentationEvent<'_>) {
+        match event {
+            InstrumentationEvent::StartQuery { query, .. } => {
+                let span = tracing::info_span!(
+                    "db.query",
+                    db.system = "postgresql",
+                    db.statement = statement(&query.to_string()),
+                );
+                self.query = Some((span, Instant::now()));
+            }
+            InstrumentationEvent::FinishQuery { error, .. } => {
+                let Some((span, started)) = self.query.take() else {
+                    return;
+                };
+                let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
+                span.in_scope(|| match error {
+                    Some(e) => tracing::error!(elapsed_ms, error = %e, "query failed"),
+                    None => tracing::debug!(elapsed_ms, "query finished"),
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Strips the bind values Diesel appends to a query's SQL, which can hold
+/// recorded commands.
+fn statement(query: &str) -> &str {
+    query
+        .split_once(" -- binds: ")
+        .map_or(query, |(sql, _)| sql)
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use tracing_subscriber::Layer;
+
+    /// Builds a provider exporting to `OTEL_EXPORTER_OTLP_ENDPOINT` (or the
+    /// traces specific variable) over OTLP/HTTP, when one is set.
+    pub fn provider_from_env() -> Option<SdkTracerProvider> {
+        let configured = [
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+        ]
+        .iter()
+        .any(|name| std::env::var_os(name).is_some());
+        configured.then(|| provider(None))
+    }
+
+    /// Builds a provider exporting to `endpoint`, or to the endpoint
+    /// configured through the standard `OTEL_*` variables.
+    pub fn provider(endpoint: Option<&str>) -> SdkTracerProvider {
+        let mut exporter = opentelemetry_otlp::SpanExporter::builder().with_http();
+        if let Some(endpoint) = endpoint {
+            exporter = exporter.with_endpoint(endpoint);
+        }
+        let exporter = exporter.build().expect("cannot build OTLP exporter");
+
+        let service_name =
+            std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "clh-server".to_string());
+        SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(service_name).build())
+            .build()
+    }
+
+    pub fn layer<S>(provider: &SdkTracerProvider) -> Box<dyn Layer<S> + Send + Sync>
+    where
+        S: tracing::Subscriber
+            + for<'span> tracing_subscriber::registry::LookupSpan<'span>
+            + Send
+            + Sync,
+    {
+        let tracer = provider.tracer("clh-server");
+        tracing_opentelemetry::layer().with_tracer(tracer).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::prelude::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Collects everything the subscriber writes.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_statement_strips_binds() {
+        assert_eq!(
+            statement(
+                r#"SELECT "id" FROM "histories" WHERE "command" = $1 -- binds: ["rm -rf /"]"#
+            ),
+            r#"SELECT "id" FROM "histories" WHERE "command" = $1"#
+        );
+        assert_eq!(statement("SELECT 1"), "SELECT 1");
+    }
+
+    #[test]
+    fn test_query_tracing() {
+        dotenv::dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let mut conn = PgConnection::establish(&database_url).unwrap();
+        conn.set_instrumentation(QueryTracing::default());
+
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _request = tracing::info_span!("request", request_id = "abc123").entered();
+            diesel::sql_query("select 1").execute(&mut conn).unwrap();
+            assert!(diesel::sql_query("select * from no_such_table")
+                .execute(&mut conn)
+                .is_err());
+        });
+
+        let lines: Vec<serde_json::Value> = buffer
+            .contents()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let finished = lines
+            .iter()
+            .find(|l| l["fields"]["message"] == "query finished")
+            .expect("finished query should be logged at debug");
+        assert_eq!(finished["span"]["db.statement"], "select 1");
+        assert_eq!(finished["spans"][0]["request_id"], "abc123");
+
+        let failed = lines
+            .iter()
+            .find(|l| l["fields"]["message"] == "query failed")
+            .expect("failed query should be logged");
+        assert_eq!(failed["level"], "ERROR");
+        assert!(failed["fields"]["error"]
+            .as_str()
+            .unwrap()
+            .contains("no_such_table"));
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_otlp_export() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let (url, rx) = crate::test_support::spawn_receiver(vec![200]);
+        let provider = otlp::provider(Some(&url));
+        let subscriber = tracing_subscriber::registry().with(otlp::layer(&provider));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("exported").in_scope(|| tracing::info!("inside"));
+        });
+        provider.force_flush().unwrap();
+
+        let received = rx
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .expect("spans should be exported");
+        assert_eq!(
+            received.header("content-type"),
+            Some("application/x-protobuf")
+        );
+        assert!(received.body.contains("exported"));
+        provider.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,551 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{DigitallySignedStruct, DistinguishedName, RootCertStore, SignatureScheme};
+use serde::{Deserialize, Serialize};
+
+/// How often the certificate files are checked for changes.
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// `[tls]` section of the server configuration. TLS applies to TCP
+/// listeners only; Unix domain sockets stay plain.
+///
+/// Setting `client_ca` turns on client certificate authentication.
+/// Clients are then identified by the common name of their certificate,
+/// which `clients` can map to the hostname their commands are recorded
+/// under:
+///
+/// ```toml
+/// [tls]
+/// cert = "/etc/clh/server.pem"
+/// key = "/etc/clh/server.key"
+/// client_ca = "/etc/clh/clients.pem"
+///
+/// [tls.clients]
+/// "laptop.example.com" = "laptop"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert: String,
+    /// PEM private key.
+    pub key: String,
+    /// PEM certificates of the authorities client certificates must chain
+    /// to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ca: Option<String>,
+    pub client_auth: ClientAuth,
+    /// Hostnames by client certificate common name. Clients missing from
+    /// the table are recorded under their common name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub clients: BTreeMap<String, String>,
+}
+
+/// Whether clients must present a certificate when `client_ca` is set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    #[default]
+    Required,
+    /// Clients without a certificate are let in, unidentified.
+    Optional,
+}
+
+/// The authenticated client of a connection, stored in its connection
+/// data by `on_connect`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub common_name: String,
+    /// Hostname the client's commands are recorded under.
+    pub hostname: String,
+}
+
+impl TlsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cert.is_empty() {
+            return Err("tls.cert is required".to_string());
+        }
+        if self.key.is_empty() {
+            return Err("tls.key is required".to_string());
+        }
+        load_certified_key(&self.cert, &self.key)?;
+        if let Some(ref ca) = self.client_ca {
+            load_verifier(ca, self.client_auth)?;
+        }
+        Ok(())
+    }
+
+    fn files(&self) -> Vec<String> {
+        let mut files = vec![self.cert.clone(), self.key.clone()];
+        files.extend(self.client_ca.clone());
+        files
+    }
+
+    fn identity(&self, common_name: String) -> ClientIdentity {
+        let hostname = self
+            .clients
+            .get(&common_name)
+            .cloned()
+            .unwrap_or_else(|| common_name.clone());
+        ClientIdentity {
+            common_name,
+            hostname,
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_certified_key(cert: &str, key: &str) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("cannot read certificates from {cert}: {e}"))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate found in {cert}"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("cannot read private key from {key}: {e}"))?;
+    CertifiedKey::from_der(certs, key, &provider()).map_err(|e| format!("invalid key pair: {e}"))
+}
+
+fn load_verifier(ca: &str, auth: ClientAuth) -> Result<Arc<dyn ClientCertVerifier>, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca).map_err(|e| format!("cannot read {ca}: {e}"))? {
+        let cert = cert.map_err(|e| format!("cannot read {ca}: {e}"))?;
+        roots
+            .add(cert)
+            .map_err(|e| format!("invalid certificate in {ca}: {e}"))?;
+    }
+
+    let mut builder = WebPkiClientVerifier::builder_with_provider(roots.into(), provider());
+    if auth == ClientAuth::Optional {
+        builder = builder.allow_unauthenticated();
+    }
+    builder
+        .build()
+        .map_err(|e| format!("invalid client_ca {ca}: {e}"))
+}
+
+/// Serves whichever certificate was loaded last.
+#[derive(Debug)]
+struct CertResolver(RwLock<Arc<CertifiedKey>>);
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().unwrap().clone())
+    }
+}
+
+/// Verifies client certificates against whichever authorities were loaded
+/// last.
+#[derive(Debug)]
+struct ClientVerifier {
+    inner: RwLock<Arc<dyn ClientCertVerifier>>,
+    mandatory: bool,
+}
+
+impl ClientVerifier {
+    fn current(&self) -> Arc<dyn ClientCertVerifier> {
+        self.inner.read().unwrap().clone()
+    }
+}
+
+impl ClientCertVerifier for ClientVerifier {
+    fn client_auth_mandatory(&self) -> bool {
+        self.mandatory
+    }
+
+    // The hints would have to outlive a reload. Clients with a single
+    // certificate send it without them.
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.current()
+            .verify_client_cert(end_entity, intermediates, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.current().verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.current().verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.current().supported_verify_schemes()
+    }
+}
+
+/// Accepts whichever certificate the server presents. `clh-server health`
+/// connects to the listener its own configuration names, and sends nothing
+/// but a request for the server's readiness.
+#[derive(Debug)]
+struct AnyServerCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
+        _: &[u8],
+        _: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Returns the client configuration `clh-server health` probes HTTPS
+/// listeners with.
+pub fn probe_config() -> rustls::ClientConfig {
+    let provider = provider();
+    rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyServerCert(provider)))
+        .with_no_client_auth()
+}
+
+/// Keeps a rustls configuration in step with the files it was loaded from.
+#[derive(Clone)]
+pub struct Tls {
+    config: Arc<TlsConfig>,
+    resolver: Arc<CertResolver>,
+    verifier: Option<Arc<ClientVerifier>>,
+}
+
+impl Tls {
+    pub fn load(config: &TlsConfig) -> Result<Self, String> {
+        let key = load_certified_key(&config.cert, &config.key)?;
+        let verifier = match config.client_ca {
+            Some(ref ca) => Some(Arc::new(ClientVerifier {
+                inner: RwLock::new(load_verifier(ca, config.client_auth)?),
+                mandatory: config.client_auth == ClientAuth::Required,
+            })),
+            None => None,
+        };
+        Ok(Self {
+            config: Arc::new(config.clone()),
+            resolver: Arc::new(CertResolver(RwLock::new(Arc::new(key)))),
+            verifier,
+        })
+    }
+
+    /// Returns the configuration to hand to the server.
+    pub fn server_config(&self) -> rustls::ServerConfig {
+        let builder = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions");
+        let builder = match self.verifier {
+            Some(ref verifier) => builder.with_client_cert_verifier(verifier.clone()),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(self.resolver.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        config
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        let key = load_certified_key(&self.config.cert, &self.config.key)?;
+        let verifier = match (&self.verifier, &self.config.client_ca) {
+            (Some(_), Some(ca)) => Some(load_verifier(ca, self.config.client_auth)?),
+            _ => None,
+        };
+
+        *self.resolver.0.write().unwrap() = Arc::new(key);
+        if let (Some(current), Some(verifier)) = (&self.verifier, verifier) {
+            *current.inner.write().unwrap() = verifier;
+        }
+        Ok(())
+    }
+
+    /// Records the client certificate of TLS connections in the connection
+    /// data, as a `ClientIdentity`. For `HttpServer::on_connect`.
+    pub fn on_connect(&self) -> impl Fn(&dyn Any, &mut Extensions) + Send + Sync + 'static {
+        let config = self.config.clone();
+        move |conn, data| {
+            let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() else {
+                return;
+            };
+            let (_, session) = stream.get_ref();
+            let common_name = session
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| common_name(cert));
+            if let Some(common_name) = common_name {
+                data.insert(config.identity(common_name));
+            }
+        }
+    }
+}
+
+fn common_name(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let name = cert.subject().iter_common_name().next()?;
+    name.as_str().ok().map(str::to_string)
+}
+
+fn modified(files: &[String]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|f| Path::new(f).metadata().and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Starts a thread reloading `tls` whenever one of its files changes,
+/// checking every `interval`. A broken file is logged and the previous
+/// certificates kept. The thread exits once every clone of `tls` has been
+/// dropped.
+pub fn spawn_reloader(tls: &Tls, interval: Duration) -> thread::JoinHandle<()> {
+    let files = tls.config.files();
+    let config = tls.config.clone();
+    let resolver: Weak<CertResolver> = Arc::downgrade(&tls.resolver);
+    let verifier = tls.verifier.as_ref().map(Arc::downgrade);
+
+    thread::Builder::new()
+        .name("clh-tls-reloader".to_string())
+        .spawn(move || {
+            let mut seen = modified(&files);
+            loop {
+                thread::sleep(interval);
+                let Some(resolver) = resolver.upgrade() else {
+                    return;
+                };
+                let current = modified(&files);
+                if current == seen {
+                    continue;
+                }
+                seen = current;
+
+                let tls = Tls {
+                    config: config.clone(),
+                    resolver,
+                    verifier: verifier.as_ref().and_then(Weak::upgrade),
+                };
+                match tls.reload() {
+                    Ok(()) => tracing::info!("reloaded TLS certificates"),
+                    Err(e) => tracing::error!(error = %e, "cannot reload TLS certificates"),
+                }
+            }
+        })
+        .expect("cannot spawn TLS reloader")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedIssuer, DnType, IsCa, Issuer, KeyPair,
+    };
+
+    /// A certificate authority issuing server and client certificates into
+    /// a temporary directory.
+    pub(crate) struct TestPki {
+        pub dir: std::path::PathBuf,
+        ca: CertifiedIssuer<'static, KeyPair>,
+    }
+
+    impl TestPki {
+        pub fn new(label: &str) -> Self {
+            let unique = SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos();
+            let dir = std::env::temp_dir().join(format!("clh-tls-{label}-{unique}"));
+            std::fs::create_dir(&dir).unwrap();
+
+            let mut params = CertificateParams::default();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "clh test CA");
+            let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+            std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+            Self { dir, ca }
+        }
+
+        pub fn path(&self, name: &str) -> String {
+            self.dir.join(name).to_string_lossy().into_owned()
+        }
+
+        /// Issues a certificate for `common_name` (also its DNS name),
+        /// written to `<name>.pem` and `<name>.key`.
+        pub fn issue(&self, name: &str, common_name: &str) -> (String, String) {
+            let mut params = CertificateParams::new(vec![common_name.to_string()]).unwrap();
+            params
+                .distinguished_name
+                .push(DnType::CommonName, common_name);
+            let key = KeyPair::generate().unwrap();
+            let issuer: &Issuer<'_, KeyPair> = &self.ca;
+            let cert = params.signed_by(&key, issuer).unwrap();
+
+            let (cert_path, key_path) = (
+                self.path(&format!("{name}.pem")),
+                self.path(&format!("{name}.key")),
+            );
+            std::fs::write(&cert_path, cert.pem()).unwrap();
+            std::fs::write(&key_path, key.serialize_pem()).unwrap();
+            (cert_path, key_path)
+        }
+
+        pub fn config(&self, client_auth: Option<ClientAuth>) -> TlsConfig {
+            let (cert, key) = self.issue("server", "localhost");
+            TlsConfig {
+                cert,
+                key,
+                client_ca: client_auth.map(|_| self.path("ca.pem")),
+                client_auth: client_auth.unwrap_or_default(),
+                clients: BTreeMap::from([("laptop.example.com".to_string(), "laptop".to_string())]),
+            }
+        }
+    }
+
+    impl Drop for TestPki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn serial(tls: &Tls) -> Vec<u8> {
+        let key = tls.resolver.0.read().unwrap().clone();
+        let (_, cert) = x509_parser::parse_x509_certificate(&key.cert[0]).unwrap();
+        cert.raw_serial().to_vec()
+    }
+
+    #[test]
+    fn test_validate() {
+        let pki = TestPki::new("validate");
+        let config = pki.config(Some(ClientAuth::Required));
+        assert_eq!(config.validate(), Ok(()));
+
+        let missing = TlsConfig {
+            key: pki.path("missing.key"),
+            ..config.clone()
+        };
+        assert!(missing.validate().unwrap_err().contains("missing.key"));
+
+        let (_, other_key) = pki.issue("other", "other");
+        let mismatched = TlsConfig {
+            key: other_key,
+            ..config
+        };
+        assert!(mismatched
+            .validate()
+            .unwrap_err()
+            .contains("invalid key pair"));
+    }
+
+    #[test]
+    fn test_identity() {
+        let pki = TestPki::new("identity");
+        let config = pki.config(Some(ClientAuth::Required));
+        assert_eq!(
+            config.identity("laptop.example.com".to_string()).hostname,
+            "laptop"
+        );
+        assert_eq!(
+            config.identity("ci-runner".to_string()).hostname,
+            "ci-runner"
+        );
+
+        let (cert, _) = pki.issue("client", "laptop.example.com");
+        let cert = CertificateDer::from_pem_file(cert).unwrap();
+        assert_eq!(common_name(&cert).as_deref(), Some("laptop.example.com"));
+    }
+
+    #[test]
+    fn test_reloader_picks_up_new_certificate() {
+        let pki = TestPki::new("reload");
+        let config = pki.config(None);
+        let tls = Tls::load(&config).unwrap();
+        let before = serial(&tls);
+        let reloader = spawn_reloader(&tls, Duration::from_millis(20));
+
+        // A broken file keeps the current certificate.
+        std::fs::write(&config.cert, "garbage").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(serial(&tls), before);
+
+        pki.issue("server", "localhost");
+        let mut reloaded = false;
+        for _ in 0..50 {
+            thread::sleep(Duration::from_millis(20));
+            if serial(&tls) != before {
+                reloaded = true;
+                break;
+            }
+        }
+        assert!(reloaded, "certificate should be reloaded");
+
+        drop(tls);
+        reloader.join().unwrap();
+    }
+}
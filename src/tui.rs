@@ -0,0 +1,442 @@
+use std::io::Stderr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+use chrono::Local;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::prelude::CrosstermBackend;
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Cell, Paragraph, Row, Table, TableState};
+use ratatui::{Frame, Terminal};
+
+use crate::cli::{Scope, TuiArgs};
+use crate::client::{Client, ClientResult};
+use crate::models::{History, SearchQuery};
+
+/// Number of histories fetched for each search.
+const PAGE_SIZE: i64 = 200;
+
+type SearchResult = Result<(Vec<History>, i64), String>;
+type SearchRequest = (u64, SearchQuery);
+
+enum Outcome {
+    Continue,
+    Search,
+    Accept(String),
+    Cancel,
+}
+
+struct App {
+    query: String,
+    scope: Scope,
+    hostname: String,
+    directory: String,
+    histories: Vec<History>,
+    total: i64,
+    table: TableState,
+    error: Option<String>,
+    /// Incremented for every search, so that stale results can be dropped.
+    generation: u64,
+}
+
+impl App {
+    fn new(query: String, scope: Scope, hostname: String, directory: String) -> Self {
+        App {
+            query,
+            scope,
+            hostname,
+            directory,
+            histories: Vec::new(),
+            total: 0,
+            table: TableState::default(),
+            error: None,
+            generation: 0,
+        }
+    }
+
+    fn search_query(&self) -> SearchQuery {
+        let mut q = SearchQuery {
+            command: Some(self.query.clone()).filter(|query| !query.is_empty()),
+            limit: Some(PAGE_SIZE),
+            ..Default::default()
+        };
+        match self.scope {
+            Scope::Global => {}
+            Scope::Host => q.hostname = Some(self.hostname.clone()),
+            Scope::Directory => {
+                q.pwd = Some(self.directory.clone());
+                q.origin = Some(self.hostname.clone());
+            }
+        }
+        q
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Outcome {
+        if key.kind != KeyEventKind::Press {
+            return Outcome::Continue;
+        }
+
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => Outcome::Cancel,
+            KeyCode::Char('c' | 'd' | 'g') if ctrl => Outcome::Cancel,
+            KeyCode::Enter => match self.table.selected().and_then(|i| self.histories.get(i)) {
+                Some(history) => Outcome::Accept(history.command.clone()),
+                None => Outcome::Cancel,
+            },
+            KeyCode::Up => self.move_selection(-1),
+            KeyCode::Char('p' | 'k') if ctrl => self.move_selection(-1),
+            KeyCode::Down => self.move_selection(1),
+            KeyCode::Char('n' | 'j') if ctrl => self.move_selection(1),
+            KeyCode::Tab | KeyCode::BackTab => {
+                self.scope = self.scope.next();
+                Outcome::Search
+            }
+            KeyCode::Char('u') if ctrl => {
+                self.query.clear();
+                Outcome::Search
+            }
+            KeyCode::Char('w') if ctrl => {
+                let trimmed = self.query.trim_end();
+                let end = trimmed.rfind(' ').map_or(0, |i| i + 1);
+                self.query.truncate(end);
+                Outcome::Search
+            }
+            KeyCode::Backspace => {
+                self.query.pop();
+                Outcome::Search
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.query.push(c);
+                Outcome::Search
+            }
+            _ => Outcome::Continue,
+        }
+    }
+
+    fn move_selection(&mut self, delta: isize) -> Outcome {
+        if let Some(selected) = self.table.selected() {
+            let last = self.histories.len().saturating_sub(1);
+            let selected = selected.saturating_add_signed(delta).min(last);
+            self.table.select(Some(selected));
+        }
+        Outcome::Continue
+    }
+
+    fn set_result(&mut self, result: SearchResult) {
+        match result {
+            Ok((histories, total)) => {
+                self.table
+                    .select(if histories.is_empty() { None } else { Some(0) });
+                self.histories = histories;
+                self.total = total;
+                self.error = None;
+            }
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    fn render(&mut self, frame: &mut Frame) {
+        let [input, list, status] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let scope = match self.scope {
+            Scope::Global => "global".to_string(),
+            Scope::Host => format!("host {}", self.hostname),
+            Scope::Directory => format!("dir {}", self.directory),
+        };
+        frame.render_widget(
+            Paragraph::new(Line::from(vec![
+                Span::styled(format!("[{scope}] "), Style::new().fg(Color::Cyan)),
+                Span::raw("> "),
+                Span::raw(self.query.as_str()),
+            ])),
+            input,
+        );
+        let prompt_width = scope.chars().count() + 5 + self.query.chars().count();
+        frame.set_cursor_position((input.x + prompt_width as u16, input.y));
+
+        let rows = self.histories.iter().map(|h| {
+            let exit_status = match h.exit_status {
+                Some(0) => Cell::from("0"),
+                Some(s) => Cell::from(s.to_string()).style(Style::new().fg(Color::Red)),
+                None => Cell::from(""),
+            };
+            Row::new(vec![
+                Cell::from(
+                    h.updated_at
+                        .with_timezone(&Local)
+                        .format("%Y-%m-%d %H:%M")
+                        .to_string(),
+                ),
+                Cell::from(h.hostname.as_str()),
+                Cell::from(h.working_directory.as_deref().unwrap_or_default()),
+                exit_status,
+                Cell::from(h.command.replace('\n', " ")),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(16),
+                Constraint::Max(16),
+                Constraint::Max(32),
+                Constraint::Length(3),
+                Constraint::Fill(1),
+            ],
+        )
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, list, &mut self.table);
+
+        let status_line = match self.error {
+            Some(ref e) => Span::styled(e.as_str(), Style::new().fg(Color::Red)),
+            None => Span::styled(
+                format!(
+                    "{}/{}  Enter: insert  Tab: scope  Esc: cancel",
+                    self.histories.len(),
+                    self.total
+                ),
+                Style::new().fg(Color::DarkGray),
+            ),
+        };
+        frame.render_widget(Paragraph::new(status_line), status);
+    }
+}
+
+/// Restores the terminal when dropped, even if the search fails.
+struct TerminalGuard {
+    terminal: Terminal<CrosstermBackend<Stderr>>,
+}
+
+impl TerminalGuard {
+    fn new() -> std::io::Result<Self> {
+        enable_raw_mode()?;
+        let mut stderr = std::io::stderr();
+        execute!(stderr, EnterAlternateScreen)?;
+        Ok(TerminalGuard {
+            terminal: Terminal::new(CrosstermBackend::new(stderr))?,
+        })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+    }
+}
+
+/// Searches in the background, always answering the most recent request.
+fn spawn_searcher(client: Client) -> (Sender<SearchRequest>, Receiver<(u64, SearchResult)>) {
+    let (request_tx, request_rx) = mpsc::channel::<SearchRequest>();
+    let (result_tx, result_rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        while let Ok(mut request) = request_rx.recv() {
+            while let Ok(newer) = request_rx.try_recv() {
+                request = newer;
+            }
+            let (generation, q) = request;
+            let result = client.search(&q).map_err(|e| e.to_string());
+            if result_tx.send((generation, result)).is_err() {
+                break;
+            }
+        }
+    });
+
+    (request_tx, result_rx)
+}
+
+/// Runs the interactive search. The terminal UI is drawn on stderr, so that
+/// the chosen command, if any, can be returned to be printed on stdout.
+pub fn run(args: TuiArgs) -> ClientResult<Option<String>> {
+    let client = Client::new(
+        &args.connection.server,
+        args.connection.token,
+        &args.connection.tls,
+        clh_client::Retry::default(),
+    )?
+    .with_key_file(args.connection.key_file.as_deref())?;
+    let hostname = gethostname::gethostname().to_string_lossy().into_owned();
+    let directory = std::env::current_dir()?.to_string_lossy().into_owned();
+    let mut app = App::new(args.query, args.scope, hostname, directory);
+
+    let (requests, results) = spawn_searcher(client);
+    requests.send((app.generation, app.search_query()))?;
+
+    let mut guard = TerminalGuard::new()?;
+    loop {
+        while let Ok((generation, result)) = results.try_recv() {
+            if generation == app.generation {
+                app.set_result(result);
+            }
+        }
+
+        guard.terminal.draw(|frame| app.render(frame))?;
+
+        if !event::poll(Duration::from_millis(50))? {
+            continue;
+        }
+        if let Event::Key(key) = event::read()? {
+            match app.handle_key(key) {
+                Outcome::Continue => {}
+                Outcome::Search => {
+                    app.generation += 1;
+                    requests.send((app.generation, app.search_query()))?;
+                }
+                Outcome::Accept(command) => return Ok(Some(command)),
+                Outcome::Cancel => return Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use ratatui::backend::TestBackend;
+
+    fn app() -> App {
+        App::new(
+            String::new(),
+            Scope::Global,
+            "tui-host".to_string(),
+            "/home/tui".to_string(),
+        )
+    }
+
+    fn history(id: i32, command: &str, exit_status: Option<i32>) -> History {
+        History {
+            id,
+            hostname: "tui-host".to_string(),
+            working_directory: Some("/home/tui".to_string()),
+            command: command.to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            git_remote: None,
+            git_branch: None,
+            git_root: None,
+            normalized_directory: None,
+            exit_status,
+            run_count: 1,
+            failure_count: 0,
+            deleted_at: None,
+            encrypted: false,
+            blind_index: Vec::new(),
+            key_id: None,
+            data_key: None,
+            content_hash: None,
+            search_index: None,
+        }
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn ctrl(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL)
+    }
+
+    #[test]
+    fn test_typing_updates_query() {
+        let mut app = app();
+        for c in "git st".chars() {
+            assert!(matches!(
+                app.handle_key(key(KeyCode::Char(c))),
+                Outcome::Search
+            ));
+        }
+        assert_eq!(app.search_query().command.as_deref(), Some("git st"));
+
+        app.handle_key(ctrl('w'));
+        assert_eq!(app.query, "git ");
+        app.handle_key(key(KeyCode::Backspace));
+        assert_eq!(app.query, "git");
+        app.handle_key(ctrl('u'));
+        assert_eq!(app.search_query().command, None);
+    }
+
+    #[test]
+    fn test_tab_cycles_scopes() {
+        let mut app = app();
+        assert_eq!(app.search_query().hostname, None);
+
+        assert!(matches!(app.handle_key(key(KeyCode::Tab)), Outcome::Search));
+        assert_eq!(app.search_query().hostname.as_deref(), Some("tui-host"));
+
+        app.handle_key(key(KeyCode::Tab));
+        let q = app.search_query();
+        assert_eq!(q.hostname, None);
+        assert_eq!(q.pwd.as_deref(), Some("/home/tui"));
+        assert_eq!(q.origin.as_deref(), Some("tui-host"));
+
+        app.handle_key(key(KeyCode::Tab));
+        assert_eq!(app.scope, Scope::Global);
+    }
+
+    #[test]
+    fn test_selection_and_accept() {
+        let mut app = app();
+        assert!(matches!(
+            app.handle_key(key(KeyCode::Enter)),
+            Outcome::Cancel
+        ));
+
+        app.set_result(Ok((
+            vec![history(1, "ls", Some(0)), history(2, "make", Some(2))],
+            2,
+        )));
+        app.handle_key(key(KeyCode::Down));
+        app.handle_key(key(KeyCode::Down));
+        match app.handle_key(key(KeyCode::Enter)) {
+            Outcome::Accept(command) => assert_eq!(command, "make"),
+            _ => panic!("expected the selected command"),
+        }
+
+        app.handle_key(ctrl('p'));
+        app.handle_key(key(KeyCode::Up));
+        match app.handle_key(key(KeyCode::Enter)) {
+            Outcome::Accept(command) => assert_eq!(command, "ls"),
+            _ => panic!("expected the selected command"),
+        }
+
+        assert!(matches!(app.handle_key(key(KeyCode::Esc)), Outcome::Cancel));
+    }
+
+    #[test]
+    fn test_render_shows_columns() {
+        let mut app = app();
+        app.set_result(Ok((vec![history(1, "cargo test", Some(101))], 1)));
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 5)).unwrap();
+        terminal.draw(|frame| app.render(frame)).unwrap();
+
+        let buffer = terminal.backend().buffer();
+        let text = (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer[(x, y)].symbol())
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert!(text.contains("[global] >"));
+        assert!(text.contains("tui-host"));
+        assert!(text.contains("/home/tui"));
+        assert!(text.contains("101"));
+        assert!(text.contains("cargo test"));
+        assert!(text.contains("1/1"));
+    }
+}
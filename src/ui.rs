@@ -0,0 +1,46 @@
+use actix_web::http::header;
+use actix_web::{get, web, HttpResponse, Responder};
+
+const INDEX_HTML: &str = include_str!("ui/index.html");
+const APP_JS: &str = include_str!("ui/app.js");
+const STYLE_CSS: &str = include_str!("ui/style.css");
+
+/// Registers the web UI, served from assets compiled into the binary.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/ui", web::get().to(redirect))
+        .service(index)
+        .service(asset);
+}
+
+async fn redirect() -> impl Responder {
+    HttpResponse::MovedPermanently()
+        .insert_header((header::LOCATION, "/ui/"))
+        .finish()
+}
+
+#[get("/ui/")]
+async fn index() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(INDEX_HTML)
+}
+
+#[get("/ui/{file}")]
+async fn asset(file: web::Path<String>) -> impl Responder {
+    let (content_type, body) = match file.as_str() {
+        "app.js" => ("text/javascript; charset=utf-8", APP_JS),
+        "style.css" => ("text/css; charset=utf-8", STYLE_CSS),
+        _ => return HttpResponse::NotFound().finish(),
+    };
+
+    HttpResponse::Ok().content_type(content_type).body(body)
+}
+
+/// Whether a request to the JSON API came from a browser navigating to it,
+/// rather than from a client or script.
+pub fn wants_html(req: &actix_web::HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.starts_with("text/html"))
+}
@@ -0,0 +1,197 @@
+"use strict";
+
+const form = document.getElementById("filters");
+const state = { offset: 0, hostname: null, pwd: null, total: 0 };
+
+function filters() {
+  const data = new FormData(form);
+  const params = new URLSearchParams();
+  const command = data.get("command").trim();
+  if (command) params.set("command", command);
+  if (data.get("since")) params.set("since", new Date(data.get("since") + "T00:00:00").toISOString());
+  if (data.get("until")) {
+    // The date picker is inclusive, the API's upper bound is not.
+    const until = new Date(data.get("until") + "T00:00:00");
+    until.setDate(until.getDate() + 1);
+    params.set("until", until.toISOString());
+  }
+  if (data.get("deleted")) params.set("deleted", "true");
+  if (state.hostname) params.set("hostname", state.hostname);
+  if (state.pwd) params.set("pwd", state.pwd);
+  return params;
+}
+
+const API = "/api/v1";
+
+async function request(method, path) {
+  const response = await fetch(API + path, { method, headers: { Accept: "application/json" } });
+  if (!response.ok) throw new Error(`${response.status} ${await response.text()}`);
+  return response;
+}
+
+function showMessage(text, action) {
+  const message = document.getElementById("message");
+  message.replaceChildren(text);
+  if (action) {
+    const button = document.createElement("button");
+    button.type = "button";
+    button.textContent = action.label;
+    button.addEventListener("click", action.run);
+    message.append(" ", button);
+  }
+  message.hidden = false;
+}
+
+function hideMessage() {
+  document.getElementById("message").hidden = true;
+}
+
+function cell(row, text, className) {
+  const td = row.insertCell();
+  td.textContent = text;
+  if (className) td.className = className;
+  return td;
+}
+
+function button(label, onClick) {
+  const b = document.createElement("button");
+  b.type = "button";
+  b.textContent = label;
+  b.addEventListener("click", onClick);
+  return b;
+}
+
+function renderHistories(histories, deleted) {
+  const tbody = document.getElementById("histories");
+  tbody.replaceChildren();
+  for (const history of histories) {
+    const row = tbody.insertRow();
+    cell(row, new Date(history.updated_at).toLocaleString());
+    cell(row, history.hostname);
+    cell(row, history.working_directory || "");
+    const failed = history.exit_status !== null && history.exit_status !== 0;
+    cell(row, history.exit_status ?? "", failed ? "failed" : null);
+    cell(row, history.command, "command");
+    const actions = row.insertCell();
+    if (deleted) {
+      actions.append(
+        button("Restore", () => restore(history)),
+        " ",
+        button("Purge", () => purge(history)),
+      );
+    } else {
+      actions.append(button("Delete", () => remove(history)));
+    }
+  }
+}
+
+function renderFacet(id, entries, selected, onSelect) {
+  const list = document.getElementById(id);
+  list.replaceChildren();
+  for (const entry of entries) {
+    const item = document.createElement("li");
+    const key = document.createElement("span");
+    key.textContent = entry.key;
+    const count = document.createElement("span");
+    count.textContent = entry.count;
+    item.append(key, count);
+    if (entry.key === selected) item.className = "selected";
+    item.addEventListener("click", () => onSelect(entry.key === selected ? null : entry.key));
+    list.append(item);
+  }
+}
+
+function renderPagination(limit, shown) {
+  const page = Math.floor(state.offset / limit) + 1;
+  const pages = Math.max(1, Math.ceil(state.total / limit));
+  document.getElementById("page").textContent =
+    `Page ${page} of ${pages} (${shown} of ${state.total} commands)`;
+  document.getElementById("previous").disabled = state.offset === 0;
+  document.getElementById("next").disabled = state.offset + limit >= state.total;
+}
+
+async function load() {
+  const limit = Number(form.elements.limit.value);
+  const params = filters();
+  const deleted = params.has("deleted");
+  params.set("limit", limit);
+  params.set("offset", state.offset);
+
+  try {
+    const response = await request("GET", `/?${params}`);
+    state.total = Number(response.headers.get("X-Total-Count") || 0);
+    const histories = await response.json();
+    renderHistories(histories, deleted);
+    renderPagination(limit, histories.length);
+
+    const facetParams = filters();
+    facetParams.set("top", 20);
+    const stats = await (await request("GET", `/stats?${facetParams}`)).json();
+    renderFacet("hosts", stats.per_host, state.hostname, (hostname) => {
+      state.hostname = hostname;
+      reload();
+    });
+    renderFacet("directories", stats.per_directory, state.pwd, (pwd) => {
+      state.pwd = pwd;
+      reload();
+    });
+  } catch (e) {
+    showMessage(`Failed to load histories: ${e.message}`);
+  }
+}
+
+function reload() {
+  state.offset = 0;
+  load();
+}
+
+async function remove(history) {
+  try {
+    await request("DELETE", `/${history.id}`);
+    showMessage(`Deleted "${history.command}".`, {
+      label: "Undo",
+      run: () => restore(history),
+    });
+    load();
+  } catch (e) {
+    showMessage(`Failed to delete: ${e.message}`);
+  }
+}
+
+async function restore(history) {
+  try {
+    await request("POST", `/${history.id}/restore`);
+    showMessage(`Restored "${history.command}".`);
+    load();
+  } catch (e) {
+    showMessage(`Failed to restore: ${e.message}`);
+  }
+}
+
+async function purge(history) {
+  if (!confirm(`Permanently delete "${history.command}"?`)) return;
+  try {
+    await request("DELETE", `/${history.id}?purge=true`);
+    hideMessage();
+    load();
+  } catch (e) {
+    showMessage(`Failed to purge: ${e.message}`);
+  }
+}
+
+let debounce;
+form.addEventListener("input", (event) => {
+  clearTimeout(debounce);
+  debounce = setTimeout(reload, event.target.type === "search" ? 250 : 0);
+});
+form.addEventListener("submit", (event) => event.preventDefault());
+document.getElementById("previous").addEventListener("click", () => {
+  state.offset = Math.max(0, state.offset - Number(form.elements.limit.value));
+  load();
+});
+document.getElementById("next").addEventListener("click", () => {
+  state.offset += Number(form.elements.limit.value);
+  load();
+});
+
+load();
@@ -0,0 +1,392 @@
+use std::time::Duration;
+
+use actix_web::{delete, error, get, post, put, web, HttpResponse, Responder, Result};
+use diesel::Connection;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::{Instrument, Span};
+
+use crate::actions;
+use crate::audit::Actor;
+use crate::models::{NewWebhook, Webhook};
+use crate::telemetry;
+use crate::DbPool;
+
+/// Header carrying the hex encoded HMAC-SHA256 of the body, keyed with the
+/// webhook's secret, as `sha256=<hex>`.
+pub const SIGNATURE_HEADER: &str = "X-Clh-Signature-256";
+pub const EVENT_HEADER: &str = "X-Clh-Event";
+
+const MAX_ATTEMPTS: u32 = 5;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delivers webhook events in the background, retrying failed deliveries
+/// with exponential backoff. Deliveries are only kept in memory, so those
+/// still pending when the server stops are lost.
+#[derive(Clone)]
+pub struct Dispatcher {
+    client: reqwest::Client,
+    retry_delay: Duration,
+    enabled: bool,
+}
+
+impl Default for Dispatcher {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1))
+    }
+}
+
+impl Dispatcher {
+    pub fn new(retry_delay: Duration) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .user_agent(concat!("clh-server/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("cannot build webhook client");
+        Self {
+            client,
+            retry_delay,
+            enabled: true,
+        }
+    }
+
+    /// A dispatcher that never delivers, for when webhooks are turned off.
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Self::default()
+        }
+    }
+
+    /// Whether events should be looked up and dispatched at all.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Sends `event` to each of `webhooks` without waiting for the
+    /// deliveries. The payload is `fields` plus the event name and time.
+    /// Must be called from within the actix runtime.
+    pub fn dispatch(&self, webhooks: Vec<Webhook>, event: &str, fields: serde_json::Value) {
+        if !self.enabled || webhooks.is_empty() {
+            return;
+        }
+
+        let mut payload = serde_json::json!({
+            "event": event,
+            "occurred_at": chrono::Utc::now(),
+        });
+        if let (Some(payload), serde_json::Value::Object(fields)) =
+            (payload.as_object_mut(), fields)
+        {
+            payload.extend(fields);
+        }
+        let body = payload.to_string();
+
+        for webhook in webhooks {
+            let dispatcher = self.clone();
+            let body = body.clone();
+            let event = event.to_string();
+            let delivery = async move { dispatcher.deliver(&webhook, &event, body).await };
+            actix_rt::spawn(delivery.instrument(Span::current()));
+        }
+    }
+
+    async fn deliver(&self, webhook: &Webhook, event: &str, body: String) -> bool {
+        let signature = webhook.secret.as_deref().map(|secret| sign(secret, &body));
+
+        for attempt in 0..MAX_ATTEMPTS {
+            if attempt > 0 {
+                actix_rt::time::sleep(self.retry_delay * 2u32.pow(attempt - 1)).await;
+            }
+
+            let mut request = self
+                .client
+                .post(&webhook.url)
+                .header("Content-Type", "application/json")
+                .header(EVENT_HEADER, event)
+                .body(body.clone());
+            if let Some(ref signature) = signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+
+            match request.send().await {
+                Ok(response) if response.status().is_success() => return true,
+                Ok(response) => tracing::warn!(
+                    webhook.id,
+                    attempt = attempt + 1,
+                    status = %response.status(),
+                    "webhook delivery failed"
+                ),
+                Err(e) => tracing::warn!(
+                    webhook.id,
+                    attempt = attempt + 1,
+                    error = %e,
+                    "webhook delivery failed"
+                ),
+            }
+        }
+
+        tracing::error!(
+            webhook.id,
+            event,
+            "giving up on webhook after {MAX_ATTEMPTS} attempts"
+        );
+        false
+    }
+}
+
+/// Returns the value of the signature header for `body`.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Registers the webhook management endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(index)
+        .service(show)
+        .service(create)
+        .service(update)
+        .service(remove);
+}
+
+/// Outcome of saving a webhook.
+enum Saved {
+    Webhook(Webhook),
+    InvalidPattern,
+    NotFound,
+}
+
+/// Creates a webhook, or replaces webhook `id`, unless its command pattern
+/// is not one the database can evaluate. Saved webhooks are audited, since
+/// their secrets are credentials.
+fn save(
+    conn: &mut diesel::PgConnection,
+    id: Option<i32>,
+    w: &NewWebhook,
+    actor: &Actor,
+) -> Result<Saved, diesel::result::Error> {
+    if let Some(ref pattern) = w.command_pattern {
+        if !actions::is_valid_pattern(conn, pattern)? {
+            return Ok(Saved::InvalidPattern);
+        }
+    }
+
+    let saved = match id {
+        Some(id) => actions::update_webhook(conn, id, w)?,
+        None => Some(actions::create_webhook(conn, w)?),
+    };
+    if let Some(ref webhook) = saved {
+        let action = if id.is_some() {
+            "webhook.update"
+        } else {
+            "webhook.create"
+        };
+        let event = actor.event(action, Vec::new()).details(serde_json::json!({
+            "webhook_id": webhook.id,
+            "url": webhook.url,
+            "has_secret": webhook.secret.is_some(),
+        }));
+        actions::record_audit(conn, &event)?;
+    }
+    Ok(saved.map_or(Saved::NotFound, Saved::Webhook))
+}
+
+async fn save_response(
+    pool: web::Data<DbPool>,
+    actor: Actor,
+    id: Option<i32>,
+    webhook: NewWebhook,
+) -> Result<HttpResponse> {
+    webhook.validate().map_err(error::ErrorBadRequest)?;
+    let mut conn = pool.get().expect("cannot get db connection from pool");
+
+    let saved = move || conn.transaction(|conn| save(conn, id, &webhook, &actor));
+    match telemetry::block(saved).await {
+        Ok(response) => match response {
+            Ok(Saved::Webhook(r)) if id.is_none() => Ok(HttpResponse::Created().json(r)),
+            Ok(Saved::Webhook(r)) => Ok(HttpResponse::Ok().json(r)),
+            Ok(Saved::InvalidPattern) => Err(error::ErrorBadRequest("invalid command_pattern")),
+            Ok(Saved::NotFound) => Err(error::ErrorNotFound("webhook not found")),
+            Err(e) => Err(error::ErrorInternalServerError(e)),
+        },
+        Err(e) => Err(error::ErrorInternalServerError(e)),
+    }
+}
+
+#[utoipa::path(
+    tag = "webhooks",
+    operation_id = "list_webhooks",
+    responses((status = 200, body = [Webhook]))
+)]
+#[get("/webhooks")]
+async fn index(pool: web::Data<DbPool>) -> Result<impl Responder> {
+    let mut conn = pool.get().expect("cannot get db connection from pool");
+
+    match telemetry::block(move || actions::list_webhooks(&mut conn)).await {
+        Ok(response) => match response {
+            Ok(r) => Ok(web::Json(r)),
+            Err(e) => Err(error::ErrorInternalServerError(e)),
+        },
+        Err(e) => Err(error::ErrorInternalServerError(e)),
+    }
+}
+
+#[utoipa::path(
+    tag = "webhooks",
+    operation_id = "show_webhook",
+    responses((status = 200, body = Webhook), (status = 404))
+)]
+#[get("/webhooks/{id}")]
+async fn show(pool: web::Data<DbPool>, id: web::Path<i32>) -> Result<impl Responder> {
+    let mut conn = pool.get().expect("cannot get db connection from pool");
+
+    match telemetry::block(move || actions::find_webhook(&mut conn, *id)).await {
+        Ok(response) => match response {
+            Ok(Some(r)) => Ok(web::Json(r)),
+            Ok(None) => Err(error::ErrorNotFound("webhook not found")),
+            Err(e) => Err(error::ErrorInternalServerError(e)),
+        },
+        Err(e) => Err(error::ErrorInternalServerError(e)),
+    }
+}
+
+/// Registers a webhook. Events are delivered in the background and retried
+/// a few times, but not across restarts of the server.
+#[utoipa::path(
+    tag = "webhooks",
+    operation_id = "create_webhook",
+    request_body = NewWebhook,
+    responses((status = 201, body = Webhook), (status = 400))
+)]
+#[post("/webhooks")]
+async fn create(
+    pool: web::Data<DbPool>,
+    actor: Actor,
+    webhook: web::Json<NewWebhook>,
+) -> Result<HttpResponse> {
+    save_response(pool, actor, None, webhook.into_inner()).await
+}
+
+#[utoipa::path(
+    tag = "webhooks",
+    operation_id = "update_webhook",
+    request_body = NewWebhook,
+    responses((status = 200, body = Webhook), (status = 400), (status = 404))
+)]
+#[put("/webhooks/{id}")]
+async fn update(
+    pool: web::Data<DbPool>,
+    actor: Actor,
+    id: web::Path<i32>,
+    webhook: web::Json<NewWebhook>,
+) -> Result<HttpResponse> {
+    save_response(pool, actor, Some(*id), webhook.into_inner()).await
+}
+
+#[utoipa::path(
+    tag = "webhooks",
+    operation_id = "delete_webhook",
+    responses((status = 204), (status = 404))
+)]
+#[delete("/webhooks/{id}")]
+async fn remove(
+    pool: web::Data<DbPool>,
+    actor: Actor,
+    id: web::Path<i32>,
+) -> Result<impl Responder> {
+    let mut conn = pool.get().expect("cannot get db connection from pool");
+
+    let wrapped_response = telemetry::block(move || {
+        conn.transaction(|conn| {
+            let deleted = actions::delete_webhook(conn, *id)?;
+            if deleted > 0 {
+                let event = actor
+                    .event("webhook.delete", Vec::new())
+                    .details(serde_json::json!({ "webhook_id": *id }));
+                actions::record_audit(conn, &event)?;
+            }
+            Ok::<_, diesel::result::Error>(deleted)
+        })
+    })
+    .await;
+
+    match wrapped_response {
+        Ok(response) => match response {
+            Ok(0) => Err(error::ErrorNotFound("webhook not found")),
+            Ok(_) => Ok(HttpResponse::NoContent().finish()),
+            Err(e) => Err(error::ErrorInternalServerError(e)),
+        },
+        Err(e) => Err(error::ErrorInternalServerError(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{spawn_receiver, Received};
+
+    fn webhook(url: &str, secret: Option<&str>) -> Webhook {
+        let now = chrono::Utc::now();
+        Webhook {
+            id: 1,
+            url: url.to_string(),
+            secret: secret.map(str::to_string),
+            events: vec!["created".to_string()],
+            command_pattern: None,
+            active: true,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("key", "The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_deliver_retries_until_success() {
+        let (url, rx) = spawn_receiver(vec![500, 503, 204]);
+        let dispatcher = Dispatcher::new(Duration::from_millis(10));
+        let body = r#"{"event":"created"}"#.to_string();
+
+        assert!(
+            dispatcher
+                .deliver(&webhook(&url, Some("s3cret")), "created", body.clone())
+                .await
+        );
+
+        let received: Vec<Received> = rx.try_iter().collect();
+        assert_eq!(received.len(), 3);
+        for r in received {
+            assert_eq!(r.body, body);
+            assert_eq!(r.header(EVENT_HEADER), Some("created"));
+            assert_eq!(
+                r.header(SIGNATURE_HEADER),
+                Some(sign("s3cret", &body).as_str())
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_deliver_gives_up() {
+        let (url, rx) = spawn_receiver(vec![500; MAX_ATTEMPTS as usize]);
+        let dispatcher = Dispatcher::new(Duration::from_millis(1));
+
+        assert!(
+            !dispatcher
+                .deliver(&webhook(&url, None), "created", "{}".to_string())
+                .await
+        );
+
+        let received: Vec<Received> = rx.try_iter().collect();
+        assert_eq!(received.len(), MAX_ATTEMPTS as usize);
+        assert_eq!(received[0].header(SIGNATURE_HEADER), None);
+    }
+}